@@ -64,9 +64,9 @@ defmodule BubblegumBench do
             @test_owner,
             @test_delegate,
             @test_authority,
-            <<0::256>>,
-            <<0::256>>,
-            <<0::256>>,
+            Base58.encode(<<0::256>>),
+            Base58.encode(<<0::256>>),
+            Base58.encode(<<0::256>>),
             1,
             0
           )
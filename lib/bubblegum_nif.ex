@@ -22,6 +22,10 @@ defmodule BubblegumNif do
 
       {:ok, _} = BubblegumNif.load_keypair(File.read!("~/.config/solana/id.json"))
 
+  To have a transaction signed elsewhere (a wallet, a multisig, an HSM), pass
+  `%BubblegumNif.Types.TransactionOptions{build_only: true}` to the operation,
+  add signatures with `partially_sign/2` and send it with `submit_signed/1`.
+
   ## Examples
 
       # Create a new tree configuration
@@ -58,7 +62,38 @@ defmodule BubblegumNif do
   """
 
   use Rustler, otp_app: :bubblegum_nif, crate: :bubblegum_nif
-  alias BubblegumNif.Types.{Config, MetadataArgs}
+  alias BubblegumNif.Types.{
+    CacheStats,
+    Config,
+    Creator,
+    HistoricalProof,
+    LeafRecord,
+    LeafSchema,
+    MetadataArgs,
+    MultiProof,
+    NonceAccount,
+    StorageConfig,
+    TransactionOptions,
+    TreeAccount,
+    TreeDescriptor
+  }
+
+  @typedoc """
+  Why sending a transaction did not produce a confirmed signature:
+
+  * `{:expired, signature}` - it never landed and its blockhash or durable
+    nonce can no longer be used, so it is safe to send the change again
+  * `{:failed, signature, reason}` - it landed with an error, which still
+    costs its fee
+  * `{:unknown, signature}` - its status could not be read in time; it may
+    still land, so check `signature` before sending the change again
+  * a message for errors before anything was sent
+  """
+  @type send_error ::
+    {:expired, String.t()}
+    | {:failed, String.t(), String.t()}
+    | {:unknown, String.t()}
+    | String.t()
 
   @doc """
   Initializes the Solana client with the given configuration.
@@ -93,6 +128,33 @@ defmodule BubblegumNif do
   @spec load_keypair(String.t()) :: {:ok, String.t()} | {:error, String.t()}
   def load_keypair(_keypair_json), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Chooses the storage backend of the local tree manager. Must be called before
+  any tree is managed; `BubblegumNif.Application` does so at startup from
+
+      config :bubblegum_nif, :tree_storage, backend: "file", path: "/var/lib/bubblegum"
+
+  Without it, the `BUBBLEGUM_TREE_BACKEND`, `BUBBLEGUM_TREE_STORE`,
+  `BUBBLEGUM_TREE_WAL` and `BUBBLEGUM_TREE_MEMORY_BUDGET` environment
+  variables are used, defaulting to RocksDB in `bubblegum_trees`. Only backends enabled as cargo features
+  (`persistent-storage`, `file-storage`, `sqlite-storage`) are available;
+  `"memory"` always is and keeps nothing across restarts.
+
+  Leaf changes interrupted by a crash are replayed from the write-ahead log
+  (`wal_path`) before this returns.
+
+  With a `memory_budget`, trees are saved and dropped from memory, least
+  recently used first, once the resident ones exceed that many bytes. They
+  are reloaded from storage on next use; see `tree_cache_stats/0`.
+
+  ## Returns
+
+  * `{:ok, message}` on success
+  * `{:error, reason}` for an unknown backend or once storage is already in use
+  """
+  @spec configure_tree_storage(StorageConfig.t()) :: {:ok, String.t()} | {:error, String.t()}
+  def configure_tree_storage(_config), do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Creates a new compressed NFT tree configuration.
 
@@ -102,19 +164,33 @@ defmodule BubblegumNif do
   * `max_buffer_size` - Maximum buffer size for the tree
   * `public_key` - Base58 encoded public key of the tree authority
   * `canopy_depth` - Optional depth of the canopy
+  * `options` - Optional `BubblegumNif.Types.TransactionOptions` setting the
+    compute-unit limit and priority fee of the transaction
 
   ## Returns
 
-  * `{:ok, signature}` on success
-  * `{:error, reason}` on failure
+  * `{:ok, signature}` once the transaction is confirmed
+  * `{:error, {:failed, signature, reason}}` if the transaction landed with
+    an error
+  * `{:error, {:expired, signature}}` if it never landed; it is signed with
+    a fresh blockhash at most three times, and only once the previous one
+    can no longer land
+  * `{:error, {:unknown, signature}}` if its status cannot be read 10 times
+    in a row or it is not confirmed within two minutes; the transaction may
+    still land
+  * `{:error, reason}` with a message for errors before it was sent; see
+    `t:send_error/0`
+  * `{:ok, transaction}` with the base64 encoded unsigned transaction when
+    `options` name a `nonce_account` or set `build_only`; see `partially_sign/2`
   """
   @spec create_tree_config(
     max_depth :: non_neg_integer(),
     max_buffer_size :: non_neg_integer(),
     public_key :: String.t(),
-    canopy_depth :: non_neg_integer() | nil
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def create_tree_config(_max_depth, _max_buffer_size, _public_key, _canopy_depth),
+    canopy_depth :: non_neg_integer() | nil,
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def create_tree_config(_max_depth, _max_buffer_size, _public_key, _canopy_depth, _options \\ nil),
     do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
@@ -126,6 +202,7 @@ defmodule BubblegumNif do
   * `leaf_owner` - Base58 encoded public key of the leaf owner
   * `leaf_delegate` - Base58 encoded public key of the leaf delegate
   * `metadata` - A `BubblegumNif.Types.MetadataArgs` struct containing the NFT metadata
+  * `options` - Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
 
@@ -136,9 +213,10 @@ defmodule BubblegumNif do
     tree_authority :: String.t(),
     leaf_owner :: String.t(),
     leaf_delegate :: String.t(),
-    metadata :: MetadataArgs.t()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def mint_v1(_tree_authority, _leaf_owner, _leaf_delegate, _metadata),
+    metadata :: MetadataArgs.t(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def mint_v1(_tree_authority, _leaf_owner, _leaf_delegate, _metadata, _options \\ nil),
     do: :erlang.nif_error(:nif_not_loaded)
 
   @doc """
@@ -150,28 +228,32 @@ defmodule BubblegumNif do
   * `leaf_owner` - Base58 encoded public key of the current leaf owner
   * `new_leaf_owner` - Base58 encoded public key of the new leaf owner
   * `merkle_tree` - Base58 encoded public key of the merkle tree
-  * `root` - Current root hash of the merkle tree
-  * `data_hash` - Hash of the NFT data
-  * `creator_hash` - Hash of the NFT creator
+  * `root` - Base58 encoded current root hash of the merkle tree
+  * `data_hash` - Base58 encoded hash of the NFT data
+  * `creator_hash` - Base58 encoded hash of the NFT creators
   * `nonce` - Nonce of the leaf
   * `index` - Index of the leaf in the tree
+  * `options` - Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
+
+  Each hash must decode to exactly 32 bytes.
 
   ## Returns
 
   * `{:ok, signature}` on success
-  * `{:error, reason}` on failure
+  * `{:error, reason}` on failure, e.g. `"Invalid hash: ..."` for a malformed hash
   """
   @spec transfer(
     tree_authority :: String.t(),
     leaf_owner :: String.t(),
     new_leaf_owner :: String.t(),
     merkle_tree :: String.t(),
-    root :: binary(),
-    data_hash :: binary(),
-    creator_hash :: binary(),
+    root :: String.t(),
+    data_hash :: String.t(),
+    creator_hash :: String.t(),
     nonce :: non_neg_integer(),
-    index :: non_neg_integer()
-  ) :: {:ok, String.t()} | {:error, String.t()}
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
   def transfer(
     _tree_authority,
     _leaf_owner,
@@ -181,9 +263,47 @@ defmodule BubblegumNif do
     _data_hash,
     _creator_hash,
     _nonce,
-    _index
+    _index,
+    _options \\ nil
   ), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Transfers a compressed NFT, deriving `data_hash` and `creator_hash` from the
+  metadata it was minted with instead of taking them from the caller.
+
+  Takes the same arguments as `transfer/9`, with `metadata` in place of
+  `data_hash` and `creator_hash`.
+  """
+  @spec transfer(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    new_leaf_owner :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer()
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def transfer(tree_authority, leaf_owner, new_leaf_owner, merkle_tree, root, metadata, nonce, index),
+    do: transfer_with_metadata(tree_authority, leaf_owner, new_leaf_owner, merkle_tree, root, metadata, nonce, index, nil)
+
+  @doc """
+  `transfer/8` with `options` as for `transfer/10`.
+  """
+  @spec transfer_with_metadata(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    new_leaf_owner :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def transfer_with_metadata(_tree_authority, _leaf_owner, _new_leaf_owner, _merkle_tree, _root, _metadata, _nonce, _index, _options),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Requests an airdrop of SOL tokens for testing purposes (devnet only).
 
@@ -214,6 +334,7 @@ defmodule BubblegumNif do
   - creator_hash: The hash of the creator data
   - nonce: The nonce of the leaf
   - index: The index of the leaf in the tree
+  - options: Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
   - `{:ok, signature}` on success
@@ -228,15 +349,50 @@ defmodule BubblegumNif do
     data_hash :: String.t(),
     creator_hash :: String.t(),
     nonce :: non_neg_integer(),
-    index :: non_neg_integer()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def decompress_v1(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index) do
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def decompress_v1(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index, _options \\ nil) do
     case :erlang.nif_error(:nif_not_loaded) do
       {:ok, signature} -> {:ok, signature}
       {:error, reason} -> {:error, reason}
     end
   end
 
+  @doc """
+  Decompresses a compressed NFT, deriving `data_hash` and `creator_hash` from
+  `metadata`. Otherwise identical to `decompress_v1/9`.
+  """
+  @spec decompress_v1(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer()
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def decompress_v1(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, metadata, nonce, index),
+    do: decompress_v1_with_metadata(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, metadata, nonce, index, nil)
+
+  @doc """
+  `decompress_v1/8` with `options` as for `decompress_v1/10`.
+  """
+  @spec decompress_v1_with_metadata(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def decompress_v1_with_metadata(_tree_authority, _leaf_owner, _leaf_delegate, _merkle_tree, _root, _metadata, _nonce, _index, _options),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Delegates authority over a compressed NFT to a new delegate.
 
@@ -251,6 +407,7 @@ defmodule BubblegumNif do
   - creator_hash: The hash of the creator data
   - nonce: The nonce of the leaf
   - index: The index of the leaf in the tree
+  - options: Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
   - `{:ok, signature}` on success
@@ -266,15 +423,52 @@ defmodule BubblegumNif do
     data_hash :: String.t(),
     creator_hash :: String.t(),
     nonce :: non_neg_integer(),
-    index :: non_neg_integer()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def delegate(tree_authority, leaf_owner, previous_leaf_delegate, new_leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index) do
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def delegate(tree_authority, leaf_owner, previous_leaf_delegate, new_leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index, _options \\ nil) do
     case :erlang.nif_error(:nif_not_loaded) do
       {:ok, signature} -> {:ok, signature}
       {:error, reason} -> {:error, reason}
     end
   end
 
+  @doc """
+  Delegates authority over a compressed NFT, deriving `data_hash` and
+  `creator_hash` from `metadata`. Otherwise identical to `delegate/10`.
+  """
+  @spec delegate(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    previous_leaf_delegate :: String.t(),
+    new_leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer()
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def delegate(tree_authority, leaf_owner, previous_leaf_delegate, new_leaf_delegate, merkle_tree, root, metadata, nonce, index),
+    do: delegate_with_metadata(tree_authority, leaf_owner, previous_leaf_delegate, new_leaf_delegate, merkle_tree, root, metadata, nonce, index, nil)
+
+  @doc """
+  `delegate/9` with `options` as for `delegate/11`.
+  """
+  @spec delegate_with_metadata(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    previous_leaf_delegate :: String.t(),
+    new_leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def delegate_with_metadata(_tree_authority, _leaf_owner, _previous_leaf_delegate, _new_leaf_delegate, _merkle_tree, _root, _metadata, _nonce, _index, _options),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Redeems a compressed NFT, preparing it for decompression.
 
@@ -288,6 +482,7 @@ defmodule BubblegumNif do
   - creator_hash: The hash of the creator data
   - nonce: The nonce of the leaf
   - index: The index of the leaf in the tree
+  - options: Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
   - `{:ok, signature}` on success
@@ -302,15 +497,50 @@ defmodule BubblegumNif do
     data_hash :: String.t(),
     creator_hash :: String.t(),
     nonce :: non_neg_integer(),
-    index :: non_neg_integer()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def redeem(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index) do
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def redeem(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, data_hash, creator_hash, nonce, index, _options \\ nil) do
     case :erlang.nif_error(:nif_not_loaded) do
       {:ok, signature} -> {:ok, signature}
       {:error, reason} -> {:error, reason}
     end
   end
 
+  @doc """
+  Redeems a compressed NFT, deriving `data_hash` and `creator_hash` from
+  `metadata`. Otherwise identical to `redeem/9`.
+  """
+  @spec redeem(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer()
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def redeem(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, metadata, nonce, index),
+    do: redeem_with_metadata(tree_authority, leaf_owner, leaf_delegate, merkle_tree, root, metadata, nonce, index, nil)
+
+  @doc """
+  `redeem/8` with `options` as for `redeem/10`.
+  """
+  @spec redeem_with_metadata(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def redeem_with_metadata(_tree_authority, _leaf_owner, _leaf_delegate, _merkle_tree, _root, _metadata, _nonce, _index, _options),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Cancels a pending redemption of a compressed NFT.
 
@@ -323,6 +553,7 @@ defmodule BubblegumNif do
   - creator_hash: The hash of the creator data
   - nonce: The nonce of the leaf
   - index: The index of the leaf in the tree
+  - options: Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
   - `{:ok, signature}` on success
@@ -336,15 +567,48 @@ defmodule BubblegumNif do
     data_hash :: String.t(),
     creator_hash :: String.t(),
     nonce :: non_neg_integer(),
-    index :: non_neg_integer()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def cancel_redeem(tree_authority, leaf_owner, merkle_tree, root, data_hash, creator_hash, nonce, index) do
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def cancel_redeem(tree_authority, leaf_owner, merkle_tree, root, data_hash, creator_hash, nonce, index, _options \\ nil) do
     case :erlang.nif_error(:nif_not_loaded) do
       {:ok, signature} -> {:ok, signature}
       {:error, reason} -> {:error, reason}
     end
   end
 
+  @doc """
+  Cancels a pending redemption, deriving `data_hash` and `creator_hash` from
+  `metadata`. Otherwise identical to `cancel_redeem/8`.
+  """
+  @spec cancel_redeem(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer()
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def cancel_redeem(tree_authority, leaf_owner, merkle_tree, root, metadata, nonce, index),
+    do: cancel_redeem_with_metadata(tree_authority, leaf_owner, merkle_tree, root, metadata, nonce, index, nil)
+
+  @doc """
+  `cancel_redeem/7` with `options` as for `cancel_redeem/9`.
+  """
+  @spec cancel_redeem_with_metadata(
+    tree_authority :: String.t(),
+    leaf_owner :: String.t(),
+    merkle_tree :: String.t(),
+    root :: String.t(),
+    metadata :: MetadataArgs.t(),
+    nonce :: non_neg_integer(),
+    index :: non_neg_integer(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def cancel_redeem_with_metadata(_tree_authority, _leaf_owner, _merkle_tree, _root, _metadata, _nonce, _index, _options),
+    do: :erlang.nif_error(:nif_not_loaded)
+
   @doc """
   Compresses a regular SPL token into a compressed NFT.
 
@@ -355,6 +619,7 @@ defmodule BubblegumNif do
   - merkle_tree: The public key of the merkle tree
   - token_account: The public key of the token account to compress
   - mint: The public key of the token mint
+  - options: Optional `BubblegumNif.Types.TransactionOptions`, as for `create_tree_config/5`
 
   ## Returns
   - `{:ok, signature}` on success
@@ -366,12 +631,405 @@ defmodule BubblegumNif do
     leaf_delegate :: String.t(),
     merkle_tree :: String.t(),
     token_account :: String.t(),
-    mint :: String.t()
-  ) :: {:ok, String.t()} | {:error, String.t()}
-  def compress(tree_authority, leaf_owner, leaf_delegate, merkle_tree, token_account, mint) do
+    mint :: String.t(),
+    options :: TransactionOptions.t() | nil
+  ) :: {:ok, String.t()} | {:error, send_error()}
+  def compress(tree_authority, leaf_owner, leaf_delegate, merkle_tree, token_account, mint, _options \\ nil) do
     case :erlang.nif_error(:nif_not_loaded) do
       {:ok, signature} -> {:ok, signature}
       {:error, reason} -> {:error, reason}
     end
   end
-end
\ No newline at end of file
+
+  @doc """
+  Computes the Bubblegum data hash for the given metadata, exactly as `mint_v1` stores it.
+
+  ## Returns
+
+  * `{:ok, data_hash}` with the base58 encoded hash on success
+  * `{:error, reason}` on failure
+  """
+  @spec compute_data_hash(MetadataArgs.t()) :: {:ok, String.t()} | {:error, String.t()}
+  def compute_data_hash(_metadata), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Computes the Bubblegum creator hash for the given list of creators.
+
+  ## Returns
+
+  * `{:ok, creator_hash}` with the base58 encoded hash on success
+  * `{:error, reason}` on failure
+  """
+  @spec compute_creator_hash([Creator.t()]) :: {:ok, String.t()} | {:error, String.t()}
+  def compute_creator_hash(_creators), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Derives the asset id of the compressed NFT minted into `merkle_tree` with `nonce`.
+
+  ## Returns
+
+  * `{:ok, asset_id}` on success
+  * `{:error, reason}` on failure
+  """
+  @spec get_asset_id(merkle_tree :: String.t(), nonce :: non_neg_integer()) ::
+    {:ok, String.t()} | {:error, String.t()}
+  def get_asset_id(_merkle_tree, _nonce), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Computes the full V1 leaf schema that `mint_v1` creates for the given owner,
+  delegate, nonce and metadata.
+
+  ## Parameters
+
+  * `merkle_tree` - Base58 encoded public key of the merkle tree
+  * `leaf_owner` - Base58 encoded public key of the leaf owner
+  * `leaf_delegate` - Base58 encoded public key of the leaf delegate
+  * `nonce` - Nonce of the leaf (the tree's mint count at mint time)
+  * `metadata` - A `BubblegumNif.Types.MetadataArgs` struct
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.LeafSchema{}}` on success
+  * `{:error, reason}` on failure
+  """
+  @spec compute_leaf(
+    merkle_tree :: String.t(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    nonce :: non_neg_integer(),
+    metadata :: MetadataArgs.t()
+  ) :: {:ok, LeafSchema.t()} | {:error, String.t()}
+  def compute_leaf(_merkle_tree, _leaf_owner, _leaf_delegate, _nonce, _metadata),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Fetches and decodes an spl-account-compression merkle tree account.
+
+  ## Parameters
+
+  * `merkle_tree` - Base58 encoded public key of the merkle tree account
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.TreeAccount{}}` with the sequence number, active
+    changelog index, current root and canopy nodes (all base58 encoded)
+  * `{:error, reason}` if the account cannot be fetched or is not a merkle tree
+  """
+  @spec fetch_tree_account(merkle_tree :: String.t()) ::
+    {:ok, TreeAccount.t()} | {:error, String.t()}
+  def fetch_tree_account(_merkle_tree), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Starts tracking a merkle tree locally, with the depth, buffer size, canopy,
+  authority and creation slot of its on-chain account. Only trees that have
+  not changed on chain yet can be opened for the first time.
+
+  Transfers, delegations, redemptions and decompressions on a managed tree
+  carry the leaf's proof, minus the levels its canopy holds, as remaining
+  accounts, as long as the local copy is at the root they are given. Send
+  them through `with_leaf_change/4` to keep the local copy in step.
+
+  ## Parameters
+
+  * `merkle_tree` - Base58 encoded public key of the merkle tree account
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.TreeDescriptor{}}` on success
+  * `{:error, reason}` if the tree is already managed with different parameters
+  """
+  @spec manage_tree(merkle_tree :: String.t()) :: {:ok, TreeDescriptor.t()} | {:error, String.t()}
+  def manage_tree(_merkle_tree), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Lists the trees tracked by the local tree manager, ordered by address.
+  """
+  @spec list_managed_trees() :: {:ok, [TreeDescriptor.t()]} | {:error, String.t()}
+  def list_managed_trees(), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Builds a single inclusion proof for several leaves of the locally managed
+  tree, sending each shared interior node only once.
+
+  ## Parameters
+
+  * `merkle_tree` - Base58 encoded public key of a tree opened with `manage_tree/1`
+  * `indices` - Leaf indices to prove, in any order
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.MultiProof{}}` with the base58 root, the sorted
+    and deduplicated indices, and the base64 encoded proof
+  * `{:error, reason}` on failure
+  """
+  @spec get_multi_proof(merkle_tree :: String.t(), indices :: [non_neg_integer()]) ::
+    {:ok, MultiProof.t()} | {:error, String.t()}
+  def get_multi_proof(_merkle_tree, _indices), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Rebuilds the proof for a leaf of the locally managed tree as it stood right
+  after sequence number `seq`, e.g. when a sale settled.
+
+  History is recorded only for trees created while the `BUBBLEGUM_ROOT_HISTORY`
+  environment variable (the number of recent roots kept in memory per tree) is
+  set; every changelog is then persisted alongside the tree.
+
+  Past states are rebuilt by replaying every changelog up to `seq`, so the
+  cost grows with `seq`. Sequence numbers past `BUBBLEGUM_MAX_PROOF_REPLAY`
+  (1,048,576 changelogs by default) are refused, except the tree's current one.
+
+  ## Parameters
+
+  * `merkle_tree` - Base58 encoded public key of a tree opened with `manage_tree/1`
+  * `index` - Index of the leaf in the tree
+  * `seq` - Sequence number of the change after which the proof should hold
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.HistoricalProof{}}` with the leaf, proof and root
+    at that point, all base58 encoded
+  * `{:error, reason}` if history is unavailable, the leaf did not exist yet
+    or `seq` is past the replay limit
+  """
+  @spec get_proof_at(
+    merkle_tree :: String.t(),
+    index :: non_neg_integer(),
+    seq :: non_neg_integer()
+  ) :: {:ok, HistoricalProof.t()} | {:error, String.t()}
+  def get_proof_at(_merkle_tree, _index, _seq), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Verifies a proof returned by `get_multi_proof/2`.
+
+  ## Parameters
+
+  * `root` - Base58 encoded root the proof was built against
+  * `leaves` - Base58 encoded leaf hashes, in the order of the proof's indices
+  * `proof` - Base64 encoded multi-proof
+
+  ## Returns
+
+  * `{:ok, true | false}`
+  * `{:error, reason}` if an argument cannot be decoded
+  """
+  @spec verify_multi_proof(root :: String.t(), leaves :: [String.t()], proof :: String.t()) ::
+    {:ok, boolean()} | {:error, String.t()}
+  def verify_multi_proof(_root, _leaves, _proof), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Indexes the leaf at `index` of a managed tree with the owner, delegate, nonce
+  and hashes `mint_v1` gives it, replacing any earlier record for that index.
+  Record a leaf again after a transfer or delegation to keep the index current.
+
+  Only the `"sqlite"` storage backend keeps the index; the others accept and
+  drop records.
+
+  ## Returns
+
+  * `{:ok, %BubblegumNif.Types.LeafRecord{}}` on success
+  * `{:error, reason}` if the tree is not managed or an argument is invalid
+  """
+  @spec record_leaf(
+    merkle_tree :: String.t(),
+    index :: non_neg_integer(),
+    leaf_owner :: String.t(),
+    leaf_delegate :: String.t(),
+    nonce :: non_neg_integer(),
+    metadata :: MetadataArgs.t()
+  ) :: {:ok, LeafRecord.t()} | {:error, String.t()}
+  def record_leaf(_merkle_tree, _index, _leaf_owner, _leaf_delegate, _nonce, _metadata),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Lists the recorded leaves of `merkle_tree` owned by `owner`, ordered by index.
+  Fails unless the storage backend indexes leaves.
+  """
+  @spec find_leaves_by_owner(merkle_tree :: String.t(), owner :: String.t()) ::
+    {:ok, [LeafRecord.t()]} | {:error, String.t()}
+  def find_leaves_by_owner(_merkle_tree, _owner), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Lists the recorded leaves of `merkle_tree` in `collection`, ordered by index.
+  Fails unless the storage backend indexes leaves.
+  """
+  @spec find_leaves_by_collection(merkle_tree :: String.t(), collection :: String.t()) ::
+    {:ok, [LeafRecord.t()]} | {:error, String.t()}
+  def find_leaves_by_collection(_merkle_tree, _collection), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Logs a change to a managed tree whose transaction is about to be sent,
+  without applying it yet. `leaf` alone appends it, `index` and `leaf`
+  replace that leaf, and `index` alone removes it; leaves are base58 encoded
+  hashes. Requires a write-ahead log (`wal_path`), which keeps the change
+  across restarts until `commit_leaf/2` or `abort_leaf/1`.
+
+  ## Returns
+
+  * `{:ok, id}` to pass to `commit_leaf/2` or `abort_leaf/1`
+  * `{:error, reason}` if the tree is not managed or there is no log
+  """
+  @spec begin_leaf(
+    merkle_tree :: String.t(),
+    index :: non_neg_integer() | nil,
+    leaf :: String.t() | nil
+  ) :: {:ok, non_neg_integer()} | {:error, String.t()}
+  def begin_leaf(_merkle_tree, _index, _leaf), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Applies the change `id` from `begin_leaf/3` once its transaction,
+  `signature`, is confirmed on chain.
+
+  ## Returns
+
+  * `{:ok, [{index, root}]}` with each leaf touched and the base58 encoded
+    root after it
+  * `{:error, reason}` if `id` is not pending
+  """
+  @spec commit_leaf(id :: non_neg_integer(), signature :: String.t()) ::
+    {:ok, [{non_neg_integer(), String.t()}]} | {:error, String.t()}
+  def commit_leaf(_id, _signature), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Drops the change `id` from `begin_leaf/3` after its transaction failed or
+  expired.
+  """
+  @spec abort_leaf(id :: non_neg_integer()) :: {:ok, non_neg_integer()} | {:error, String.t()}
+  def abort_leaf(_id), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Logs a leaf change with `begin_leaf/3`, runs `send`, and commits the
+  change if it returns `{:ok, signature}` or aborts it otherwise, so the
+  local tree only follows confirmed transactions:
+
+      BubblegumNif.with_leaf_change(merkle_tree, index, new_leaf, fn ->
+        BubblegumNif.transfer(tree_authority, owner, new_owner, merkle_tree,
+          root, data_hash, creator_hash, nonce, index)
+      end)
+
+  Returns what `commit_leaf/2` does, or the error that stopped it. After
+  `{:error, {:unknown, signature}}` the change is left pending, since the
+  transaction may still land; commit or abort it once its status is known.
+  """
+  @spec with_leaf_change(
+    merkle_tree :: String.t(),
+    index :: non_neg_integer() | nil,
+    leaf :: String.t() | nil,
+    send :: (-> {:ok, String.t()} | {:error, send_error()})
+  ) :: {:ok, [{non_neg_integer(), String.t()}]} | {:error, send_error()}
+  def with_leaf_change(merkle_tree, index, leaf, send) do
+    with {:ok, id} <- begin_leaf(merkle_tree, index, leaf) do
+      case send.() do
+        {:ok, signature} ->
+          commit_leaf(id, signature)
+
+        {:error, {:unknown, _signature}} = error ->
+          error
+
+        {:error, reason} ->
+          abort_leaf(id)
+          {:error, reason}
+      end
+    end
+  end
+
+  @doc """
+  Returns the tree manager's cache counters: lookups served from memory
+  (`hits`) and from storage (`misses`), trees evicted under the memory
+  budget, and the trees and approximate bytes resident now.
+  """
+  @spec tree_cache_stats() :: {:ok, CacheStats.t()} | {:error, String.t()}
+  def tree_cache_stats, do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Creates an address lookup table holding the static accounts of
+  `merkle_tree` (the tree itself, its tree authority, the log wrapper, the
+  compression program and Bubblegum), or adds any it lacks to the table
+  already registered for the tree. The loaded keypair is its authority and
+  pays for it.
+
+  Transactions on the tree are then sent as v0 messages using the table
+  whenever a legacy transaction would exceed 1232 bytes, e.g. with long
+  proofs. New addresses are usable from the next slot.
+
+  ## Returns
+
+  * `{:ok, table}` with the base58 encoded table address
+  * `{:error, reason}` on failure
+  """
+  @spec create_tree_lookup_table(merkle_tree :: String.t()) :: {:ok, String.t()} | {:error, send_error()}
+  def create_tree_lookup_table(_merkle_tree), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Uses an existing lookup table for the transactions of `merkle_tree`, e.g.
+  one created elsewhere. For a managed tree, the table is stored with its
+  descriptor, as `create_tree_lookup_table/1` does, and used again after a
+  restart.
+  """
+  @spec register_tree_lookup_table(merkle_tree :: String.t(), table :: String.t()) ::
+    {:ok, String.t()} | {:error, String.t()}
+  def register_tree_lookup_table(_merkle_tree, _table), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Creates a durable nonce account, paid for by the loaded keypair. The
+  account is advanced by `authority`, the loaded keypair by default.
+
+  Pass it as `nonce_account` in `TransactionOptions` to get unsigned
+  transactions that stay valid until the nonce is advanced, rather than
+  for the ~60 seconds of a recent blockhash. Each such transaction starts
+  by advancing the nonce, so its `authority` must sign it.
+
+  ## Returns
+
+  * `{:ok, address}` with the base58 encoded account address
+  * `{:error, reason}` on failure
+  """
+  @spec create_nonce_account(authority :: String.t() | nil) :: {:ok, String.t()} | {:error, send_error()}
+  def create_nonce_account(_authority \\ nil), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Reads the current value and authority of the durable nonce account
+  `nonce_account`.
+  """
+  @spec get_nonce(nonce_account :: String.t()) :: {:ok, NonceAccount.t()} | {:error, String.t()}
+  def get_nonce(_nonce_account), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Loads a keypair, given as a JSON byte array like `load_keypair/1`, into a
+  handle for `partially_sign/2`. Unlike `load_keypair/1`, it does not become
+  the keypair the other NIFs sign with.
+  """
+  @spec load_signer(keypair_json :: String.t()) :: {:ok, reference()} | {:error, String.t()}
+  def load_signer(_keypair_json), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Adds one signature to a base64 encoded transaction, such as one built with
+  `build_only` or a `nonce_account` in `TransactionOptions`. Signs with
+  `signer`, a handle from `load_signer/1`, or with the loaded keypair when it
+  is `nil`. Other signatures are kept, so a wallet, multisig member or HSM
+  can sign the same transaction before or after.
+
+  ## Returns
+
+  * `{:ok, transaction}` with the base64 encoded transaction
+  * `{:error, reason}` if `signer` is not one of its signers
+  """
+  @spec partially_sign(tx :: String.t(), signer :: reference() | nil) ::
+    {:ok, String.t()} | {:error, String.t()}
+  def partially_sign(_tx, _signer \\ nil), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sends a base64 encoded transaction once all its signers have signed, and
+  waits until it lands or can no longer land. Transactions built on a durable
+  nonce stay valid until the nonce is advanced; others expire with their
+  blockhash and are not re-signed.
+
+  ## Returns
+
+  * `{:ok, signature}` once the transaction is confirmed
+  * `{:error, reason}` if a signature is missing or invalid
+  * `{:error, {:failed, signature, reason}}`, `{:error, {:expired, signature}}`
+    or `{:error, {:unknown, signature}}` as for `create_tree_config/5`
+  """
+  @spec submit_signed(tx :: String.t()) :: {:ok, String.t()} | {:error, send_error()}
+  def submit_signed(_tx), do: :erlang.nif_error(:nif_not_loaded)
+end
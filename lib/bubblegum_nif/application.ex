@@ -7,6 +7,8 @@ defmodule BubblegumNif.Application do
 
   @impl true
   def start(_type, _args) do
+    configure_tree_storage()
+
     children = [
       # Starts a worker by calling: BubblegumNif.Worker.start_link(arg)
       # {BubblegumNif.Worker, arg}
@@ -17,4 +19,22 @@ defmodule BubblegumNif.Application do
     opts = [strategy: :one_for_one, name: BubblegumNif.Supervisor]
     Supervisor.start_link(children, opts)
   end
+
+  defp configure_tree_storage do
+    case Application.get_env(:bubblegum_nif, :tree_storage) do
+      nil ->
+        :ok
+
+      opts ->
+        config = %BubblegumNif.Types.StorageConfig{
+          backend: opts |> Keyword.fetch!(:backend) |> to_string(),
+          path: opts[:path],
+          wal_path: opts[:wal_path],
+          memory_budget: opts[:memory_budget]
+        }
+
+        {:ok, _} = BubblegumNif.configure_tree_storage(config)
+        :ok
+    end
+  end
 end
@@ -55,4 +55,240 @@ defmodule BubblegumNif.Types do
       collection: String.t() | nil
     }
   end
-end 
\ No newline at end of file
+
+  defmodule LeafSchema do
+    @moduledoc """
+    Bubblegum V1 leaf schema. Hashes are base58 encoded.
+    """
+    defstruct [
+      :asset_id,
+      :owner,
+      :delegate,
+      :nonce,
+      :data_hash,
+      :creator_hash,
+      :leaf_hash
+    ]
+
+    @type t :: %__MODULE__{
+      asset_id: String.t(),
+      owner: String.t(),
+      delegate: String.t(),
+      nonce: non_neg_integer(),
+      data_hash: String.t(),
+      creator_hash: String.t(),
+      leaf_hash: String.t()
+    }
+  end
+
+  defmodule TreeAccount do
+    @moduledoc """
+    On-chain state of a concurrent merkle tree account. Keys and nodes are
+    base58 encoded; canopy nodes that were never written are all zeros.
+    """
+    defstruct [
+      :merkle_tree,
+      :authority,
+      :max_depth,
+      :max_buffer_size,
+      :canopy_depth,
+      :creation_slot,
+      :sequence_number,
+      :active_index,
+      :num_leaves,
+      :current_root,
+      :canopy
+    ]
+
+    @type t :: %__MODULE__{
+      merkle_tree: String.t(),
+      authority: String.t(),
+      max_depth: non_neg_integer(),
+      max_buffer_size: non_neg_integer(),
+      canopy_depth: non_neg_integer(),
+      creation_slot: non_neg_integer(),
+      sequence_number: non_neg_integer(),
+      active_index: non_neg_integer(),
+      num_leaves: non_neg_integer(),
+      current_root: String.t(),
+      canopy: [String.t()]
+    }
+  end
+
+  defmodule TreeDescriptor do
+    @moduledoc """
+    Parameters of a tree tracked by the local tree manager. Keys are base58 encoded.
+    `lookup_table` is the address lookup table used for the tree's transactions, if any.
+    """
+    defstruct [
+      :merkle_tree,
+      :authority,
+      :max_depth,
+      :max_buffer_size,
+      :canopy_depth,
+      :creation_slot,
+      :lookup_table
+    ]
+
+    @type t :: %__MODULE__{
+      merkle_tree: String.t(),
+      authority: String.t(),
+      max_depth: non_neg_integer(),
+      max_buffer_size: non_neg_integer(),
+      canopy_depth: non_neg_integer(),
+      creation_slot: non_neg_integer(),
+      lookup_table: String.t() | nil
+    }
+  end
+
+  defmodule LeafRecord do
+    @moduledoc """
+    A Bubblegum leaf indexed by the local tree manager. Keys and hashes are
+    base58 encoded; `collection` is `nil` for leaves minted outside one.
+    """
+    defstruct [
+      :merkle_tree,
+      :index,
+      :asset_id,
+      :owner,
+      :delegate,
+      :nonce,
+      :data_hash,
+      :creator_hash,
+      :collection
+    ]
+
+    @type t :: %__MODULE__{
+      merkle_tree: String.t(),
+      index: non_neg_integer(),
+      asset_id: String.t(),
+      owner: String.t(),
+      delegate: String.t(),
+      nonce: non_neg_integer(),
+      data_hash: String.t(),
+      creator_hash: String.t(),
+      collection: String.t() | nil
+    }
+  end
+
+  defmodule StorageConfig do
+    @moduledoc """
+    Where the local tree manager keeps its trees. `backend` is `"rocksdb"`,
+    `"file"`, `"sqlite"` or `"memory"`; `path` is the directory (or, for
+    SQLite, the database file) used by all but the last. `wal_path` is the
+    write-ahead log replayed at startup, `path <> ".wal"` by default; the
+    memory backend has none unless one is given. `memory_budget` caps, in
+    bytes, the trees kept in memory; `nil` keeps every tree once loaded.
+    """
+    defstruct [:backend, :path, :wal_path, :memory_budget]
+
+    @type t :: %__MODULE__{
+      backend: String.t(),
+      path: String.t() | nil,
+      wal_path: String.t() | nil,
+      memory_budget: non_neg_integer() | nil
+    }
+  end
+
+  defmodule CacheStats do
+    @moduledoc """
+    Counters for the trees the local tree manager keeps in memory. Hits and
+    misses count tree lookups served from memory and from storage.
+    """
+    defstruct [:hits, :misses, :evictions, :resident_trees, :resident_bytes]
+
+    @type t :: %__MODULE__{
+      hits: non_neg_integer(),
+      misses: non_neg_integer(),
+      evictions: non_neg_integer(),
+      resident_trees: non_neg_integer(),
+      resident_bytes: non_neg_integer()
+    }
+  end
+
+  defmodule TransactionOptions do
+    @moduledoc """
+    Per-call settings for the transactions a NIF sends; `nil` fields keep
+    the defaults. `priority_fee` is a fixed price in micro-lamports per
+    compute unit, `priority_fee_percentile` (0-100) picks one from the fees
+    recently paid for the same writable accounts; set at most one of them.
+    `max_priority_fee` caps either. The compute-unit limit is the units used
+    in simulation plus `compute_unit_margin` percent (10 by default), unless
+    `compute_unit_limit` fixes it.
+
+    With a `nonce_account`, nothing is sent: the NIF returns the base64
+    encoded unsigned transaction, made durable by the account's nonce and
+    paid for by the operation's payer, for signing elsewhere. `build_only`
+    does the same with a recent blockhash, so the transaction must be
+    signed and submitted within about a minute. `payer` overrides the fee
+    payer; operations the loaded keypair pays for when sent are paid for by
+    the leaf owner when only built.
+    """
+    defstruct [
+      :priority_fee,
+      :priority_fee_percentile,
+      :max_priority_fee,
+      :compute_unit_margin,
+      :compute_unit_limit,
+      :nonce_account,
+      :build_only,
+      :payer
+    ]
+
+    @type t :: %__MODULE__{
+      priority_fee: non_neg_integer() | nil,
+      priority_fee_percentile: 0..100 | nil,
+      max_priority_fee: non_neg_integer() | nil,
+      compute_unit_margin: non_neg_integer() | nil,
+      compute_unit_limit: pos_integer() | nil,
+      nonce_account: String.t() | nil,
+      build_only: boolean() | nil,
+      payer: String.t() | nil
+    }
+  end
+
+  defmodule NonceAccount do
+    @moduledoc """
+    A durable nonce account. `nonce` is the base58 encoded value transactions
+    built on the account use as their recent blockhash.
+    """
+    defstruct [:address, :authority, :nonce, :lamports_per_signature]
+
+    @type t :: %__MODULE__{
+      address: String.t(),
+      authority: String.t(),
+      nonce: String.t(),
+      lamports_per_signature: non_neg_integer()
+    }
+  end
+
+  defmodule MultiProof do
+    @moduledoc """
+    Inclusion proof covering several leaves. `proof` is base64 encoded and
+    self-describing; `indices` lists the leaves it covers, in proof order.
+    """
+    defstruct [:root, :indices, :proof]
+
+    @type t :: %__MODULE__{
+      root: String.t(),
+      indices: [non_neg_integer()],
+      proof: String.t()
+    }
+  end
+
+  defmodule HistoricalProof do
+    @moduledoc """
+    Proof for a leaf as of an earlier sequence number. `leaf`, `proof` and
+    `root` are base58 encoded and hold the values at `seq`.
+    """
+    defstruct [:index, :seq, :leaf, :proof, :root]
+
+    @type t :: %__MODULE__{
+      index: non_neg_integer(),
+      seq: non_neg_integer(),
+      leaf: String.t(),
+      proof: [String.t()],
+      root: String.t()
+    }
+  end
+end
@@ -180,9 +180,9 @@ defmodule BubblegumNifWeb.NFTLive do
       params["current_owner"],
       params["new_owner"],
       params["merkle_tree"],
-      <<0::256>>,
-      <<0::256>>,
-      <<0::256>>,
+      Base58.encode(<<0::256>>),
+      Base58.encode(<<0::256>>),
+      Base58.encode(<<0::256>>),
       0,
       String.to_integer(params["index"])
     ) do
@@ -0,0 +1,309 @@
+use crate::memory_storage::{TreeParts, TreeUpdate};
+use crate::merkle::{ChangeLog, MerkleTree, Subtree};
+use crate::tree_manager::{decode_descriptor, TreeDescriptor, TreeStorage};
+use crate::wal::{append_records, read_records};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::{de::DeserializeOwned, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+const DESCRIPTOR_FILE: &str = "descriptor";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+const HISTORY_FILE: &str = "history";
+const SUBTREES_DIR: &str = "subtrees";
+
+// The log is folded into a new snapshot once it outgrows both this and the snapshot.
+const MIN_COMPACTION_BYTES: u64 = 1 << 20;
+
+/// Lengths of the append-only files of a tree up to their last complete
+/// record, once they have been checked in this process.
+#[derive(Default)]
+struct TreeFiles {
+    log_len: Option<u64>,
+    history_len: Option<u64>,
+}
+
+/// Stores each tree in its own directory under `root`:
+///
+/// * `descriptor` - the tree's `TreeDescriptor`
+/// * `snapshot` - the tree's nodes, changelog buffer and header as of the last compaction
+/// * `log` - one record per `save_tree` since then, replayed on top of the snapshot
+/// * `history` - every changelog recorded for history, by sequence number
+/// * `subtrees/<index>` - spilled subtrees
+///
+/// Whole files are replaced atomically by writing a temporary file and renaming
+/// it; a record torn by a crash at the end of a log is dropped on the next read.
+pub struct FileStorage {
+    root: PathBuf,
+    trees: DashMap<Pubkey, Arc<Mutex<TreeFiles>>>,
+}
+
+impl FileStorage {
+    pub fn new(root: PathBuf) -> anyhow::Result<Self> {
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            trees: DashMap::new(),
+        })
+    }
+
+    fn tree_dir(&self, merkle_tree: &Pubkey) -> PathBuf {
+        self.root.join(merkle_tree.to_string())
+    }
+
+    /// Runs `f` with the tree's files locked against other calls for the same tree.
+    fn with_tree<T>(
+        &self,
+        merkle_tree: &Pubkey,
+        f: impl FnOnce(&Path, &mut TreeFiles) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let files = self.trees.entry(*merkle_tree).or_default().clone();
+        let mut files = files.lock().map_err(|_| anyhow::anyhow!("Tree {} lock is poisoned", merkle_tree))?;
+        f(&self.tree_dir(merkle_tree), &mut *files)
+    }
+
+    /// The stored parts of the tree in `dir`: its snapshot with the log replayed on top.
+    fn read_parts(dir: &Path, files: &mut TreeFiles) -> anyhow::Result<Option<TreeParts>> {
+        let mut parts = read_file::<TreeParts>(&dir.join(SNAPSHOT_FILE))?;
+        let (updates, log_len) = read_records::<TreeUpdate>(&dir.join(LOG_FILE))?;
+        files.log_len = Some(log_len);
+
+        for update in updates {
+            parts.get_or_insert_with(TreeParts::default).apply(update);
+        }
+        Ok(parts)
+    }
+
+    /// Folds the log into a fresh snapshot. Replaying the old log over the new
+    /// snapshot is harmless, so a crash between the two steps loses nothing.
+    fn compact(dir: &Path, files: &mut TreeFiles) -> anyhow::Result<()> {
+        if let Some(parts) = Self::read_parts(dir, files)? {
+            write_file(&dir.join(SNAPSHOT_FILE), &parts)?;
+        }
+        File::create(dir.join(LOG_FILE))?.sync_all()?;
+        files.log_len = Some(0);
+        Ok(())
+    }
+}
+
+/// Serializes `value` to `path` through a temporary file, so readers see either
+/// the old contents or the new ones.
+fn write_file<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&bincode::serialize(value)?)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_file<T: DeserializeOwned>(path: &Path) -> anyhow::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl TreeStorage for FileStorage {
+    async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
+        let parts = self.with_tree(merkle_tree, Self::read_parts)?;
+        parts
+            .map(|parts| parts.to_tree())
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("Failed to load tree {}: {}", merkle_tree, e))
+    }
+
+    async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
+        let update = TreeUpdate::from_tree(tree)?;
+        self.with_tree(merkle_tree, |dir, files| {
+            fs::create_dir_all(dir)?;
+
+            // History goes first: if the log append is lost, the tree reloads at the
+            // older state and later saves rewrite the same sequence numbers.
+            if !tree.unsaved_change_logs().is_empty() {
+                let path = dir.join(HISTORY_FILE);
+                let valid_len = match files.history_len {
+                    Some(len) => len,
+                    None => read_records::<(u64, ChangeLog)>(&path)?.1,
+                };
+                files.history_len = Some(append_records(&path, valid_len, tree.unsaved_change_logs())?);
+            }
+
+            let path = dir.join(LOG_FILE);
+            let valid_len = match files.log_len {
+                Some(len) => len,
+                None => read_records::<TreeUpdate>(&path)?.1,
+            };
+            let log_len = append_records(&path, valid_len, &[update])?;
+            files.log_len = Some(log_len);
+
+            let snapshot_len = fs::metadata(dir.join(SNAPSHOT_FILE)).map(|m| m.len()).unwrap_or(0);
+            if log_len > snapshot_len.max(MIN_COMPACTION_BYTES) {
+                Self::compact(dir, files)?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()> {
+        self.with_tree(merkle_tree, |dir, files| {
+            *files = TreeFiles::default();
+            match fs::remove_dir_all(dir) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()> {
+        self.with_tree(merkle_tree, |dir, _| {
+            fs::create_dir_all(dir)?;
+            write_file(&dir.join(DESCRIPTOR_FILE), descriptor)
+        })
+    }
+
+    async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+        self.with_tree(merkle_tree, |dir, _| match fs::read(dir.join(DESCRIPTOR_FILE)) {
+            Ok(bytes) => Ok(Some(decode_descriptor(&bytes)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+        let mut descriptors = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            // Anything that is not a tree directory is left alone.
+            let merkle_tree = match entry.file_name().to_str().map(Pubkey::from_str) {
+                Some(Ok(merkle_tree)) => merkle_tree,
+                _ => continue,
+            };
+            if let Some(descriptor) = self.load_descriptor(&merkle_tree).await? {
+                descriptors.push((merkle_tree, descriptor));
+            }
+        }
+        Ok(descriptors)
+    }
+
+    /// Spilled subtrees get a file of their own, so loading one back does not
+    /// mean replaying the whole log.
+    async fn save_subtree(&self, merkle_tree: &Pubkey, subtree: &Subtree) -> anyhow::Result<()> {
+        self.with_tree(merkle_tree, |dir, _| {
+            let subtrees = dir.join(SUBTREES_DIR);
+            fs::create_dir_all(&subtrees)?;
+            write_file(&subtrees.join(subtree.index.to_string()), subtree)
+        })
+    }
+
+    async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>> {
+        self.with_tree(merkle_tree, |dir, _| {
+            read_file(&dir.join(SUBTREES_DIR).join(index.to_string()))
+        })
+    }
+
+    async fn load_change_logs(
+        &self,
+        merkle_tree: &Pubkey,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(u64, ChangeLog)>> {
+        let (records, _) = self.with_tree(merkle_tree, |dir, _| {
+            read_records::<(u64, ChangeLog)>(&dir.join(HISTORY_FILE))
+        })?;
+
+        // Later records for a sequence number replace earlier ones.
+        let history: BTreeMap<u64, ChangeLog> = records.into_iter().collect();
+        Ok(history.into_iter().skip_while(|(seq, _)| *seq < from_seq).take(limit).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::Node;
+    use crate::tree_manager::TreeManager;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    fn descriptor() -> TreeDescriptor {
+        TreeDescriptor {
+            max_depth: 14,
+            max_buffer_size: 64,
+            canopy_depth: 0,
+            authority: Pubkey::new_from_array([7; 32]),
+            creation_slot: 1,
+            lookup_table: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen_with_compaction() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let merkle_tree = Pubkey::new_unique();
+        let leaves: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 8]).collect();
+
+        let expected = {
+            let storage = Arc::new(FileStorage::new(temp_dir.path().to_path_buf())?);
+            let manager = TreeManager::new(storage.clone()).with_root_history(8);
+            manager.open_tree(&merkle_tree, descriptor()).await?;
+            manager.insert_leaves(&merkle_tree, &leaves).await?;
+            storage.with_tree(&merkle_tree, FileStorage::compact)?;
+            manager.replace_leaf(&merkle_tree, 7, Node::new([9; 32])).await?;
+            manager.remove_leaf(&merkle_tree, 39).await?;
+            manager.get_proof(&merkle_tree, 7).await?
+        };
+
+        let storage = Arc::new(FileStorage::new(temp_dir.path().to_path_buf())?);
+        let manager = TreeManager::new(storage);
+        assert_eq!(manager.list_trees().await?, vec![(merkle_tree, descriptor())]);
+        assert_eq!(manager.get_proof(&merkle_tree, 7).await?, expected);
+        let historical = manager.get_proof_at(&merkle_tree, 7, 40).await?;
+        assert_eq!(historical.leaf, Node::new(solana_program::keccak::hash(&leaves[7]).to_bytes()));
+        let (index, _) = manager.insert_leaf(&merkle_tree, b"next").await?;
+        assert_eq!(index, 40);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torn_log_record_is_dropped() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let merkle_tree = Pubkey::new_unique();
+
+        let expected = {
+            let manager = TreeManager::new(Arc::new(FileStorage::new(temp_dir.path().to_path_buf())?));
+            manager.open_tree(&merkle_tree, descriptor()).await?;
+            manager.insert_leaf(&merkle_tree, b"kept").await?;
+            manager.get_proof(&merkle_tree, 0).await?
+        };
+
+        // A crash partway through the next append leaves half a record behind.
+        let log = temp_dir.path().join(merkle_tree.to_string()).join(LOG_FILE);
+        let mut file = OpenOptions::new().append(true).open(&log)?;
+        file.write_all(&[0xff; 40])?;
+
+        let manager = TreeManager::new(Arc::new(FileStorage::new(temp_dir.path().to_path_buf())?));
+        assert_eq!(manager.get_proof(&merkle_tree, 0).await?, expected);
+        assert_eq!(manager.insert_leaf(&merkle_tree, b"next").await?.0, 1);
+
+        let manager = TreeManager::new(Arc::new(FileStorage::new(temp_dir.path().to_path_buf())?));
+        assert!(manager.verify_leaf(&merkle_tree, &Node::new(solana_program::keccak::hash(b"next").to_bytes()), 1).await?);
+
+        Ok(())
+    }
+}
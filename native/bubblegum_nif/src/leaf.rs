@@ -0,0 +1,151 @@
+use borsh::BorshSerialize;
+use mpl_bubblegum::types::{Creator, MetadataArgs};
+use solana_program::{keccak, pubkey::Pubkey};
+
+pub const LEAF_SCHEMA_VERSION_V1: u8 = 1;
+
+/// keccak(keccak(borsh(metadata)) || seller_fee_basis_points), as computed by Bubblegum.
+pub fn hash_metadata(metadata: &MetadataArgs) -> std::io::Result<[u8; 32]> {
+    let metadata_hash = keccak::hashv(&[metadata.try_to_vec()?.as_slice()]);
+    Ok(keccak::hashv(&[
+        &metadata_hash.to_bytes(),
+        &metadata.seller_fee_basis_points.to_le_bytes(),
+    ])
+    .to_bytes())
+}
+
+/// keccak over each creator's (address, verified, share), in order.
+pub fn hash_creators(creators: &[Creator]) -> [u8; 32] {
+    let creator_data: Vec<Vec<u8>> = creators
+        .iter()
+        .map(|c| [c.address.as_ref(), &[c.verified as u8], &[c.share]].concat())
+        .collect();
+    let slices: Vec<&[u8]> = creator_data.iter().map(|c| c.as_slice()).collect();
+    keccak::hashv(&slices).to_bytes()
+}
+
+pub fn get_asset_id(merkle_tree: &Pubkey, nonce: u64) -> Pubkey {
+    Pubkey::find_program_address(
+        &[b"asset", merkle_tree.as_ref(), &nonce.to_le_bytes()],
+        &mpl_bubblegum::ID,
+    )
+    .0
+}
+
+/// The Bubblegum tree config PDA, which is the tree's authority.
+pub fn get_tree_authority(merkle_tree: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[merkle_tree.as_ref()], &mpl_bubblegum::ID).0
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafSchemaV1 {
+    pub id: Pubkey,
+    pub owner: Pubkey,
+    pub delegate: Pubkey,
+    pub nonce: u64,
+    pub data_hash: [u8; 32],
+    pub creator_hash: [u8; 32],
+}
+
+impl LeafSchemaV1 {
+    pub fn new(
+        merkle_tree: &Pubkey,
+        owner: &Pubkey,
+        delegate: &Pubkey,
+        nonce: u64,
+        metadata: &MetadataArgs,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            id: get_asset_id(merkle_tree, nonce),
+            owner: *owner,
+            delegate: *delegate,
+            nonce,
+            data_hash: hash_metadata(metadata)?,
+            creator_hash: hash_creators(&metadata.creators),
+        })
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        keccak::hashv(&[
+            &[LEAF_SCHEMA_VERSION_V1],
+            self.id.as_ref(),
+            self.owner.as_ref(),
+            self.delegate.as_ref(),
+            &self.nonce.to_le_bytes(),
+            &self.data_hash,
+            &self.creator_hash,
+        ])
+        .to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mpl_bubblegum::types::{Collection, LeafSchema, TokenProgramVersion, TokenStandard};
+
+    fn metadata() -> MetadataArgs {
+        MetadataArgs {
+            name: "My NFT".to_string(),
+            symbol: "MNFT".to_string(),
+            uri: "https://arweave.net/xxx".to_string(),
+            seller_fee_basis_points: 500,
+            primary_sale_happened: false,
+            is_mutable: true,
+            edition_nonce: None,
+            token_standard: Some(TokenStandard::NonFungible),
+            collection: Some(Collection {
+                verified: false,
+                key: Pubkey::new_from_array([7; 32]),
+            }),
+            uses: None,
+            token_program_version: TokenProgramVersion::Original,
+            creators: vec![
+                Creator {
+                    address: Pubkey::new_from_array([1; 32]),
+                    verified: true,
+                    share: 60,
+                },
+                Creator {
+                    address: Pubkey::new_from_array([2; 32]),
+                    verified: false,
+                    share: 40,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_hashes_match_bubblegum() {
+        let metadata = metadata();
+        assert_eq!(
+            hash_metadata(&metadata).unwrap(),
+            mpl_bubblegum::hash::hash_metadata(&metadata).unwrap()
+        );
+        assert_eq!(
+            hash_creators(&metadata.creators),
+            mpl_bubblegum::hash::hash_creators(&metadata.creators)
+        );
+
+        let tree = Pubkey::new_from_array([9; 32]);
+        assert_eq!(get_asset_id(&tree, 42), mpl_bubblegum::utils::get_asset_id(&tree, 42));
+    }
+
+    #[test]
+    fn test_leaf_hash_matches_bubblegum() {
+        let tree = Pubkey::new_from_array([9; 32]);
+        let owner = Pubkey::new_from_array([3; 32]);
+        let delegate = Pubkey::new_from_array([4; 32]);
+        let leaf = LeafSchemaV1::new(&tree, &owner, &delegate, 5, &metadata()).unwrap();
+
+        let expected = LeafSchema::V1 {
+            id: leaf.id,
+            owner,
+            delegate,
+            nonce: 5,
+            data_hash: leaf.data_hash,
+            creator_hash: leaf.creator_hash,
+        };
+        assert_eq!(leaf.hash(), expected.hash());
+    }
+}
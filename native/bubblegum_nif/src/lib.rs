@@ -1,15 +1,17 @@
-use rustler::{Encoder, Env, Error, NifResult, Term, NifStruct};
+use rustler::{Encoder, Env, Error, NifResult, ResourceArc, Term, NifStruct};
 use solana_sdk::{
     pubkey::Pubkey,
+    instruction::Instruction,
     signature::{Keypair, Signature},
-    transaction::Transaction,
     signer::Signer,
+    transaction::VersionedTransaction,
     commitment_config::CommitmentConfig,
 };
 use solana_client::rpc_client::RpcClient;
 use mpl_bubblegum::{
     instructions as bubblegum_ix,
-    state::{metaplex_adapter::MetadataArgs, TreeConfig, Creator},
+    state::TreeConfig,
+    types::{Collection, Creator, MetadataArgs, TokenProgramVersion, TokenStandard},
 };
 use anyhow::Result;
 use thiserror::Error;
@@ -19,10 +21,30 @@ use tokio::sync::Mutex;
 use log::{info, error, warn};
 use bs58;
 
+#[cfg(feature = "file-storage")]
+mod file_storage;
+mod leaf;
+mod memory_storage;
+mod merkle;
+#[cfg(feature = "sqlite-storage")]
+mod sqlite_storage;
+mod transaction;
+mod tree_account;
+mod tree_manager;
+mod wal;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use merkle::Node;
+use wal::LeafOp;
+use transaction::{ComputeBudgetConfig, FeePolicy, TransactionManager};
+use tree_manager::{CacheStats, LeafRecord, TreeDescriptor, TreeManager, TreeStorage};
+
 // Global state management
 lazy_static::lazy_static! {
     static ref SOLANA_CLIENT: Arc<Mutex<Option<RpcClient>>> = Arc::new(Mutex::new(None));
     static ref CURRENT_KEYPAIR: Arc<Mutex<Option<Keypair>>> = Arc::new(Mutex::new(None));
+    static ref TREE_MANAGER: Arc<Mutex<Option<Arc<TreeManager>>>> = Arc::new(Mutex::new(None));
+    static ref TRANSACTION_MANAGER: Arc<Mutex<Option<Arc<TransactionManager>>>> = Arc::new(Mutex::new(None));
 }
 
 #[derive(Error, Debug)]
@@ -45,6 +67,27 @@ pub enum BubblegumError {
     DecodingError(String),
     #[error("Instruction error: {0}")]
     InstructionError(String),
+    #[error("Invalid hash: {0}")]
+    InvalidHash(String),
+    #[error("Transaction {signature} expired: {reason}")]
+    TransactionExpired { signature: String, reason: String },
+    #[error("Transaction {signature} failed: {reason}")]
+    TransactionFailed { signature: String, reason: String },
+    #[error("Transaction {signature} status unknown: {reason}")]
+    TransactionUnknown { signature: String, reason: String },
+}
+
+/// Transactions that were sent but did not land encode as `{:expired, sig}`,
+/// `{:failed, sig, reason}` or `{:unknown, sig}`; other errors as their message.
+impl Encoder for BubblegumError {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        match self {
+            BubblegumError::TransactionExpired { signature, .. } => (atoms::expired(), signature).encode(env),
+            BubblegumError::TransactionFailed { signature, reason } => (atoms::failed(), signature, reason).encode(env),
+            BubblegumError::TransactionUnknown { signature, .. } => (atoms::unknown(), signature).encode(env),
+            e => e.to_string().encode(env),
+        }
+    }
 }
 
 #[derive(NifStruct)]
@@ -76,10 +119,247 @@ pub struct NifMetadataArgs {
     pub collection: Option<String>,
 }
 
+/// Per-call transaction settings; `nil` fields keep the `TransactionManager` default.
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.TransactionOptions"]
+pub struct NifTransactionOptions {
+    pub priority_fee: Option<u64>,
+    pub priority_fee_percentile: Option<u8>,
+    pub max_priority_fee: Option<u64>,
+    pub compute_unit_margin: Option<u32>,
+    pub compute_unit_limit: Option<u32>,
+    pub nonce_account: Option<String>,
+    pub build_only: Option<bool>,
+    pub payer: Option<String>,
+}
+
+impl NifTransactionOptions {
+    /// Whether the transaction is returned unsigned rather than sent.
+    fn builds_only(&self) -> bool {
+        self.nonce_account.is_some() || self.build_only.unwrap_or(false)
+    }
+
+    fn compute_budget(&self, default: ComputeBudgetConfig) -> Result<ComputeBudgetConfig, BubblegumError> {
+        let fee_policy = match (self.priority_fee, self.priority_fee_percentile) {
+            (Some(_), Some(_)) => return Err(BubblegumError::ConfigError(
+                "Set either priority_fee or priority_fee_percentile, not both".to_string()
+            )),
+            (Some(micro_lamports), None) => FeePolicy::Fixed(micro_lamports),
+            (None, Some(percentile)) if percentile > 100 => return Err(BubblegumError::ConfigError(
+                format!("Invalid priority fee percentile: {}", percentile)
+            )),
+            (None, Some(percentile)) => FeePolicy::Percentile(percentile),
+            (None, None) => default.fee_policy,
+        };
+
+        Ok(ComputeBudgetConfig {
+            fee_policy,
+            max_micro_lamports: self.max_priority_fee.or(default.max_micro_lamports),
+            unit_margin_percent: self.compute_unit_margin.unwrap_or(default.unit_margin_percent),
+            unit_limit: self.compute_unit_limit.or(default.unit_limit),
+        })
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.LeafSchema"]
+pub struct NifLeafSchema {
+    pub asset_id: String,
+    pub owner: String,
+    pub delegate: String,
+    pub nonce: u64,
+    pub data_hash: String,
+    pub creator_hash: String,
+    pub leaf_hash: String,
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.TreeAccount"]
+pub struct NifTreeAccount {
+    pub merkle_tree: String,
+    pub authority: String,
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+    pub canopy_depth: u32,
+    pub creation_slot: u64,
+    pub sequence_number: u64,
+    pub active_index: u64,
+    pub num_leaves: u32,
+    pub current_root: String,
+    pub canopy: Vec<String>,
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.TreeDescriptor"]
+pub struct NifTreeDescriptor {
+    pub merkle_tree: String,
+    pub authority: String,
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+    pub canopy_depth: u32,
+    pub creation_slot: u64,
+    pub lookup_table: Option<String>,
+}
+
+impl NifTreeDescriptor {
+    fn new(merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> Self {
+        Self {
+            merkle_tree: merkle_tree.to_string(),
+            authority: descriptor.authority.to_string(),
+            max_depth: descriptor.max_depth,
+            max_buffer_size: descriptor.max_buffer_size,
+            canopy_depth: descriptor.canopy_depth,
+            creation_slot: descriptor.creation_slot,
+            lookup_table: descriptor.lookup_table.map(|table| table.to_string()),
+        }
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.LeafRecord"]
+pub struct NifLeafRecord {
+    pub merkle_tree: String,
+    pub index: u32,
+    pub asset_id: String,
+    pub owner: String,
+    pub delegate: String,
+    pub nonce: u64,
+    pub data_hash: String,
+    pub creator_hash: String,
+    pub collection: Option<String>,
+}
+
+impl NifLeafRecord {
+    fn new(merkle_tree: &Pubkey, record: &LeafRecord) -> Self {
+        Self {
+            merkle_tree: merkle_tree.to_string(),
+            index: record.index,
+            asset_id: record.asset_id.to_string(),
+            owner: record.owner.to_string(),
+            delegate: record.delegate.to_string(),
+            nonce: record.nonce,
+            data_hash: record.data_hash.to_string(),
+            creator_hash: record.creator_hash.to_string(),
+            collection: record.collection.map(|collection| collection.to_string()),
+        }
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.StorageConfig"]
+pub struct NifStorageConfig {
+    pub backend: String,
+    pub path: Option<String>,
+    pub wal_path: Option<String>,
+    pub memory_budget: Option<u64>,
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.CacheStats"]
+pub struct NifCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub resident_trees: u64,
+    pub resident_bytes: u64,
+}
+
+impl From<CacheStats> for NifCacheStats {
+    fn from(stats: CacheStats) -> Self {
+        Self {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+            resident_trees: stats.resident_trees as u64,
+            resident_bytes: stats.resident_bytes as u64,
+        }
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.MultiProof"]
+pub struct NifMultiProof {
+    pub root: String,
+    pub indices: Vec<u32>,
+    pub proof: String,
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.HistoricalProof"]
+pub struct NifHistoricalProof {
+    pub index: u32,
+    pub seq: u64,
+    pub leaf: String,
+    pub proof: Vec<String>,
+    pub root: String,
+}
+
 fn decode_pubkey(encoded: &str) -> Result<Pubkey, BubblegumError> {
     Pubkey::from_str(encoded).map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))
 }
 
+fn creators_from_nif(creators: &[NifCreator]) -> Result<Vec<Creator>, BubblegumError> {
+    creators.iter()
+        .map(|c| Ok(Creator {
+            address: decode_pubkey(&c.address)
+                .map_err(|_| BubblegumError::MetadataError(format!("Invalid creator address: {}", c.address)))?,
+            verified: c.verified,
+            share: c.share,
+        }))
+        .collect()
+}
+
+// Builds the exact MetadataArgs that mint_v1 sends, so that locally computed
+// data hashes match the ones Bubblegum stores in the leaf.
+fn metadata_args_from_nif(metadata: &NifMetadataArgs) -> Result<MetadataArgs, BubblegumError> {
+    let collection = match &metadata.collection {
+        Some(key) => Some(Collection {
+            verified: false,
+            key: decode_pubkey(key)?,
+        }),
+        None => None,
+    };
+
+    Ok(MetadataArgs {
+        name: metadata.name.clone(),
+        symbol: metadata.symbol.clone(),
+        uri: metadata.uri.clone(),
+        seller_fee_basis_points: metadata.seller_fee_basis_points,
+        primary_sale_happened: metadata.primary_sale_happened,
+        is_mutable: metadata.is_mutable,
+        edition_nonce: None,
+        token_standard: Some(TokenStandard::NonFungible),
+        collection,
+        uses: None,
+        token_program_version: TokenProgramVersion::Original,
+        creators: creators_from_nif(&metadata.creators)?,
+    })
+}
+
+fn decode_hash(encoded: &str) -> Result<Node, BubblegumError> {
+    encoded
+        .parse::<Node>()
+        .map_err(|e| BubblegumError::InvalidHash(e.to_string()))
+}
+
+fn decode_leaf_hashes(
+    root: &str,
+    data_hash: &str,
+    creator_hash: &str,
+) -> Result<(Node, Node, Node), BubblegumError> {
+    Ok((decode_hash(root)?, decode_hash(data_hash)?, decode_hash(creator_hash)?))
+}
+
+/// Derives (data_hash, creator_hash) for a leaf minted with `metadata`.
+fn leaf_hashes_from_metadata(metadata: &NifMetadataArgs) -> Result<(Node, Node), BubblegumError> {
+    let metadata_args = metadata_args_from_nif(metadata)?;
+    let data_hash = leaf::hash_metadata(&metadata_args)
+        .map_err(|e| BubblegumError::MetadataError(e.to_string()))?;
+    let creator_hash = leaf::hash_creators(&metadata_args.creators);
+
+    Ok((Node::new(data_hash), Node::new(creator_hash)))
+}
+
 fn get_client() -> Result<RpcClient, BubblegumError> {
     let client = SOLANA_CLIENT.try_lock()
         .map_err(|e| BubblegumError::ConfigError(format!("Failed to acquire client lock: {}", e)))?
@@ -88,17 +368,239 @@ fn get_client() -> Result<RpcClient, BubblegumError> {
     client.ok_or_else(|| BubblegumError::ConfigError("Solana client not initialized".to_string()))
 }
 
+fn get_transaction_manager() -> Result<Arc<TransactionManager>, BubblegumError> {
+    let manager = TRANSACTION_MANAGER.try_lock()
+        .map_err(|e| BubblegumError::ConfigError(format!("Failed to acquire transaction manager lock: {}", e)))?
+        .clone();
+
+    manager.ok_or_else(|| BubblegumError::ConfigError("Solana client not initialized".to_string()))
+}
+
+fn current_keypair() -> Result<Keypair, BubblegumError> {
+    match CURRENT_KEYPAIR.try_lock() {
+        Ok(lock) => match &*lock {
+            Some(kp) => Ok(kp.insecure_clone()),
+            None => Err(BubblegumError::KeypairError("No keypair loaded".to_string())),
+        },
+        Err(e) => Err(BubblegumError::KeypairError(format!("Failed to acquire keypair lock: {}", e))),
+    }
+}
+
+fn encode_transaction(tx: &VersionedTransaction) -> Result<String, BubblegumError> {
+    let bytes = bincode::serialize(tx)
+        .map_err(|e| BubblegumError::TransactionError(format!("Failed to serialize transaction: {}", e)))?;
+    Ok(BASE64.encode(bytes))
+}
+
+fn decode_transaction(encoded: &str) -> Result<VersionedTransaction, BubblegumError> {
+    let bytes = BASE64.decode(encoded)
+        .map_err(|e| BubblegumError::DecodingError(format!("Invalid base64 transaction: {}", e)))?;
+    bincode::deserialize(&bytes)
+        .map_err(|e| BubblegumError::DecodingError(format!("Invalid transaction: {}", e)))
+}
+
+/// Signs `instructions` with the loaded keypair and sends them, preceded by
+/// the ComputeBudget instructions `options` (or the manager's defaults) ask
+/// for, returning the signature. With a `nonce_account` or `build_only` in
+/// `options`, returns the base64 encoded unsigned transaction instead.
+/// `payer` pays unless `options` name another one.
+fn send_instructions(
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    options: Option<&NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let payer = &match options.and_then(|options| options.payer.as_deref()) {
+        Some(payer) => decode_pubkey(payer)?,
+        None => *payer,
+    };
+    let manager = get_transaction_manager()?;
+    let config = match options {
+        Some(options) => options.compute_budget(manager.compute_budget())?,
+        None => manager.compute_budget(),
+    };
+
+    if let Some(nonce_account) = options.and_then(|options| options.nonce_account.as_deref()) {
+        let nonce_account = decode_pubkey(nonce_account)?;
+        let (tx, _) = manager.build_nonce_transaction(instructions, payer, &nonce_account, &config)?;
+        info!("Built transaction on nonce account {}", nonce_account);
+        return encode_transaction(&tx);
+    }
+    if options.and_then(|options| options.build_only).unwrap_or(false) {
+        let (tx, _) = manager.build_transaction(instructions, payer, &config)?;
+        return encode_transaction(&tx);
+    }
+
+    let keypair = current_keypair()?;
+    let signature = futures::executor::block_on(manager.send_instructions(instructions, payer, &[&keypair], &config))?
+        .into_result()?;
+    Ok(signature.to_string())
+}
+
+/// Sends a single Bubblegum instruction paid for and signed by the loaded
+/// keypair. Unsigned transactions are paid for by `leaf_owner` instead, so
+/// building one needs no loaded keypair.
+fn process_instruction(
+    ix: Instruction,
+    leaf_owner: &Pubkey,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let payer = match &options {
+        Some(options) if options.builds_only() => *leaf_owner,
+        _ => current_keypair()?.pubkey(),
+    };
+    let result = send_instructions(&[ix], &payer, options.as_ref())?;
+    info!("Transaction successful: {}", result);
+    Ok(result)
+}
+
+// Backend used when neither `configure_tree_storage` nor BUBBLEGUM_TREE_BACKEND picks one.
+const DEFAULT_TREE_BACKEND: Option<&str> = if cfg!(feature = "persistent-storage") {
+    Some("rocksdb")
+} else if cfg!(feature = "file-storage") {
+    Some("file")
+} else {
+    None
+};
+
+const DEFAULT_TREE_STORE: &str = "bubblegum_trees";
+
+fn open_tree_storage(backend: &str, path: Option<String>) -> Result<Arc<dyn TreeStorage>, BubblegumError> {
+    #[allow(unused_variables)]
+    let path = path.unwrap_or_else(|| DEFAULT_TREE_STORE.to_string());
+    let storage: Arc<dyn TreeStorage> = match backend {
+        "memory" => Arc::new(memory_storage::InMemoryStorage::new()),
+        #[cfg(feature = "persistent-storage")]
+        "rocksdb" => Arc::new(tree_manager::RocksDBStorage::new(path.into())
+            .map_err(|e| BubblegumError::ConfigError(format!("Failed to open tree storage: {}", e)))?),
+        #[cfg(feature = "file-storage")]
+        "file" => Arc::new(file_storage::FileStorage::new(path.into())
+            .map_err(|e| BubblegumError::ConfigError(format!("Failed to open tree storage: {}", e)))?),
+        #[cfg(feature = "sqlite-storage")]
+        "sqlite" => Arc::new(sqlite_storage::SqliteStorage::new(path)
+            .map_err(|e| BubblegumError::ConfigError(format!("Failed to open tree storage: {}", e)))?),
+        other => return Err(BubblegumError::ConfigError(format!(
+            "Tree storage backend {:?} is unknown or not compiled in", other
+        ))),
+    };
+    Ok(storage)
+}
+
+/// Opens the tree manager and finishes any leaf changes its write-ahead log
+/// shows as interrupted. Persistent backends log to `wal_path`, by default a
+/// `.wal` file next to the store. With a `memory_budget` in bytes, least
+/// recently used trees are saved and dropped from memory past it.
+fn start_tree_manager(
+    backend: &str,
+    path: Option<String>,
+    wal_path: Option<String>,
+    memory_budget: Option<u64>,
+) -> Result<TreeManager, BubblegumError> {
+    let wal_path = wal_path.or_else(|| {
+        (backend != "memory").then(|| format!("{}.wal", path.as_deref().unwrap_or(DEFAULT_TREE_STORE)))
+    });
+    let mut tree_manager = TreeManager::new(open_tree_storage(backend, path)?);
+    // Number of (sequence number, root) pairs kept per tree; unset disables history.
+    if let Ok(max_roots) = std::env::var("BUBBLEGUM_ROOT_HISTORY") {
+        let max_roots = max_roots.parse::<usize>()
+            .map_err(|e| BubblegumError::ConfigError(format!("Invalid BUBBLEGUM_ROOT_HISTORY: {}", e)))?;
+        tree_manager = tree_manager.with_root_history(max_roots);
+    }
+    // Changelogs replayed at most to rebuild one historical proof.
+    if let Ok(max_replay) = std::env::var("BUBBLEGUM_MAX_PROOF_REPLAY") {
+        let max_replay = max_replay.parse::<u64>()
+            .map_err(|e| BubblegumError::ConfigError(format!("Invalid BUBBLEGUM_MAX_PROOF_REPLAY: {}", e)))?;
+        tree_manager = tree_manager.with_max_proof_replay(max_replay);
+    }
+    if let Some(memory_budget) = memory_budget {
+        tree_manager = tree_manager.with_memory_budget(memory_budget as usize);
+    }
+
+    if let Some(wal_path) = wal_path {
+        let wal = wal::WriteAheadLog::open(wal_path.into())
+            .map_err(|e| BubblegumError::ConfigError(format!("Failed to open write-ahead log: {}", e)))?;
+        tree_manager = tree_manager.with_wal(Arc::new(wal));
+        let replayed = futures::executor::block_on(tree_manager.recover())
+            .map_err(|e| BubblegumError::ConfigError(format!("Failed to recover trees: {}", e)))?;
+        if replayed > 0 {
+            warn!("Replayed {} interrupted leaf changes from the write-ahead log", replayed);
+        }
+    }
+    Ok(tree_manager)
+}
+
+fn get_tree_manager() -> Result<Arc<TreeManager>, BubblegumError> {
+    let mut manager = TREE_MANAGER.try_lock()
+        .map_err(|e| BubblegumError::ConfigError(format!("Failed to acquire tree manager lock: {}", e)))?;
+
+    if manager.is_none() {
+        let backend = match std::env::var("BUBBLEGUM_TREE_BACKEND") {
+            Ok(backend) => backend,
+            Err(_) => DEFAULT_TREE_BACKEND
+                .ok_or_else(|| BubblegumError::ConfigError("No tree storage backend compiled in".to_string()))?
+                .to_string(),
+        };
+        let tree_manager = start_tree_manager(
+            &backend,
+            std::env::var("BUBBLEGUM_TREE_STORE").ok(),
+            std::env::var("BUBBLEGUM_TREE_WAL").ok(),
+            std::env::var("BUBBLEGUM_TREE_MEMORY_BUDGET")
+                .ok()
+                .map(|budget| budget.parse::<u64>())
+                .transpose()
+                .map_err(|e| BubblegumError::ConfigError(format!("Invalid BUBBLEGUM_TREE_MEMORY_BUDGET: {}", e)))?,
+        )?;
+        *manager = Some(Arc::new(tree_manager));
+    }
+    Ok(manager.as_ref().unwrap().clone())
+}
+
+/// The tree manager if `configure_tree_storage` or an earlier call started
+/// one, without starting the default store.
+fn running_tree_manager() -> Option<Arc<TreeManager>> {
+    TREE_MANAGER.try_lock().ok().and_then(|manager| manager.clone())
+}
+
+fn configure_storage(config: &NifStorageConfig) -> Result<(), BubblegumError> {
+    let mut manager = TREE_MANAGER.try_lock()
+        .map_err(|e| BubblegumError::ConfigError(format!("Failed to acquire tree manager lock: {}", e)))?;
+    if manager.is_some() {
+        return Err(BubblegumError::ConfigError("Tree storage is already in use".to_string()));
+    }
+
+    let tree_manager = start_tree_manager(
+        &config.backend,
+        config.path.clone(),
+        config.wal_path.clone(),
+        config.memory_budget,
+    )?;
+    *manager = Some(Arc::new(tree_manager));
+    Ok(())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn configure_tree_storage(config: NifStorageConfig) -> NifResult<(Term, Term)> {
+    match configure_storage(&config) {
+        Ok(()) => Ok((atoms::ok(), format!("Using {} tree storage", config.backend).encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
 #[rustler::nif]
 fn initialize_client(config: Config) -> NifResult<(Term, Term)> {
     let commitment = CommitmentConfig::from_str(&config.commitment)
         .map_err(|e| Error::Term(Box::new(format!("Invalid commitment: {}", e))))?;
 
-    let client = RpcClient::new_with_commitment(config.rpc_url, commitment);
+    let client = RpcClient::new_with_commitment(config.rpc_url.clone(), commitment);
+    let manager = TransactionManager::new(&config.rpc_url, commitment);
 
     let mut client_lock = SOLANA_CLIENT.try_lock()
         .map_err(|e| Error::Term(Box::new(format!("Failed to acquire lock: {}", e))))?;
     *client_lock = Some(client);
 
+    let mut manager_lock = TRANSACTION_MANAGER.try_lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to acquire lock: {}", e))))?;
+    *manager_lock = Some(Arc::new(manager));
+
     Ok((atoms::ok(), "Client initialized successfully".encode(env)))
 }
 
@@ -117,18 +619,26 @@ fn load_keypair(keypair_json: String) -> NifResult<(Term, Term)> {
     Ok((atoms::ok(), "Keypair loaded successfully".encode(env)))
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 fn create_tree_config(
     max_depth: u32,
     max_buffer_size: u32,
     public_key: String,
     canopy_depth: Option<u32>,
+    options: Option<NifTransactionOptions>,
 ) -> NifResult<(Term, Term)> {
     let authority = match decode_pubkey(&public_key) {
         Ok(key) => key,
         Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
     };
 
+    if !merkle::is_valid_depth_size_pair(max_depth, max_buffer_size) {
+        return Ok((atoms::error(), format!(
+            "Unsupported max_depth/max_buffer_size pair: ({}, {})",
+            max_depth, max_buffer_size
+        ).encode(env)));
+    }
+
     let tree_config = TreeConfig {
         max_depth,
         max_buffer_size,
@@ -136,19 +646,6 @@ fn create_tree_config(
         canopy_depth: canopy_depth.unwrap_or(0),
     };
 
-    let client = match get_client() {
-        Ok(client) => client,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let keypair = match CURRENT_KEYPAIR.try_lock() {
-        Ok(lock) => match &*lock {
-            Some(kp) => kp.clone(),
-            None => return Ok((atoms::error(), "No keypair loaded".encode(env))),
-        },
-        Err(e) => return Ok((atoms::error(), format!("Failed to acquire keypair lock: {}", e).encode(env))),
-    };
-
     let ix = match bubblegum_ix::create_tree(
         &tree_config,
         &authority,
@@ -157,37 +654,26 @@ fn create_tree_config(
         Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
     };
 
-    let recent_blockhash = match client.get_latest_blockhash() {
-        Ok(hash) => hash,
-        Err(e) => return Ok((atoms::error(), format!("Failed to get blockhash: {}", e).encode(env))),
-    };
-
-    let tx = Transaction::new_signed_with_payer(
-        &[ix],
-        Some(&authority),
-        &[&keypair],
-        recent_blockhash,
-    );
-
     info!("Sending create_tree transaction...");
-    match client.send_and_confirm_transaction_with_spinner(&tx) {
+    match send_instructions(&[ix], &authority, options.as_ref()) {
         Ok(signature) => {
             info!("Tree created successfully: {}", signature);
-            Ok((atoms::ok(), signature.to_string().encode(env)))
+            Ok((atoms::ok(), signature.encode(env)))
         },
         Err(e) => {
             error!("Failed to create tree: {}", e);
-            Ok((atoms::error(), format!("Transaction failed: {}", e).encode(env)))
+            Ok((atoms::error(), e.encode(env)))
         }
     }
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 fn mint_v1(
     tree_authority: String,
     leaf_owner: String,
     leaf_delegate: String,
     metadata: NifMetadataArgs,
+    options: Option<NifTransactionOptions>,
 ) -> NifResult<(Term, Term)> {
     let tree_auth = match decode_pubkey(&tree_authority) {
         Ok(key) => key,
@@ -204,50 +690,11 @@ fn mint_v1(
         Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
     };
 
-    let collection_key = match metadata.collection {
-        Some(key) => Some(match decode_pubkey(&key) {
-            Ok(key) => key,
-            Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-        }),
-        None => None,
-    };
-
-    let creators: Vec<Creator> = metadata.creators.iter()
-        .map(|c| Creator {
-            address: match decode_pubkey(&c.address) {
-                Ok(key) => key,
-                Err(_) => return Ok((atoms::error(), format!("Invalid creator address: {}", c.address).encode(env))),
-            },
-            verified: c.verified,
-            share: c.share,
-        })
-        .collect();
-
-    let metadata_args = MetadataArgs {
-        name: metadata.name,
-        symbol: metadata.symbol,
-        uri: metadata.uri,
-        creators,
-        collection: collection_key,
-        seller_fee_basis_points: metadata.seller_fee_basis_points,
-        primary_sale_happened: metadata.primary_sale_happened,
-        is_mutable: metadata.is_mutable,
-        ..Default::default()
-    };
-
-    let client = match get_client() {
-        Ok(client) => client,
+    let metadata_args = match metadata_args_from_nif(&metadata) {
+        Ok(args) => args,
         Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
     };
 
-    let keypair = match CURRENT_KEYPAIR.try_lock() {
-        Ok(lock) => match &*lock {
-            Some(kp) => kp.clone(),
-            None => return Ok((atoms::error(), "No keypair loaded".encode(env))),
-        },
-        Err(e) => return Ok((atoms::error(), format!("Failed to acquire keypair lock: {}", e).encode(env))),
-    };
-
     let ix = match bubblegum_ix::mint_v1(
         &tree_auth,
         &owner,
@@ -258,117 +705,129 @@ fn mint_v1(
         Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
     };
 
-    let recent_blockhash = match client.get_latest_blockhash() {
-        Ok(hash) => hash,
-        Err(e) => return Ok((atoms::error(), format!("Failed to get blockhash: {}", e).encode(env))),
-    };
-
-    let tx = Transaction::new_signed_with_payer(
-        &[ix],
-        Some(&owner),
-        &[&keypair],
-        recent_blockhash,
-    );
-
     info!("Sending mint transaction...");
-    match client.send_and_confirm_transaction_with_spinner(&tx) {
+    match send_instructions(&[ix], &owner, options.as_ref()) {
         Ok(signature) => {
             info!("NFT minted successfully: {}", signature);
-            Ok((atoms::ok(), signature.to_string().encode(env)))
+            Ok((atoms::ok(), signature.encode(env)))
         },
         Err(e) => {
             error!("Failed to mint NFT: {}", e);
-            Ok((atoms::error(), format!("Transaction failed: {}", e).encode(env)))
+            Ok((atoms::error(), e.encode(env)))
         }
     }
 }
 
-#[rustler::nif]
-fn transfer(
-    tree_authority: String,
-    leaf_owner: String,
-    new_leaf_owner: String,
-    merkle_tree: String,
-    root: Vec<u8>,
-    data_hash: Vec<u8>,
-    creator_hash: Vec<u8>,
+fn transfer_leaf(
+    tree_authority: &str,
+    leaf_owner: &str,
+    new_leaf_owner: &str,
+    merkle_tree: &str,
+    root: &Node,
+    data_hash: &Node,
+    creator_hash: &Node,
     nonce: u64,
     index: u32,
-) -> NifResult<(Term, Term)> {
-    let tree_auth = match decode_pubkey(&tree_authority) {
-        Ok(key) => key,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let owner = match decode_pubkey(&leaf_owner) {
-        Ok(key) => key,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let new_owner = match decode_pubkey(&new_leaf_owner) {
-        Ok(key) => key,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let tree = match decode_pubkey(&merkle_tree) {
-        Ok(key) => key,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let client = match get_client() {
-        Ok(client) => client,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let keypair = match CURRENT_KEYPAIR.try_lock() {
-        Ok(lock) => match &*lock {
-            Some(kp) => kp.clone(),
-            None => return Ok((atoms::error(), "No keypair loaded".encode(env))),
-        },
-        Err(e) => return Ok((atoms::error(), format!("Failed to acquire keypair lock: {}", e).encode(env))),
-    };
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let tree_auth = decode_pubkey(tree_authority)?;
+    let owner = decode_pubkey(leaf_owner)?;
+    let new_owner = decode_pubkey(new_leaf_owner)?;
+    let tree = decode_pubkey(merkle_tree)?;
 
-    let ix = match bubblegum_ix::transfer(
+    let mut ix = bubblegum_ix::transfer(
         &tree_auth,
         &owner,
         &new_owner,
         &tree,
-        root.as_slice(),
-        data_hash.as_slice(),
-        creator_hash.as_slice(),
+        root.as_ref(),
+        data_hash.as_ref(),
+        creator_hash.as_ref(),
         nonce,
         index,
-    ) {
-        Ok(ix) => ix,
-        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
-    };
-
-    let recent_blockhash = match client.get_latest_blockhash() {
-        Ok(hash) => hash,
-        Err(e) => return Ok((atoms::error(), format!("Failed to get blockhash: {}", e).encode(env))),
-    };
-
-    let tx = Transaction::new_signed_with_payer(
-        &[ix],
-        Some(&owner),
-        &[&keypair],
-        recent_blockhash,
-    );
+    ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+    attach_proof(&mut ix, &tree, root, index);
 
     info!("Sending transfer transaction...");
-    match client.send_and_confirm_transaction_with_spinner(&tx) {
+    match send_instructions(&[ix], &owner, options.as_ref()) {
         Ok(signature) => {
             info!("NFT transferred successfully: {}", signature);
-            Ok((atoms::ok(), signature.to_string().encode(env)))
+            Ok(signature)
         },
         Err(e) => {
             error!("Failed to transfer NFT: {}", e);
-            Ok((atoms::error(), format!("Transaction failed: {}", e).encode(env)))
+            Err(e)
         }
     }
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
+fn transfer(
+    tree_authority: String,
+    leaf_owner: String,
+    new_leaf_owner: String,
+    merkle_tree: String,
+    root: String,
+    data_hash: String,
+    creator_hash: String,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> NifResult<(Term, Term)> {
+    let result = decode_leaf_hashes(&root, &data_hash, &creator_hash)
+        .and_then(|(root, data_hash, creator_hash)| transfer_leaf(
+            &tree_authority,
+            &leaf_owner,
+            &new_leaf_owner,
+            &merkle_tree,
+            &root,
+            &data_hash,
+            &creator_hash,
+            nonce,
+            index,
+            options,
+        ));
+
+    match result {
+        Ok(signature) => Ok((atoms::ok(), signature.encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn transfer_with_metadata(
+    tree_authority: String,
+    leaf_owner: String,
+    new_leaf_owner: String,
+    merkle_tree: String,
+    root: String,
+    metadata: NifMetadataArgs,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> NifResult<(Term, Term)> {
+    let result = decode_hash(&root)
+        .and_then(|root| leaf_hashes_from_metadata(&metadata).map(|hashes| (root, hashes)))
+        .and_then(|(root, (data_hash, creator_hash))| transfer_leaf(
+            &tree_authority,
+            &leaf_owner,
+            &new_leaf_owner,
+            &merkle_tree,
+            &root,
+            &data_hash,
+            &creator_hash,
+            nonce,
+            index,
+            options,
+        ));
+
+    match result {
+        Ok(signature) => Ok((atoms::ok(), signature.encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
 fn request_airdrop(public_key: String, amount_sol: f64) -> NifResult<(Term, Term)> {
     let pubkey = match decode_pubkey(&public_key) {
         Ok(key) => key,
@@ -403,53 +862,196 @@ fn request_airdrop(public_key: String, amount_sol: f64) -> NifResult<(Term, Term
     }
 }
 
-#[rustler::nif]
-pub fn decompress_v1(
-    tree_authority: String,
-    leaf_owner: String,
-    leaf_delegate: String,
-    merkle_tree: String,
-    root: String,
-    data_hash: String,
-    creator_hash: String,
+/// Appends the canopy-truncated proof of leaf `index`, read from the local
+/// copy of `merkle_tree`, to `ix` as the remaining accounts Bubblegum leaf
+/// instructions expect. The proof is only attached while the local copy is
+/// at `root`, the root `ix` was built with, and only from a tree manager that
+/// is already running; otherwise `ix` is left to a full canopy.
+fn attach_proof(ix: &mut Instruction, merkle_tree: &Pubkey, root: &Node, index: u32) {
+    let Some(manager) = running_tree_manager() else {
+        warn!("Tree storage is not started; sending leaf {} of {} without proof accounts", index, merkle_tree);
+        return;
+    };
+    let proof = futures::executor::block_on(async {
+        if !manager.is_managed(merkle_tree).await? {
+            return Ok::<_, anyhow::Error>(None);
+        }
+        manager.get_canopy_proof(merkle_tree, index).await.map(Some)
+    });
+
+    match proof {
+        Ok(Some((proof, local_root))) if local_root == *root => {
+            ix.accounts.extend(merkle::proof_to_remaining_accounts(&proof));
+            if let Err(e) = restore_lookup_table(&manager, merkle_tree) {
+                warn!("Failed to restore lookup table for tree {}: {}", merkle_tree, e);
+            }
+        }
+        Ok(Some((_, local_root))) => warn!(
+            "Local copy of tree {} is at root {}, not {}; sending leaf {} without proof accounts",
+            merkle_tree, local_root, root, index
+        ),
+        Ok(None) => warn!("Tree {} is not managed; sending leaf {} without proof accounts", merkle_tree, index),
+        Err(e) => warn!("Failed to read proof of leaf {} in tree {}: {}; sending it without proof accounts", index, merkle_tree, e),
+    }
+}
+
+/// Registers the lookup table stored with managed tree `merkle_tree` with
+/// the transaction manager, unless one already is, e.g. after a restart.
+fn restore_lookup_table(tree_manager: &TreeManager, merkle_tree: &Pubkey) -> Result<(), BubblegumError> {
+    let manager = get_transaction_manager()?;
+    if manager.lookup_table(merkle_tree).is_some() {
+        return Ok(());
+    }
+    let descriptor = futures::executor::block_on(tree_manager.descriptor(merkle_tree))
+        .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+    if let Some(table) = descriptor.and_then(|descriptor| descriptor.lookup_table) {
+        manager.register_lookup_table(merkle_tree, &table)?;
+        info!("Restored lookup table {} for tree {}", table, merkle_tree);
+    }
+    Ok(())
+}
+
+/// Stores `table` with `merkle_tree` if the tree is managed, so
+/// `restore_lookup_table` finds it after a restart.
+fn save_lookup_table(merkle_tree: &Pubkey, table: &Pubkey) -> Result<(), BubblegumError> {
+    let tree_manager = get_tree_manager()?;
+    futures::executor::block_on(async {
+        if tree_manager.is_managed(merkle_tree).await? {
+            tree_manager.set_lookup_table(merkle_tree, table).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .map_err(|e| BubblegumError::ConfigError(e.to_string()))
+}
+
+fn decompress_v1_leaf(
+    tree_authority: &str,
+    leaf_owner: &str,
+    leaf_delegate: &str,
+    merkle_tree: &str,
+    root: &Node,
+    data_hash: &Node,
+    creator_hash: &Node,
     nonce: u64,
     index: u32,
+    options: Option<NifTransactionOptions>,
 ) -> Result<String, BubblegumError> {
-    let tree_authority = Pubkey::from_str(&tree_authority)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_owner = Pubkey::from_str(&leaf_owner)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_delegate = Pubkey::from_str(&leaf_delegate)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let merkle_tree = Pubkey::from_str(&merkle_tree)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    
-    let root = bs58::decode(root)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let data_hash = bs58::decode(data_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let creator_hash = bs58::decode(creator_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-
-    let ix = mpl_bubblegum::instructions::decompress_v1(
+    let tree_authority = decode_pubkey(tree_authority)?;
+    let leaf_owner = decode_pubkey(leaf_owner)?;
+    let leaf_delegate = decode_pubkey(leaf_delegate)?;
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+
+    let mut ix = mpl_bubblegum::instructions::decompress_v1(
         &tree_authority,
         &leaf_owner,
         &leaf_delegate,
         &merkle_tree,
-        root.as_slice(),
-        data_hash.as_slice(),
-        creator_hash.as_slice(),
+        root.as_ref(),
+        data_hash.as_ref(),
+        creator_hash.as_ref(),
         nonce,
         index,
     ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+    attach_proof(&mut ix, &merkle_tree, root, index);
 
-    process_instruction(ix)
+    process_instruction(ix, &leaf_owner, options)
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn decompress_v1(
+    tree_authority: String,
+    leaf_owner: String,
+    leaf_delegate: String,
+    merkle_tree: String,
+    root: String,
+    data_hash: String,
+    creator_hash: String,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let (root, data_hash, creator_hash) = decode_leaf_hashes(&root, &data_hash, &creator_hash)?;
+
+    decompress_v1_leaf(
+        &tree_authority,
+        &leaf_owner,
+        &leaf_delegate,
+        &merkle_tree,
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn decompress_v1_with_metadata(
+    tree_authority: String,
+    leaf_owner: String,
+    leaf_delegate: String,
+    merkle_tree: String,
+    root: String,
+    metadata: NifMetadataArgs,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let root = decode_hash(&root)?;
+    let (data_hash, creator_hash) = leaf_hashes_from_metadata(&metadata)?;
+
+    decompress_v1_leaf(
+        &tree_authority,
+        &leaf_owner,
+        &leaf_delegate,
+        &merkle_tree,
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+fn delegate_leaf(
+    tree_authority: &str,
+    leaf_owner: &str,
+    previous_leaf_delegate: &str,
+    new_leaf_delegate: &str,
+    merkle_tree: &str,
+    root: &Node,
+    data_hash: &Node,
+    creator_hash: &Node,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let tree_authority = decode_pubkey(tree_authority)?;
+    let leaf_owner = decode_pubkey(leaf_owner)?;
+    let previous_leaf_delegate = decode_pubkey(previous_leaf_delegate)?;
+    let new_leaf_delegate = decode_pubkey(new_leaf_delegate)?;
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+
+    let mut ix = mpl_bubblegum::instructions::delegate(
+        &tree_authority,
+        &leaf_owner,
+        &previous_leaf_delegate,
+        &new_leaf_delegate,
+        &merkle_tree,
+        root.as_ref(),
+        data_hash.as_ref(),
+        creator_hash.as_ref(),
+        nonce,
+        index,
+    ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+    attach_proof(&mut ix, &merkle_tree, root, index);
+
+    process_instruction(ix, &leaf_owner, options)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn delegate(
     tree_authority: String,
     leaf_owner: String,
@@ -461,45 +1063,90 @@ pub fn delegate(
     creator_hash: String,
     nonce: u64,
     index: u32,
+    options: Option<NifTransactionOptions>,
 ) -> Result<String, BubblegumError> {
-    let tree_authority = Pubkey::from_str(&tree_authority)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_owner = Pubkey::from_str(&leaf_owner)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let previous_leaf_delegate = Pubkey::from_str(&previous_leaf_delegate)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let new_leaf_delegate = Pubkey::from_str(&new_leaf_delegate)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let merkle_tree = Pubkey::from_str(&merkle_tree)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    
-    let root = bs58::decode(root)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let data_hash = bs58::decode(data_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let creator_hash = bs58::decode(creator_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-
-    let ix = mpl_bubblegum::instructions::delegate(
+    let (root, data_hash, creator_hash) = decode_leaf_hashes(&root, &data_hash, &creator_hash)?;
+
+    delegate_leaf(
+        &tree_authority,
+        &leaf_owner,
+        &previous_leaf_delegate,
+        &new_leaf_delegate,
+        &merkle_tree,
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn delegate_with_metadata(
+    tree_authority: String,
+    leaf_owner: String,
+    previous_leaf_delegate: String,
+    new_leaf_delegate: String,
+    merkle_tree: String,
+    root: String,
+    metadata: NifMetadataArgs,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let root = decode_hash(&root)?;
+    let (data_hash, creator_hash) = leaf_hashes_from_metadata(&metadata)?;
+
+    delegate_leaf(
         &tree_authority,
         &leaf_owner,
         &previous_leaf_delegate,
         &new_leaf_delegate,
         &merkle_tree,
-        root.as_slice(),
-        data_hash.as_slice(),
-        creator_hash.as_slice(),
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+fn redeem_leaf(
+    tree_authority: &str,
+    leaf_owner: &str,
+    leaf_delegate: &str,
+    merkle_tree: &str,
+    root: &Node,
+    data_hash: &Node,
+    creator_hash: &Node,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let tree_authority = decode_pubkey(tree_authority)?;
+    let leaf_owner = decode_pubkey(leaf_owner)?;
+    let leaf_delegate = decode_pubkey(leaf_delegate)?;
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+
+    let mut ix = mpl_bubblegum::instructions::redeem(
+        &tree_authority,
+        &leaf_owner,
+        &leaf_delegate,
+        &merkle_tree,
+        root.as_ref(),
+        data_hash.as_ref(),
+        creator_hash.as_ref(),
         nonce,
         index,
     ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+    attach_proof(&mut ix, &merkle_tree, root, index);
 
-    process_instruction(ix)
+    process_instruction(ix, &leaf_owner, options)
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn redeem(
     tree_authority: String,
     leaf_owner: String,
@@ -510,42 +1157,84 @@ pub fn redeem(
     creator_hash: String,
     nonce: u64,
     index: u32,
+    options: Option<NifTransactionOptions>,
 ) -> Result<String, BubblegumError> {
-    let tree_authority = Pubkey::from_str(&tree_authority)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_owner = Pubkey::from_str(&leaf_owner)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_delegate = Pubkey::from_str(&leaf_delegate)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let merkle_tree = Pubkey::from_str(&merkle_tree)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    
-    let root = bs58::decode(root)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let data_hash = bs58::decode(data_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let creator_hash = bs58::decode(creator_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-
-    let ix = mpl_bubblegum::instructions::redeem(
+    let (root, data_hash, creator_hash) = decode_leaf_hashes(&root, &data_hash, &creator_hash)?;
+
+    redeem_leaf(
         &tree_authority,
         &leaf_owner,
         &leaf_delegate,
         &merkle_tree,
-        root.as_slice(),
-        data_hash.as_slice(),
-        creator_hash.as_slice(),
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn redeem_with_metadata(
+    tree_authority: String,
+    leaf_owner: String,
+    leaf_delegate: String,
+    merkle_tree: String,
+    root: String,
+    metadata: NifMetadataArgs,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let root = decode_hash(&root)?;
+    let (data_hash, creator_hash) = leaf_hashes_from_metadata(&metadata)?;
+
+    redeem_leaf(
+        &tree_authority,
+        &leaf_owner,
+        &leaf_delegate,
+        &merkle_tree,
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
+}
+
+fn cancel_redeem_leaf(
+    tree_authority: &str,
+    leaf_owner: &str,
+    merkle_tree: &str,
+    root: &Node,
+    data_hash: &Node,
+    creator_hash: &Node,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let tree_authority = decode_pubkey(tree_authority)?;
+    let leaf_owner = decode_pubkey(leaf_owner)?;
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+
+    let mut ix = mpl_bubblegum::instructions::cancel_redeem(
+        &tree_authority,
+        &leaf_owner,
+        &merkle_tree,
+        root.as_ref(),
+        data_hash.as_ref(),
+        creator_hash.as_ref(),
         nonce,
         index,
     ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+    attach_proof(&mut ix, &merkle_tree, root, index);
 
-    process_instruction(ix)
+    process_instruction(ix, &leaf_owner, options)
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn cancel_redeem(
     tree_authority: String,
     leaf_owner: String,
@@ -555,39 +1244,51 @@ pub fn cancel_redeem(
     creator_hash: String,
     nonce: u64,
     index: u32,
+    options: Option<NifTransactionOptions>,
 ) -> Result<String, BubblegumError> {
-    let tree_authority = Pubkey::from_str(&tree_authority)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let leaf_owner = Pubkey::from_str(&leaf_owner)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    let merkle_tree = Pubkey::from_str(&merkle_tree)
-        .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
-    
-    let root = bs58::decode(root)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let data_hash = bs58::decode(data_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-    let creator_hash = bs58::decode(creator_hash)
-        .into_vec()
-        .map_err(|e| BubblegumError::DecodingError(e.to_string()))?;
-
-    let ix = mpl_bubblegum::instructions::cancel_redeem(
+    let (root, data_hash, creator_hash) = decode_leaf_hashes(&root, &data_hash, &creator_hash)?;
+
+    cancel_redeem_leaf(
         &tree_authority,
         &leaf_owner,
         &merkle_tree,
-        root.as_slice(),
-        data_hash.as_slice(),
-        creator_hash.as_slice(),
+        &root,
+        &data_hash,
+        &creator_hash,
         nonce,
         index,
-    ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
+        options,
+    )
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+pub fn cancel_redeem_with_metadata(
+    tree_authority: String,
+    leaf_owner: String,
+    merkle_tree: String,
+    root: String,
+    metadata: NifMetadataArgs,
+    nonce: u64,
+    index: u32,
+    options: Option<NifTransactionOptions>,
+) -> Result<String, BubblegumError> {
+    let root = decode_hash(&root)?;
+    let (data_hash, creator_hash) = leaf_hashes_from_metadata(&metadata)?;
 
-    process_instruction(ix)
+    cancel_redeem_leaf(
+        &tree_authority,
+        &leaf_owner,
+        &merkle_tree,
+        &root,
+        &data_hash,
+        &creator_hash,
+        nonce,
+        index,
+        options,
+    )
 }
 
-#[rustler::nif]
+#[rustler::nif(schedule = "DirtyIo")]
 pub fn compress(
     tree_authority: String,
     leaf_owner: String,
@@ -595,6 +1296,7 @@ pub fn compress(
     merkle_tree: String,
     token_account: String,
     mint: String,
+    options: Option<NifTransactionOptions>,
 ) -> Result<String, BubblegumError> {
     let tree_authority = Pubkey::from_str(&tree_authority)
         .map_err(|e| BubblegumError::InvalidPublicKey(e.to_string()))?;
@@ -618,26 +1320,576 @@ pub fn compress(
         &mint,
     ).map_err(|e| BubblegumError::InstructionError(e.to_string()))?;
 
-    process_instruction(ix)
+    process_instruction(ix, &leaf_owner, options)
+}
+
+#[rustler::nif]
+fn compute_data_hash(metadata: NifMetadataArgs) -> NifResult<(Term, Term)> {
+    let metadata_args = match metadata_args_from_nif(&metadata) {
+        Ok(args) => args,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    match leaf::hash_metadata(&metadata_args) {
+        Ok(hash) => Ok((atoms::ok(), bs58::encode(hash).into_string().encode(env))),
+        Err(e) => Ok((atoms::error(), format!("Failed to serialize metadata: {}", e).encode(env))),
+    }
+}
+
+#[rustler::nif]
+fn compute_creator_hash(creators: Vec<NifCreator>) -> NifResult<(Term, Term)> {
+    match creators_from_nif(&creators) {
+        Ok(creators) => {
+            let hash = leaf::hash_creators(&creators);
+            Ok((atoms::ok(), bs58::encode(hash).into_string().encode(env)))
+        },
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif]
+fn get_asset_id(merkle_tree: String, nonce: u64) -> NifResult<(Term, Term)> {
+    let tree = match decode_pubkey(&merkle_tree) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    Ok((atoms::ok(), leaf::get_asset_id(&tree, nonce).to_string().encode(env)))
+}
+
+#[rustler::nif]
+fn compute_leaf(
+    merkle_tree: String,
+    leaf_owner: String,
+    leaf_delegate: String,
+    nonce: u64,
+    metadata: NifMetadataArgs,
+) -> NifResult<(Term, Term)> {
+    let tree = match decode_pubkey(&merkle_tree) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let owner = match decode_pubkey(&leaf_owner) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let delegate = match decode_pubkey(&leaf_delegate) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let metadata_args = match metadata_args_from_nif(&metadata) {
+        Ok(args) => args,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let leaf = match leaf::LeafSchemaV1::new(&tree, &owner, &delegate, nonce, &metadata_args) {
+        Ok(leaf) => leaf,
+        Err(e) => return Ok((atoms::error(), format!("Failed to serialize metadata: {}", e).encode(env))),
+    };
+
+    let schema = NifLeafSchema {
+        asset_id: leaf.id.to_string(),
+        owner: leaf.owner.to_string(),
+        delegate: leaf.delegate.to_string(),
+        nonce: leaf.nonce,
+        data_hash: bs58::encode(leaf.data_hash).into_string(),
+        creator_hash: bs58::encode(leaf.creator_hash).into_string(),
+        leaf_hash: bs58::encode(leaf.hash()).into_string(),
+    };
+
+    Ok((atoms::ok(), schema.encode(env)))
+}
+
+fn load_tree_account(merkle_tree: &str) -> Result<tree_account::TreeAccount, BubblegumError> {
+    let tree = decode_pubkey(merkle_tree)?;
+    let client = get_client()?;
+    let account = client.get_account(&tree)
+        .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+
+    if account.owner != mpl_bubblegum::SPL_ACCOUNT_COMPRESSION_ID {
+        return Err(BubblegumError::DecodingError(format!(
+            "{} is not owned by spl-account-compression",
+            merkle_tree
+        )));
+    }
+
+    tree_account::TreeAccount::parse(&account.data)
+        .map_err(|e| BubblegumError::DecodingError(e.to_string()))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn fetch_tree_account(merkle_tree: String) -> NifResult<(Term, Term)> {
+    let account = match load_tree_account(&merkle_tree) {
+        Ok(account) => account,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let tree_account = NifTreeAccount {
+        merkle_tree,
+        authority: account.authority.to_string(),
+        max_depth: account.max_depth,
+        max_buffer_size: account.max_buffer_size,
+        canopy_depth: account.canopy_depth(),
+        creation_slot: account.creation_slot,
+        sequence_number: account.sequence_number,
+        active_index: account.active_index,
+        num_leaves: account.num_leaves(),
+        current_root: account.root().to_string(),
+        canopy: account.canopy.iter().map(Node::to_string).collect(),
+    };
+
+    Ok((atoms::ok(), tree_account.encode(env)))
+}
+
+/// Opens `merkle_tree` in the tree manager with the parameters of its on-chain account.
+fn manage_tree_account(merkle_tree: &str) -> Result<NifTreeDescriptor, BubblegumError> {
+    let account = load_tree_account(merkle_tree)?;
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let manager = get_tree_manager()?;
+
+    futures::executor::block_on(async {
+        let managed = manager.is_managed(&merkle_tree).await
+            .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+        // The account alone does not hold the leaves needed to seed a local copy.
+        if !managed && account.sequence_number > 0 {
+            return Err(BubblegumError::ConfigError(format!(
+                "Tree {} has already changed on chain and cannot be managed from an empty local copy",
+                merkle_tree
+            )));
+        }
+
+        let descriptor = TreeDescriptor::from(&account);
+        manager.open_tree(&merkle_tree, descriptor).await
+            .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+        Ok(NifTreeDescriptor::new(&merkle_tree, &descriptor))
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn manage_tree(merkle_tree: String) -> NifResult<(Term, Term)> {
+    match manage_tree_account(&merkle_tree) {
+        Ok(descriptor) => Ok((atoms::ok(), descriptor.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn list_managed_trees() -> NifResult<(Term, Term)> {
+    let manager = match get_tree_manager() {
+        Ok(manager) => manager,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    match futures::executor::block_on(manager.list_trees()) {
+        Ok(trees) => {
+            let trees: Vec<NifTreeDescriptor> = trees
+                .iter()
+                .map(|(merkle_tree, descriptor)| NifTreeDescriptor::new(merkle_tree, descriptor))
+                .collect();
+            Ok((atoms::ok(), trees.encode(env)))
+        },
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_multi_proof(merkle_tree: String, indices: Vec<u32>) -> NifResult<(Term, Term)> {
+    let merkle_tree = match decode_pubkey(&merkle_tree) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let manager = match get_tree_manager() {
+        Ok(manager) => manager,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    match futures::executor::block_on(manager.get_multi_proof(&merkle_tree, &indices)) {
+        Ok((proof, root)) => {
+            let multi_proof = NifMultiProof {
+                root: root.to_string(),
+                indices: proof.indices.clone(),
+                proof: BASE64.encode(proof.to_bytes()),
+            };
+            Ok((atoms::ok(), multi_proof.encode(env)))
+        },
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_proof_at(merkle_tree: String, index: u32, seq: u64) -> NifResult<(Term, Term)> {
+    let merkle_tree = match decode_pubkey(&merkle_tree) {
+        Ok(key) => key,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let manager = match get_tree_manager() {
+        Ok(manager) => manager,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    match futures::executor::block_on(manager.get_proof_at(&merkle_tree, index, seq)) {
+        Ok(historical) => {
+            let historical = NifHistoricalProof {
+                index: historical.index,
+                seq: historical.seq,
+                leaf: historical.leaf.to_string(),
+                proof: historical.proof.iter().map(Node::to_string).collect(),
+                root: historical.root.to_string(),
+            };
+            Ok((atoms::ok(), historical.encode(env)))
+        },
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif]
+fn verify_multi_proof(root: String, leaves: Vec<String>, proof: String) -> NifResult<(Term, Term)> {
+    let root = match decode_hash(&root) {
+        Ok(root) => root,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let leaves = match leaves.iter().map(|leaf| decode_hash(leaf)).collect::<Result<Vec<_>, _>>() {
+        Ok(leaves) => leaves,
+        Err(e) => return Ok((atoms::error(), e.to_string().encode(env))),
+    };
+
+    let proof = match BASE64.decode(&proof) {
+        Ok(bytes) => bytes,
+        Err(e) => return Ok((atoms::error(), format!("Invalid base64 proof: {}", e).encode(env))),
+    };
+
+    match merkle::MultiProof::from_bytes(&proof) {
+        Ok(proof) => Ok((atoms::ok(), merkle::MerkleTree::verify_multi_proof(&root, &leaves, &proof).encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+fn record_managed_leaf(
+    merkle_tree: &str,
+    index: u32,
+    leaf_owner: &str,
+    leaf_delegate: &str,
+    nonce: u64,
+    metadata: &NifMetadataArgs,
+) -> Result<NifLeafRecord, BubblegumError> {
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let owner = decode_pubkey(leaf_owner)?;
+    let delegate = decode_pubkey(leaf_delegate)?;
+    let metadata_args = metadata_args_from_nif(metadata)?;
+    let leaf = leaf::LeafSchemaV1::new(&merkle_tree, &owner, &delegate, nonce, &metadata_args)
+        .map_err(|e| BubblegumError::MetadataError(format!("Failed to serialize metadata: {}", e)))?;
+    let collection = metadata_args.collection.map(|collection| collection.key);
+    let record = LeafRecord::new(index, &leaf, collection);
+
+    let manager = get_tree_manager()?;
+    futures::executor::block_on(manager.record_leaf(&merkle_tree, &record))
+        .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+    Ok(NifLeafRecord::new(&merkle_tree, &record))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn record_leaf(
+    merkle_tree: String,
+    index: u32,
+    leaf_owner: String,
+    leaf_delegate: String,
+    nonce: u64,
+    metadata: NifMetadataArgs,
+) -> NifResult<(Term, Term)> {
+    match record_managed_leaf(&merkle_tree, index, &leaf_owner, &leaf_delegate, nonce, &metadata) {
+        Ok(record) => Ok((atoms::ok(), record.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn find_managed_leaves(
+    merkle_tree: &str,
+    key: &str,
+    by_collection: bool,
+) -> Result<Vec<NifLeafRecord>, BubblegumError> {
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let key = decode_pubkey(key)?;
+    let manager = get_tree_manager()?;
+
+    let records = futures::executor::block_on(async {
+        if by_collection {
+            manager.leaves_by_collection(&merkle_tree, &key).await
+        } else {
+            manager.leaves_by_owner(&merkle_tree, &key).await
+        }
+    })
+    .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+
+    Ok(records.iter().map(|record| NifLeafRecord::new(&merkle_tree, record)).collect())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn find_leaves_by_owner(merkle_tree: String, owner: String) -> NifResult<(Term, Term)> {
+    match find_managed_leaves(&merkle_tree, &owner, false) {
+        Ok(records) => Ok((atoms::ok(), records.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn find_leaves_by_collection(merkle_tree: String, collection: String) -> NifResult<(Term, Term)> {
+    match find_managed_leaves(&merkle_tree, &collection, true) {
+        Ok(records) => Ok((atoms::ok(), records.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn begin_managed_leaf(merkle_tree: &str, index: Option<u32>, leaf: Option<&str>) -> Result<u64, BubblegumError> {
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let op = match (index, leaf.map(decode_hash).transpose()?) {
+        (None, Some(leaf)) => LeafOp::Append(vec![leaf]),
+        (Some(index), Some(leaf)) => LeafOp::Replace { index, leaf },
+        (Some(index), None) => LeafOp::Remove { index },
+        (None, None) => return Err(BubblegumError::ConfigError("A leaf change needs an index, a leaf or both".to_string())),
+    };
+
+    let manager = get_tree_manager()?;
+    futures::executor::block_on(manager.begin_leaf(&merkle_tree, op))
+        .map_err(|e| BubblegumError::ConfigError(e.to_string()))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn begin_leaf(merkle_tree: String, index: Option<u32>, leaf: Option<String>) -> NifResult<(Term, Term)> {
+    match begin_managed_leaf(&merkle_tree, index, leaf.as_deref()) {
+        Ok(id) => Ok((atoms::ok(), id.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn commit_managed_leaf(id: u64, signature: &str) -> Result<Vec<(u32, String)>, BubblegumError> {
+    let signature = Signature::from_str(signature)
+        .map_err(|e| BubblegumError::TransactionError(format!("Invalid signature: {}", e)))?;
+    let manager = get_tree_manager()?;
+    let (indices, roots) = futures::executor::block_on(manager.commit_leaf(id, signature))
+        .map_err(|e| BubblegumError::ConfigError(e.to_string()))?;
+    Ok(indices.zip(roots.iter().map(Node::to_string)).collect())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn commit_leaf(id: u64, signature: String) -> NifResult<(Term, Term)> {
+    match commit_managed_leaf(id, &signature) {
+        Ok(roots) => Ok((atoms::ok(), roots.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn abort_leaf(id: u64) -> NifResult<(Term, Term)> {
+    let aborted = get_tree_manager().and_then(|manager| {
+        manager.abort_leaf(id).map_err(|e| BubblegumError::ConfigError(e.to_string()))
+    });
+    match aborted {
+        Ok(()) => Ok((atoms::ok(), id.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[rustler::nif]
+fn tree_cache_stats() -> NifResult<(Term, Term)> {
+    match get_tree_manager() {
+        Ok(manager) => Ok((atoms::ok(), NifCacheStats::from(manager.cache_stats()).encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn ensure_lookup_table(merkle_tree: &str) -> Result<Pubkey, BubblegumError> {
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let keypair = current_keypair()?;
+    let manager = get_transaction_manager()?;
+    // Extend the table stored with the tree rather than creating another one.
+    restore_lookup_table(&get_tree_manager()?, &merkle_tree)?;
+    let table = futures::executor::block_on(manager.ensure_tree_lookup_table(&merkle_tree, &keypair, &keypair))?;
+    save_lookup_table(&merkle_tree, &table)?;
+    Ok(table)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn create_tree_lookup_table(merkle_tree: String) -> NifResult<(Term, Term)> {
+    match ensure_lookup_table(&merkle_tree) {
+        Ok(table) => Ok((atoms::ok(), table.to_string().encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+fn register_lookup_table(merkle_tree: &str, table: &str) -> Result<(), BubblegumError> {
+    let merkle_tree = decode_pubkey(merkle_tree)?;
+    let table = decode_pubkey(table)?;
+    get_transaction_manager()?.register_lookup_table(&merkle_tree, &table)?;
+    save_lookup_table(&merkle_tree, &table)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn register_tree_lookup_table(merkle_tree: String, table: String) -> NifResult<(Term, Term)> {
+    match register_lookup_table(&merkle_tree, &table) {
+        Ok(()) => Ok((atoms::ok(), table.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+#[derive(NifStruct)]
+#[module = "BubblegumNif.Types.NonceAccount"]
+pub struct NifNonceAccount {
+    pub address: String,
+    pub authority: String,
+    pub nonce: String,
+    pub lamports_per_signature: u64,
+}
+
+fn new_nonce_account(authority: Option<&str>) -> Result<Pubkey, BubblegumError> {
+    let payer = current_keypair()?;
+    let authority = match authority {
+        Some(authority) => decode_pubkey(authority)?,
+        None => payer.pubkey(),
+    };
+    let nonce_account = Keypair::new();
+    let manager = get_transaction_manager()?;
+    futures::executor::block_on(manager.create_nonce_account(&payer, &nonce_account, &authority))?;
+    Ok(nonce_account.pubkey())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn create_nonce_account(authority: Option<String>) -> NifResult<(Term, Term)> {
+    match new_nonce_account(authority.as_deref()) {
+        Ok(nonce_account) => Ok((atoms::ok(), nonce_account.to_string().encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+fn load_nonce_account(nonce_account: &str) -> Result<NifNonceAccount, BubblegumError> {
+    let address = decode_pubkey(nonce_account)?;
+    let data = get_transaction_manager()?.nonce_info(&address)?;
+    Ok(NifNonceAccount {
+        address: address.to_string(),
+        authority: data.authority.to_string(),
+        nonce: data.blockhash().to_string(),
+        lamports_per_signature: data.fee_calculator.lamports_per_signature,
+    })
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_nonce(nonce_account: String) -> NifResult<(Term, Term)> {
+    match load_nonce_account(&nonce_account) {
+        Ok(account) => Ok((atoms::ok(), account.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+/// A keypair held by Elixir as an opaque handle, for signing transactions
+/// without making it the loaded keypair.
+pub struct SignerResource(Keypair);
+
+#[rustler::nif]
+fn load_signer(keypair_json: String) -> NifResult<(Term, Term)> {
+    let keypair = serde_json::from_str::<Vec<u8>>(&keypair_json)
+        .map_err(|e| BubblegumError::KeypairError(format!("Invalid keypair JSON: {}", e)))
+        .and_then(|bytes| Keypair::from_bytes(&bytes)
+            .map_err(|e| BubblegumError::KeypairError(format!("Invalid keypair bytes: {}", e))));
+
+    match keypair {
+        Ok(keypair) => Ok((atoms::ok(), ResourceArc::new(SignerResource(keypair)).encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn sign_transaction(encoded: &str, signer: Option<&Keypair>) -> Result<String, BubblegumError> {
+    let mut tx = decode_transaction(encoded)?;
+    match signer {
+        Some(keypair) => transaction::partially_sign(&mut tx, keypair)?,
+        None => transaction::partially_sign(&mut tx, &current_keypair()?)?,
+    }
+    encode_transaction(&tx)
+}
+
+#[rustler::nif]
+fn partially_sign(tx: String, signer: Option<ResourceArc<SignerResource>>) -> NifResult<(Term, Term)> {
+    match sign_transaction(&tx, signer.as_ref().map(|signer| &signer.0)) {
+        Ok(tx) => Ok((atoms::ok(), tx.encode(env))),
+        Err(e) => Ok((atoms::error(), e.to_string().encode(env))),
+    }
+}
+
+fn send_signed(encoded: &str) -> Result<String, BubblegumError> {
+    let tx = decode_transaction(encoded)?;
+    let manager = get_transaction_manager()?;
+    let signature = futures::executor::block_on(manager.submit_signed(&tx))?.into_result()?;
+    info!("Signed transaction landed: {}", signature);
+    Ok(signature.to_string())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn submit_signed(tx: String) -> NifResult<(Term, Term)> {
+    match send_signed(&tx) {
+        Ok(signature) => Ok((atoms::ok(), signature.encode(env))),
+        Err(e) => Ok((atoms::error(), e.encode(env))),
+    }
+}
+
+fn load(env: Env, _info: Term) -> bool {
+    rustler::resource!(SignerResource, env);
+    true
 }
 
 mod atoms {
     rustler::atoms! {
         ok,
-        error
+        error,
+        expired,
+        failed,
+        unknown
     }
 }
 
 rustler::init!("Elixir.BubblegumNif", [
     initialize_client,
     load_keypair,
+    configure_tree_storage,
     create_tree_config,
     mint_v1,
     transfer,
+    transfer_with_metadata,
     request_airdrop,
     decompress_v1,
+    decompress_v1_with_metadata,
     delegate,
+    delegate_with_metadata,
     redeem,
+    redeem_with_metadata,
     cancel_redeem,
-    compress
-]);
+    cancel_redeem_with_metadata,
+    compress,
+    compute_data_hash,
+    compute_creator_hash,
+    get_asset_id,
+    compute_leaf,
+    fetch_tree_account,
+    manage_tree,
+    list_managed_trees,
+    get_multi_proof,
+    get_proof_at,
+    verify_multi_proof,
+    record_leaf,
+    find_leaves_by_owner,
+    find_leaves_by_collection,
+    begin_leaf,
+    commit_leaf,
+    abort_leaf,
+    tree_cache_stats,
+    create_tree_lookup_table,
+    register_tree_lookup_table,
+    create_nonce_account,
+    get_nonce,
+    load_signer,
+    partially_sign,
+    submit_signed
+], load = load);
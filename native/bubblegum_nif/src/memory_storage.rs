@@ -0,0 +1,166 @@
+use crate::merkle::{ChangeLog, MerkleTree, Node, Subtree, SUBTREE_HEIGHT};
+use crate::tree_manager::{TreeDescriptor, TreeStorage};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::BTreeMap;
+
+/// A stored tree as incremental backends keep it: the header snapshot, the
+/// changelog buffer slots and every non-empty node.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct TreeParts {
+    pub header: Vec<u8>,
+    pub change_logs: BTreeMap<u32, ChangeLog>,
+    pub nodes: BTreeMap<(u32, u32), Node>,
+}
+
+/// Everything one `save_tree` call changes. Values are absolute, so applying
+/// the same update twice leaves the parts as they were after the first time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TreeUpdate {
+    pub header: Vec<u8>,
+    pub change_logs: Vec<(u32, ChangeLog)>,
+    pub nodes: Vec<(u32, u32, Option<Node>)>,
+}
+
+impl TreeUpdate {
+    /// The changes made to `tree` since it was last marked saved.
+    pub fn from_tree(tree: &MerkleTree) -> anyhow::Result<Self> {
+        Ok(Self {
+            header: tree.header_snapshot()?,
+            change_logs: tree
+                .dirty_change_logs()
+                .map(|(slot, change_log)| (slot, change_log.clone()))
+                .collect(),
+            nodes: tree.dirty_nodes().collect(),
+        })
+    }
+}
+
+impl TreeParts {
+    pub fn apply(&mut self, update: TreeUpdate) {
+        self.header = update.header;
+        self.change_logs.extend(update.change_logs);
+        for (level, index, node) in update.nodes {
+            match node {
+                Some(node) => self.nodes.insert((level, index), node),
+                None => self.nodes.remove(&(level, index)),
+            };
+        }
+    }
+
+    pub fn to_tree(&self) -> anyhow::Result<MerkleTree> {
+        Ok(MerkleTree::from_parts(
+            &self.header,
+            self.change_logs.iter().map(|(slot, change_log)| (*slot, change_log.clone())),
+            self.nodes.iter().map(|((level, index), node)| (*level, *index, *node)),
+        )?)
+    }
+
+    /// The stored nodes below the root of leaf-level subtree `index`.
+    pub fn subtree(&self, index: u32) -> Subtree {
+        let mut nodes = Vec::new();
+        for level in 0..SUBTREE_HEIGHT {
+            let width = 1u32 << (SUBTREE_HEIGHT - level);
+            let range = (level, index * width)..=(level, (index + 1) * width - 1);
+            nodes.extend(self.nodes.range(range).map(|((level, index), node)| (*level, *index, *node)));
+        }
+        Subtree { index, nodes }
+    }
+}
+
+#[derive(Default)]
+struct StoredTree {
+    descriptor: Option<TreeDescriptor>,
+    parts: Option<TreeParts>,
+    history: BTreeMap<u64, ChangeLog>,
+}
+
+/// Keeps every tree in process memory. Nothing survives a restart, which makes
+/// it suited to tests and ephemeral workers that rebuild their trees anyway.
+#[derive(Default)]
+pub struct InMemoryStorage {
+    trees: DashMap<Pubkey, StoredTree>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl TreeStorage for InMemoryStorage {
+    async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
+        match self.trees.get(merkle_tree).and_then(|stored| stored.parts.clone()) {
+            Some(parts) => parts
+                .to_tree()
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("Failed to load tree {}: {}", merkle_tree, e)),
+            None => Ok(None),
+        }
+    }
+
+    async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
+        let update = TreeUpdate::from_tree(tree)?;
+        let mut stored = self.trees.entry(*merkle_tree).or_default();
+        stored.parts.get_or_insert_with(TreeParts::default).apply(update);
+        stored.history.extend(tree.unsaved_change_logs().iter().cloned());
+        Ok(())
+    }
+
+    async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()> {
+        self.trees.remove(merkle_tree);
+        Ok(())
+    }
+
+    async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()> {
+        self.trees.entry(*merkle_tree).or_default().descriptor = Some(*descriptor);
+        Ok(())
+    }
+
+    async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+        Ok(self.trees.get(merkle_tree).and_then(|stored| stored.descriptor))
+    }
+
+    async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+        Ok(self
+            .trees
+            .iter()
+            .filter_map(|stored| stored.descriptor.map(|descriptor| (*stored.key(), descriptor)))
+            .collect())
+    }
+
+    /// Like `RocksDBStorage`, every node is already held by `save_tree`.
+    async fn save_subtree(&self, _merkle_tree: &Pubkey, _subtree: &Subtree) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>> {
+        Ok(self
+            .trees
+            .get(merkle_tree)
+            .and_then(|stored| stored.parts.as_ref().map(|parts| parts.subtree(index))))
+    }
+
+    async fn load_change_logs(
+        &self,
+        merkle_tree: &Pubkey,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(u64, ChangeLog)>> {
+        Ok(self
+            .trees
+            .get(merkle_tree)
+            .map(|stored| {
+                stored
+                    .history
+                    .range(from_seq..)
+                    .take(limit)
+                    .map(|(seq, change_log)| (*seq, change_log.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
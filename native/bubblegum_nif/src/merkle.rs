@@ -1,107 +1,2125 @@
-use solana_program::keccak;
-use std::collections::HashMap;
+use crate::tree_account::TreeAccount;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use solana_program::{instruction::AccountMeta, keccak, pubkey::Pubkey};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    ops::Range,
+    str::FromStr,
+};
 
-#[derive(Debug)]
+/// A 32-byte tree node: a leaf hash, an interior node or a root.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Node(pub [u8; 32]);
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NodeError {
+    #[error("Hash must be 32 bytes, got {0}")]
+    InvalidLength(usize),
+    #[error("Hash is not valid base58: {0}")]
+    InvalidEncoding(String),
+}
+
+impl Node {
+    pub const fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+impl From<[u8; 32]> for Node {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Node> for [u8; 32] {
+    fn from(node: Node) -> Self {
+        node.0
+    }
+}
+
+impl TryFrom<&[u8]> for Node {
+    type Error = NodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_| NodeError::InvalidLength(bytes.len()))
+    }
+}
+
+impl AsRef<[u8]> for Node {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for Node {
+    type Err = NodeError;
+
+    /// Parses a base58 encoded hash.
+    fn from_str(encoded: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(encoded)
+            .into_vec()
+            .map_err(|e| NodeError::InvalidEncoding(e.to_string()))?;
+        Self::try_from(bytes.as_slice())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&bs58::encode(self.0).into_string())
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node({})", self)
+    }
+}
+
+pub const EMPTY_NODE: Node = Node([0; 32]);
+
+/// Height of the leaf-level subtrees that can be spilled out of memory as a unit.
+pub const SUBTREE_HEIGHT: u32 = 10;
+
+/// (max_depth, max_buffer_size) pairs accepted by spl-account-compression.
+pub const VALID_DEPTH_SIZE_PAIRS: &[(u32, u32)] = &[
+    (3, 8),
+    (5, 8),
+    (6, 16),
+    (7, 16),
+    (8, 16),
+    (9, 16),
+    (10, 32),
+    (11, 32),
+    (12, 32),
+    (13, 32),
+    (14, 64),
+    (14, 256),
+    (14, 1024),
+    (14, 2048),
+    (15, 64),
+    (16, 64),
+    (17, 64),
+    (18, 64),
+    (19, 64),
+    (20, 64),
+    (20, 256),
+    (20, 1024),
+    (20, 2048),
+    (24, 64),
+    (24, 256),
+    (24, 512),
+    (24, 1024),
+    (24, 2048),
+    (26, 512),
+    (26, 1024),
+    (26, 2048),
+    (30, 512),
+    (30, 1024),
+    (30, 2048),
+];
+
+pub fn is_valid_depth_size_pair(max_depth: u32, max_buffer_size: u32) -> bool {
+    VALID_DEPTH_SIZE_PAIRS.contains(&(max_depth, max_buffer_size))
+}
+
+/// Hash of an empty subtree of the given height, as used by the on-chain program.
+pub fn empty_node(level: u32) -> Node {
+    let mut node = EMPTY_NODE;
+    for _ in 0..level {
+        node = hash_to_parent(&node, &node, true);
+    }
+    node
+}
+
+fn hash_to_parent(node: &Node, sibling: &Node, is_left: bool) -> Node {
+    let (left, right) = if is_left { (node, sibling) } else { (sibling, node) };
+    Node(keccak::hashv(&[&left.0, &right.0]).to_bytes())
+}
+
+/// Nodes changed by a single tree operation, leaf first, plus the root they produced.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeLog {
+    pub root: Node,
+    pub path: Vec<Node>,
+    pub index: u32,
+}
+
+impl ChangeLog {
+    pub fn get_leaf(&self) -> Node {
+        self.path[0]
+    }
+
+    /// Applies this change to a proof for `leaf_index`: either swaps in the one
+    /// proof node the change touched, or updates the leaf if it was the one modified.
+    pub fn update_proof_or_leaf(&self, leaf_index: u32, proof: &mut [Node], leaf: &mut Node) {
+        let depth = self.path.len();
+        if leaf_index != self.index {
+            let common_path_len = ((leaf_index ^ self.index) << (32 - depth)).leading_zeros() as usize;
+            let critbit_index = (depth - 1) - common_path_len;
+            proof[critbit_index] = self.path[critbit_index];
+        } else {
+            *leaf = self.get_leaf();
+        }
+    }
+}
+
+/// Proof for the rightmost leaf, which lets appends run without a caller-supplied proof.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Path {
+    pub proof: Vec<Node>,
+    pub leaf: Node,
+    pub index: u32,
+}
+
+/// Outcome of `MerkleTree::append_batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchAppend {
+    /// Leaf indices assigned to the batch, in append order.
+    pub indices: Range<u32>,
+    /// Root after each append, i.e. the root each mint transaction will see.
+    pub roots: Vec<Node>,
+}
+
+/// Version byte leading a serialized `MultiProof`.
+pub const MULTI_PROOF_VERSION: u8 = 1;
+
+/// Inclusion proof for several leaves at once. `nodes` holds every sibling
+/// that cannot be derived from the leaves themselves, level by level and left
+/// to right, so interior nodes shared by several paths appear only once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiProof {
+    pub max_depth: u32,
+    /// Leaf indices covered by the proof, strictly increasing.
+    pub indices: Vec<u32>,
+    pub nodes: Vec<Node>,
+}
+
+/// Positions (level, index) of the siblings a multi-proof for `indices` must
+/// supply, in proof order. `indices` must be strictly increasing.
+fn multi_proof_siblings(max_depth: u32, indices: &[u32]) -> Vec<(u32, u32)> {
+    let mut siblings = Vec::new();
+    let mut known = indices.to_vec();
+    for level in 0..max_depth {
+        let mut parents = Vec::with_capacity(known.len());
+        let mut i = 0;
+        while i < known.len() {
+            let index = known[i];
+            if index & 1 == 0 && known.get(i + 1) == Some(&(index | 1)) {
+                i += 2;
+            } else {
+                siblings.push((level, index ^ 1));
+                i += 1;
+            }
+            parents.push(index >> 1);
+        }
+        known = parents;
+    }
+    siblings
+}
+
+fn is_valid_multi_proof_shape(max_depth: u32, indices: &[u32]) -> bool {
+    max_depth <= 30
+        && !indices.is_empty()
+        && indices.windows(2).all(|pair| pair[0] < pair[1])
+        && (indices[indices.len() - 1] as u64) < 1u64 << max_depth
+}
+
+impl MultiProof {
+    /// Serializes as: version (u8), max depth (u8), leaf count (u32 LE), indices
+    /// (u32 LE each), a bitmap with one bit per sibling set when the sibling is
+    /// an empty subtree, then the remaining 32-byte nodes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let siblings = multi_proof_siblings(self.max_depth, &self.indices);
+        let mut bitmap = vec![0u8; siblings.len().div_ceil(8)];
+        let mut nodes = Vec::with_capacity(self.nodes.len() * 32);
+        for (i, ((level, _), node)) in siblings.iter().zip(&self.nodes).enumerate() {
+            if *node == empty_node(*level) {
+                bitmap[i / 8] |= 1 << (i % 8);
+            } else {
+                nodes.extend_from_slice(&node.0);
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(6 + self.indices.len() * 4 + bitmap.len() + nodes.len());
+        bytes.push(MULTI_PROOF_VERSION);
+        bytes.push(self.max_depth as u8);
+        bytes.extend_from_slice(&(self.indices.len() as u32).to_le_bytes());
+        for index in &self.indices {
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        bytes.extend_from_slice(&bitmap);
+        bytes.extend_from_slice(&nodes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, &'static str> {
+        let (&version, rest) = bytes.split_first().ok_or("Multi-proof is empty")?;
+        if version != MULTI_PROOF_VERSION {
+            return Err("Unsupported multi-proof version");
+        }
+        let (&max_depth, rest) = rest.split_first().ok_or("Multi-proof is truncated")?;
+        let max_depth = max_depth as u32;
+        if rest.len() < 4 {
+            return Err("Multi-proof is truncated");
+        }
+        let (count, rest) = rest.split_at(4);
+        let count = u32::from_le_bytes(count.try_into().unwrap()) as usize;
+        if rest.len() / 4 < count {
+            return Err("Multi-proof is truncated");
+        }
+        let (indices, rest) = rest.split_at(count * 4);
+        let indices: Vec<u32> = indices
+            .chunks_exact(4)
+            .map(|index| u32::from_le_bytes(index.try_into().unwrap()))
+            .collect();
+        if !is_valid_multi_proof_shape(max_depth, &indices) {
+            return Err("Multi-proof indices are invalid");
+        }
+
+        let siblings = multi_proof_siblings(max_depth, &indices);
+        let bitmap_len = siblings.len().div_ceil(8);
+        if rest.len() < bitmap_len {
+            return Err("Multi-proof is truncated");
+        }
+        let (bitmap, mut rest) = rest.split_at(bitmap_len);
+        let mut nodes = Vec::with_capacity(siblings.len());
+        for (i, (level, _)) in siblings.iter().enumerate() {
+            if bitmap[i / 8] & (1 << (i % 8)) != 0 {
+                nodes.push(empty_node(*level));
+            } else {
+                if rest.len() < 32 {
+                    return Err("Multi-proof is truncated");
+                }
+                let (node, tail) = rest.split_at(32);
+                nodes.push(Node(node.try_into().unwrap()));
+                rest = tail;
+            }
+        }
+        if !rest.is_empty() {
+            return Err("Multi-proof has trailing bytes");
+        }
+
+        Ok(Self {
+            max_depth,
+            indices,
+            nodes,
+        })
+    }
+}
+
+/// Proof for a leaf as it stood at an earlier sequence number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoricalProof {
+    pub index: u32,
+    pub seq: u64,
+    /// The leaf's value at `seq`, which may since have been replaced or burned.
+    pub leaf: Node,
+    pub proof: Vec<Node>,
+    pub root: Node,
+}
+
+/// Rebuilds a proof as of sequence number `seq` by replaying every changelog
+/// from the empty tree onwards, fed in order through `apply`.
+#[derive(Debug, Clone)]
+pub struct ProofReplay {
+    index: u32,
+    seq: u64,
+    next_seq: u64,
+    num_leaves: u64,
+    leaf: Node,
+    proof: Vec<Node>,
+    root: Node,
+}
+
+impl ProofReplay {
+    pub fn new(max_depth: u32, index: u32, seq: u64) -> Result<Self, &'static str> {
+        if index as u64 >= 1u64 << max_depth {
+            return Err("Index out of bounds");
+        }
+        let empty_nodes: Vec<Node> = (0..=max_depth).map(empty_node).collect();
+
+        Ok(Self {
+            index,
+            seq,
+            next_seq: 1,
+            num_leaves: 0,
+            leaf: EMPTY_NODE,
+            proof: empty_nodes[..max_depth as usize].to_vec(),
+            root: empty_nodes[max_depth as usize],
+        })
+    }
+
+    /// The next sequence number `apply` expects.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Applies (sequence number, changelog) pairs, which must continue exactly
+    /// where the previous call stopped. Entries past the target are ignored.
+    pub fn apply(&mut self, change_logs: &[(u64, ChangeLog)]) -> Result<(), &'static str> {
+        for (seq, change_log) in change_logs {
+            if self.next_seq > self.seq {
+                break;
+            }
+            if *seq != self.next_seq {
+                return Err("Changelog history is incomplete");
+            }
+            if change_log.path.len() != self.proof.len() {
+                return Err("Changelog depth does not match the tree");
+            }
+            change_log.update_proof_or_leaf(self.index, &mut self.proof, &mut self.leaf);
+            self.num_leaves = self.num_leaves.max(change_log.index as u64 + 1);
+            self.root = change_log.root;
+            self.next_seq += 1;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<HistoricalProof, &'static str> {
+        if self.next_seq <= self.seq {
+            return Err("Changelog history is incomplete");
+        }
+        if self.index as u64 >= self.num_leaves {
+            return Err("Leaf had not been appended at that sequence number");
+        }
+
+        Ok(HistoricalProof {
+            index: self.index,
+            seq: self.seq,
+            leaf: self.leaf,
+            proof: self.proof,
+            root: self.root,
+        })
+    }
+}
+
+/// Why a stale proof could not be brought up to the current root.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FastForwardError {
+    #[error("Proof has {actual} nodes, expected {expected}")]
+    InvalidProofLength { expected: usize, actual: usize },
+    #[error("Leaf index {0} has not been appended yet")]
+    IndexOutOfBounds(u32),
+    #[error("Root is not among the last {0} changelog entries")]
+    RootNotInBuffer(u64),
+    #[error("Proof does not hash to the given root")]
+    InvalidProof,
+    #[error("Leaf {index} was modified at sequence number {seq}")]
+    LeafContentsModified { index: u32, seq: u64 },
+}
+
+/// Leading bytes of a serialized `MerkleTree` snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"BGMT";
+
+/// Version of the snapshot payload written by this build.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// Leading bytes of a serialized tree header, the part of a tree that
+/// incremental storage keeps apart from its nodes and changelogs.
+pub const HEADER_MAGIC: [u8; 4] = *b"BGMH";
+
+/// Version of the header payload written by this build.
+pub const HEADER_VERSION: u16 = 1;
+
+// Magic, version (u16 LE) and payload length (u64 LE).
+const SNAPSHOT_HEADER_LEN: usize = 4 + 2 + 8;
+
+/// Upgrades a snapshot payload from one version to the next.
+pub type SnapshotMigration = fn(Vec<u8>) -> Result<Vec<u8>, SnapshotError>;
+
+/// `SNAPSHOT_MIGRATIONS[v - 1]` turns a version `v` payload into a version
+/// `v + 1` one. Bumping `SNAPSHOT_VERSION` means appending a hook here.
+pub const SNAPSHOT_MIGRATIONS: &[SnapshotMigration] = &[];
+
+/// Same as `SNAPSHOT_MIGRATIONS`, for header payloads.
+pub const HEADER_MIGRATIONS: &[SnapshotMigration] = &[];
+
+const _: () = assert!(SNAPSHOT_MIGRATIONS.len() + 1 == SNAPSHOT_VERSION as usize);
+const _: () = assert!(HEADER_MIGRATIONS.len() + 1 == HEADER_VERSION as usize);
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("Snapshot is truncated")]
+    Truncated,
+    #[error("Data is not a merkle tree snapshot")]
+    InvalidMagic,
+    #[error("Unsupported snapshot version {version}; this build reads versions 1 to {latest}")]
+    UnsupportedVersion { version: u16, latest: u16 },
+    #[error("Snapshot checksum does not match its contents")]
+    ChecksumMismatch,
+    #[error("Snapshot payload is invalid: {0}")]
+    InvalidPayload(String),
+}
+
+/// Frames a payload as: magic, version (u16 LE), payload length (u64 LE), the
+/// payload, then the keccak hash of everything before it.
+fn write_snapshot(magic: [u8; 4], version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_LEN + payload.len() + 32);
+    bytes.extend_from_slice(&magic);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    let checksum = keccak::hash(&bytes).to_bytes();
+    bytes.extend_from_slice(&checksum);
+    bytes
+}
+
+/// Splits a snapshot into its version and payload, checking the framing and checksum.
+fn read_snapshot(bytes: &[u8], magic: [u8; 4], latest: u16) -> Result<(u16, &[u8]), SnapshotError> {
+    if bytes.len() < magic.len() {
+        return Err(SnapshotError::Truncated);
+    }
+    if bytes[..4] != magic {
+        return Err(SnapshotError::InvalidMagic);
+    }
+    if bytes.len() < SNAPSHOT_HEADER_LEN {
+        return Err(SnapshotError::Truncated);
+    }
+    // Checked before the checksum: a newer build may also checksum differently.
+    let version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+    if version == 0 || version > latest {
+        return Err(SnapshotError::UnsupportedVersion { version, latest });
+    }
+    let payload_len = u64::from_le_bytes(bytes[6..14].try_into().unwrap());
+    let body_len = (SNAPSHOT_HEADER_LEN as u64)
+        .checked_add(payload_len)
+        .filter(|len| *len + 32 == bytes.len() as u64)
+        .ok_or(SnapshotError::Truncated)? as usize;
+
+    let (body, checksum) = bytes.split_at(body_len);
+    if keccak::hash(body).to_bytes() != checksum {
+        return Err(SnapshotError::ChecksumMismatch);
+    }
+    Ok((version, &body[SNAPSHOT_HEADER_LEN..]))
+}
+
+/// Runs the payload of a version `version` snapshot through `migrations` up to the latest version.
+fn migrate_snapshot(
+    version: u16,
+    payload: Vec<u8>,
+    migrations: &[SnapshotMigration],
+) -> Result<Vec<u8>, SnapshotError> {
+    let latest = migrations.len() as u16 + 1;
+    migrations
+        .get(version as usize - 1..)
+        .ok_or(SnapshotError::UnsupportedVersion { version, latest })?
+        .iter()
+        .try_fold(payload, |payload, migrate| migrate(payload))
+}
+
+/// Everything in a `MerkleTree` except its nodes and changelog buffer.
+#[derive(Serialize, Deserialize)]
+struct TreeHeader {
+    max_depth: u32,
+    max_buffer_size: u32,
+    sequence_number: u64,
+    active_index: u64,
+    buffer_size: u64,
+    rightmost_proof: Path,
+    spilled_subtrees: HashSet<u32>,
+    subtree_writes: HashMap<u32, u64>,
+    root_history: Option<VecDeque<(u64, Node)>>,
+    max_root_history: usize,
+}
+
+fn decode_header(header: &[u8]) -> Result<TreeHeader, SnapshotError> {
+    let (version, payload) = read_snapshot(header, HEADER_MAGIC, HEADER_VERSION)?;
+    let payload = migrate_snapshot(version, payload.to_vec(), HEADER_MIGRATIONS)?;
+    bincode::deserialize(&payload).map_err(|e| SnapshotError::InvalidPayload(e.to_string()))
+}
+
+// Levels narrower than this are hashed on the calling thread.
+const MIN_PARALLEL_NODES: usize = 256;
+
+/// The nodes of a leaf-level subtree that has been moved out of memory.
+/// Entries are (level, index, node); the subtree root itself stays resident.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Subtree {
+    pub index: u32,
+    pub nodes: Vec<(u32, u32, Node)>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct MerkleTree {
     pub max_depth: u32,
-    pub nodes: HashMap<Vec<u8>, Vec<u8>>,
-    pub leaves: Vec<Vec<u8>>,
+    pub max_buffer_size: u32,
+    pub sequence_number: u64,
+    pub active_index: u64,
+    pub buffer_size: u64,
+    pub change_logs: Vec<ChangeLog>,
+    pub rightmost_proof: Path,
+    // Sparse (level, index) -> node store; nodes equal to the empty subtree
+    // hash for their level are never stored.
+    nodes: HashMap<(u32, u32), Node>,
+    spilled_subtrees: HashSet<u32>,
+    // Resident subtrees and the sequence number of their last write.
+    subtree_writes: HashMap<u32, u64>,
+    empty_nodes: Vec<Node>,
+    // Recent (sequence number, root) pairs, oldest first; `None` while history is off.
+    root_history: Option<VecDeque<(u64, Node)>>,
+    max_root_history: usize,
+    // Changelogs recorded for history that have not been persisted yet.
+    #[serde(skip)]
+    unsaved_change_logs: Vec<(u64, ChangeLog)>,
+    // Nodes and changelog buffer slots written since the last `mark_saved`.
+    #[serde(skip)]
+    dirty_nodes: HashSet<(u32, u32)>,
+    #[serde(skip)]
+    dirty_change_logs: HashSet<u32>,
 }
 
 impl MerkleTree {
-    pub fn new(max_depth: u32) -> Self {
-        Self {
+    pub fn new(max_depth: u32, max_buffer_size: u32) -> Result<Self, &'static str> {
+        if !is_valid_depth_size_pair(max_depth, max_buffer_size) {
+            return Err("Unsupported max_depth/max_buffer_size pair");
+        }
+
+        let empty_nodes: Vec<Node> = (0..=max_depth).map(empty_node).collect();
+        let depth = max_depth as usize;
+
+        // Mirrors ConcurrentMerkleTree::initialize: the first changelog holds the
+        // empty root and the rightmost proof is made of empty subtrees.
+        let mut change_logs = vec![
+            ChangeLog {
+                root: EMPTY_NODE,
+                path: vec![EMPTY_NODE; depth],
+                index: 0,
+            };
+            max_buffer_size as usize
+        ];
+        change_logs[0] = ChangeLog {
+            root: empty_nodes[depth],
+            path: empty_nodes[..depth].to_vec(),
+            index: 0,
+        };
+
+        Ok(Self {
             max_depth,
+            max_buffer_size,
+            sequence_number: 0,
+            active_index: 0,
+            buffer_size: 1,
+            change_logs,
+            rightmost_proof: Path {
+                proof: empty_nodes[..depth].to_vec(),
+                leaf: EMPTY_NODE,
+                index: 0,
+            },
             nodes: HashMap::new(),
-            leaves: Vec::new(),
+            spilled_subtrees: HashSet::new(),
+            subtree_writes: HashMap::new(),
+            empty_nodes,
+            root_history: None,
+            max_root_history: 0,
+            unsaved_change_logs: Vec::new(),
+            dirty_nodes: HashSet::new(),
+            dirty_change_logs: HashSet::from([0]),
+        })
+    }
+
+    /// Starts recording history: the last `max_roots` (sequence number, root)
+    /// pairs stay in memory, and every changelog from now on is queued for
+    /// persistence so proofs can later be rebuilt with `ProofReplay`.
+    pub fn enable_history(&mut self, max_roots: usize) {
+        let mut roots = self.root_history.take().unwrap_or_default();
+        if roots.back().map(|(seq, _)| *seq) != Some(self.sequence_number) {
+            roots.push_back((self.sequence_number, self.get_root()));
+        }
+        while roots.len() > max_roots.max(1) {
+            roots.pop_front();
         }
+        self.root_history = Some(roots);
+        self.max_root_history = max_roots.max(1);
+    }
+
+    pub fn history_enabled(&self) -> bool {
+        self.root_history.is_some()
+    }
+
+    /// The root right after the change with sequence number `seq`, if it is still
+    /// in the root history or the changelog buffer.
+    pub fn root_at(&self, seq: u64) -> Option<Node> {
+        if seq <= self.sequence_number && self.sequence_number - seq < self.buffer_size {
+            let mask = self.max_buffer_size as u64 - 1;
+            let position = self.active_index.wrapping_sub(self.sequence_number - seq) & mask;
+            return Some(self.change_logs[position as usize].root);
+        }
+        let roots = self.root_history.as_ref()?;
+        let position = roots.binary_search_by_key(&seq, |(seq, _)| *seq).ok()?;
+        Some(roots[position].1)
+    }
+
+    /// Changelogs recorded for history since the last `mark_saved`, oldest first.
+    pub fn unsaved_change_logs(&self) -> &[(u64, ChangeLog)] {
+        &self.unsaved_change_logs
+    }
+
+    /// Rebuilds a tree from its on-chain account and the current value of every
+    /// leaf (empty nodes for burned ones), which the account itself does not hold.
+    /// The leaves must hash to the account's root.
+    pub fn from_tree_account(account: &TreeAccount, leaves: &[Node]) -> Result<Self, &'static str> {
+        let mut tree = Self::new(account.max_depth, account.max_buffer_size)?;
+        if leaves.len() != account.num_leaves() as usize {
+            return Err("Leaf count does not match the account");
+        }
+
+        if !leaves.is_empty() {
+            let levels = tree.hash_levels(0, leaves)?;
+            if levels[tree.max_depth as usize][0] != account.root() {
+                return Err("Leaves do not hash to the account root");
+            }
+            tree.store_levels(0, &levels);
+        } else if tree.empty_nodes[tree.max_depth as usize] != account.root() {
+            return Err("Leaves do not hash to the account root");
+        }
+
+        tree.sequence_number = account.sequence_number;
+        tree.active_index = account.active_index;
+        tree.buffer_size = account.buffer_size;
+        tree.change_logs = account.change_logs.clone();
+        tree.dirty_change_logs = (0..tree.max_buffer_size).collect();
+        tree.rightmost_proof = account.rightmost_proof.clone();
+        if let Some(height) = tree.subtree_height() {
+            for subtree in 0..tree.num_leaves().div_ceil(1 << height) {
+                tree.subtree_writes.insert(subtree, tree.sequence_number);
+            }
+        }
+
+        Ok(tree)
+    }
+
+    /// Checks that this tree is in the same state as the on-chain account.
+    pub fn check_against_account(&self, account: &TreeAccount) -> Result<(), &'static str> {
+        if (self.max_depth, self.max_buffer_size) != (account.max_depth, account.max_buffer_size) {
+            return Err("Tree dimensions differ from the account");
+        }
+        if self.sequence_number != account.sequence_number {
+            return Err("Sequence number differs from the account");
+        }
+        if self.num_leaves() != account.num_leaves() {
+            return Err("Leaf count differs from the account");
+        }
+        if self.get_change_log().root != account.root() {
+            return Err("Root differs from the account");
+        }
+        if self.rightmost_proof != account.rightmost_proof {
+            return Err("Rightmost proof differs from the account");
+        }
+
+        let canopy_depth = account.canopy_depth();
+        for level in self.max_depth - canopy_depth..self.max_depth {
+            for index in 0..1u32 << (self.max_depth - level) {
+                let expected = account
+                    .canopy_node(level, index)
+                    .unwrap_or(self.empty_nodes[level as usize]);
+                if self.node_at(level, index)? != expected {
+                    return Err("Canopy differs from the account");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the whole tree as a versioned, checksummed snapshot.
+    pub fn to_snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let payload = bincode::serialize(self)
+            .map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+        Ok(write_snapshot(SNAPSHOT_MAGIC, SNAPSHOT_VERSION, &payload))
+    }
+
+    /// Reads a snapshot written by `to_snapshot`, migrating older versions.
+    /// Every node counts as unsaved, since the snapshot says nothing about
+    /// what an incremental backend already holds.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let (version, payload) = read_snapshot(bytes, SNAPSHOT_MAGIC, SNAPSHOT_VERSION)?;
+        let payload = migrate_snapshot(version, payload.to_vec(), SNAPSHOT_MIGRATIONS)?;
+        let mut tree: Self = bincode::deserialize(&payload)
+            .map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+        tree.check_layout().map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+
+        tree.dirty_nodes = tree.nodes.keys().copied().collect();
+        tree.dirty_change_logs = (0..tree.max_buffer_size).collect();
+        Ok(tree)
+    }
+
+    /// Serializes everything except the nodes and the changelog buffer, which
+    /// incremental backends store per key (see `dirty_nodes` and `dirty_change_logs`).
+    pub fn header_snapshot(&self) -> Result<Vec<u8>, SnapshotError> {
+        let header = TreeHeader {
+            max_depth: self.max_depth,
+            max_buffer_size: self.max_buffer_size,
+            sequence_number: self.sequence_number,
+            active_index: self.active_index,
+            buffer_size: self.buffer_size,
+            rightmost_proof: self.rightmost_proof.clone(),
+            spilled_subtrees: self.spilled_subtrees.clone(),
+            subtree_writes: self.subtree_writes.clone(),
+            root_history: self.root_history.clone(),
+            max_root_history: self.max_root_history,
+        };
+        let payload = bincode::serialize(&header)
+            .map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+        Ok(write_snapshot(HEADER_MAGIC, HEADER_VERSION, &payload))
+    }
+
+    /// Reassembles a tree from a `header_snapshot`, the changelog buffer slots
+    /// and the stored nodes. Missing slots are zeroed, like a fresh account;
+    /// nodes inside spilled subtrees are left in storage.
+    pub fn from_parts(
+        header: &[u8],
+        change_logs: impl IntoIterator<Item = (u32, ChangeLog)>,
+        nodes: impl IntoIterator<Item = (u32, u32, Node)>,
+    ) -> Result<Self, SnapshotError> {
+        let header = decode_header(header)?;
+        let mut tree = Self::new(header.max_depth, header.max_buffer_size)
+            .map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+
+        let zeroed = ChangeLog {
+            root: EMPTY_NODE,
+            path: vec![EMPTY_NODE; header.max_depth as usize],
+            index: 0,
+        };
+        tree.change_logs = vec![zeroed; header.max_buffer_size as usize];
+        for (slot, change_log) in change_logs {
+            let entry = tree
+                .change_logs
+                .get_mut(slot as usize)
+                .ok_or_else(|| SnapshotError::InvalidPayload(format!("Changelog slot {} is out of range", slot)))?;
+            *entry = change_log;
+        }
+
+        tree.sequence_number = header.sequence_number;
+        tree.active_index = header.active_index;
+        tree.buffer_size = header.buffer_size;
+        tree.rightmost_proof = header.rightmost_proof;
+        tree.spilled_subtrees = header.spilled_subtrees;
+        tree.subtree_writes = header.subtree_writes;
+        tree.root_history = header.root_history;
+        tree.max_root_history = header.max_root_history;
+        tree.dirty_change_logs.clear();
+
+        let height = tree.subtree_height();
+        for (level, index, node) in nodes {
+            let spilled = height.is_some_and(|height| {
+                level < height && tree.spilled_subtrees.contains(&(index >> (height - level)))
+            });
+            if !spilled {
+                tree.nodes.insert((level, index), node);
+            }
+        }
+
+        tree.check_layout().map_err(|e| SnapshotError::InvalidPayload(e.to_string()))?;
+        Ok(tree)
+    }
+
+    /// The subtree appends go to, for a tree stored with `header`. A tree can
+    /// be rebuilt lazily from the nodes above `SUBTREE_HEIGHT` and those of
+    /// this subtree, then `spill_complete_subtrees`. `None` for trees too
+    /// shallow to spill subtrees, which need every node.
+    #[cfg(feature = "persistent-storage")]
+    pub fn append_subtree(header: &[u8]) -> Result<Option<u32>, SnapshotError> {
+        let header = decode_header(header)?;
+        Ok((header.max_depth > SUBTREE_HEIGHT).then_some(header.rightmost_proof.index >> SUBTREE_HEIGHT))
+    }
+
+    /// Marks every complete subtree as spilled, for a tree rebuilt by
+    /// `from_parts` without their nodes. Proofs that touch one fail until it
+    /// is paged in through `restore_subtree`.
+    #[cfg(feature = "persistent-storage")]
+    pub fn spill_complete_subtrees(&mut self) {
+        if let Some(height) = self.subtree_height() {
+            for subtree in 0..self.num_leaves() >> height {
+                self.spilled_subtrees.insert(subtree);
+                self.subtree_writes.remove(&subtree);
+            }
+        }
+    }
+
+    /// Nodes written since the last `mark_saved`, with their current value or
+    /// `None` if they are back to the empty subtree hash.
+    pub fn dirty_nodes(&self) -> impl Iterator<Item = (u32, u32, Option<Node>)> + '_ {
+        self.dirty_nodes
+            .iter()
+            .map(|(level, index)| (*level, *index, self.nodes.get(&(*level, *index)).copied()))
+    }
+
+    /// Changelog buffer slots written since the last `mark_saved`.
+    pub fn dirty_change_logs(&self) -> impl Iterator<Item = (u32, &ChangeLog)> + '_ {
+        self.dirty_change_logs
+            .iter()
+            .map(|slot| (*slot, &self.change_logs[*slot as usize]))
+    }
+
+    /// Whether anything changed since the last `mark_saved`.
+    pub fn has_unsaved_changes(&self) -> bool {
+        !self.dirty_nodes.is_empty() || !self.dirty_change_logs.is_empty() || !self.unsaved_change_logs.is_empty()
+    }
+
+    /// Records that storage now holds every change made so far, including the
+    /// changelogs queued for history.
+    pub fn mark_saved(&mut self) {
+        self.dirty_nodes.clear();
+        self.dirty_change_logs.clear();
+        self.unsaved_change_logs.clear();
+    }
+
+    /// Sanity checks for a deserialized tree, which bincode alone does not guarantee.
+    fn check_layout(&self) -> Result<(), &'static str> {
+        if !is_valid_depth_size_pair(self.max_depth, self.max_buffer_size) {
+            return Err("Unsupported max_depth/max_buffer_size pair");
+        }
+        let depth = self.max_depth as usize;
+        if self.change_logs.len() != self.max_buffer_size as usize
+            || self.change_logs.iter().any(|change_log| change_log.path.len() != depth)
+        {
+            return Err("Changelog buffer does not match the tree dimensions");
+        }
+        if self.active_index >= self.max_buffer_size as u64
+            || self.buffer_size > self.max_buffer_size as u64
+        {
+            return Err("Changelog counters exceed the buffer size");
+        }
+        if self.rightmost_proof.proof.len() != depth || self.num_leaves() as u64 > self.capacity() {
+            return Err("Rightmost proof does not match the tree dimensions");
+        }
+        if self.empty_nodes.len() != depth + 1 || self.empty_nodes[depth] != empty_node(self.max_depth) {
+            return Err("Empty node cache does not match the tree depth");
+        }
+        if self.nodes.keys().any(|(level, _)| *level >= self.max_depth) {
+            return Err("Node store holds nodes above the tree");
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> u64 {
+        1u64 << self.max_depth
+    }
+
+    pub fn num_leaves(&self) -> u32 {
+        self.rightmost_proof.index
     }
 
     pub fn insert(&mut self, leaf_data: &[u8]) -> Result<u32, &'static str> {
-        if self.leaves.len() >= (1 << self.max_depth) {
+        self.append(Node(keccak::hash(leaf_data).to_bytes()))
+    }
+
+    /// Appends an already-hashed leaf using the rightmost proof, exactly as
+    /// ConcurrentMerkleTree::append does on chain.
+    pub fn append(&mut self, leaf: Node) -> Result<u32, &'static str> {
+        if leaf == EMPTY_NODE {
+            return Err("Cannot append an empty node");
+        }
+        if self.rightmost_proof.index as u64 >= self.capacity() {
             return Err("Tree is full");
         }
 
-        let leaf_hash = keccak::hash(leaf_data).to_bytes().to_vec();
-        self.leaves.push(leaf_hash.clone());
-        self.nodes.insert(leaf_hash, leaf_data.to_vec());
+        let depth = self.max_depth as usize;
+        let index = self.rightmost_proof.index;
+        let mut path = vec![EMPTY_NODE; depth];
+        let mut node = leaf;
+
+        if index == 0 {
+            for (i, sibling) in self.rightmost_proof.proof.iter().enumerate() {
+                path[i] = node;
+                node = hash_to_parent(&node, sibling, true);
+            }
+        } else {
+            let intersection = index.trailing_zeros() as usize;
+            let mut intersection_node = self.rightmost_proof.leaf;
+
+            for i in 0..depth {
+                path[i] = node;
+                if i < intersection {
+                    let sibling = self.empty_nodes[i];
+                    intersection_node = hash_to_parent(
+                        &intersection_node,
+                        &self.rightmost_proof.proof[i],
+                        ((index - 1) >> i) & 1 == 0,
+                    );
+                    node = hash_to_parent(&node, &sibling, true);
+                    self.rightmost_proof.proof[i] = sibling;
+                } else if i == intersection {
+                    node = hash_to_parent(&node, &intersection_node, false);
+                    self.rightmost_proof.proof[i] = intersection_node;
+                } else {
+                    node = hash_to_parent(
+                        &node,
+                        &self.rightmost_proof.proof[i],
+                        ((index - 1) >> i) & 1 == 0,
+                    );
+                }
+            }
+        }
+
+        let change_log = ChangeLog { root: node, path, index };
+        self.cache_path(&change_log);
+        self.push_change_log(change_log);
+        self.rightmost_proof.index = index + 1;
+        self.rightmost_proof.leaf = leaf;
 
-        Ok((self.leaves.len() - 1) as u32)
+        Ok(index)
     }
 
-    pub fn get_proof(&self, index: u32) -> Result<Vec<Vec<u8>>, &'static str> {
-        if index as usize >= self.leaves.len() {
-            return Err("Index out of bounds");
+    fn push_change_log(&mut self, change_log: ChangeLog) {
+        let mask = self.max_buffer_size as u64 - 1;
+        self.active_index = (self.active_index + 1) & mask;
+        if self.buffer_size < self.max_buffer_size as u64 {
+            self.buffer_size += 1;
+        }
+        self.sequence_number = self.sequence_number.saturating_add(1);
+        if let Some(roots) = &mut self.root_history {
+            if roots.len() == self.max_root_history {
+                roots.pop_front();
+            }
+            roots.push_back((self.sequence_number, change_log.root));
+            self.unsaved_change_logs
+                .push((self.sequence_number, change_log.clone()));
+        }
+        self.dirty_change_logs.insert(self.active_index as u32);
+        self.change_logs[self.active_index as usize] = change_log;
+    }
+
+    /// Appends many leaves at once. The resulting state (nodes, changelogs,
+    /// rightmost proof, sequence number) is identical to calling `append` for
+    /// each leaf, but the new nodes are hashed level by level in parallel and
+    /// the tree is only updated once.
+    pub fn append_batch(&mut self, leaves: &[Node]) -> Result<BatchAppend, &'static str> {
+        if leaves.is_empty() {
+            return Err("Batch is empty");
+        }
+        if leaves.contains(&EMPTY_NODE) {
+            return Err("Cannot append an empty node");
+        }
+        let start = self.num_leaves();
+        if start as u64 + leaves.len() as u64 > self.capacity() {
+            return Err("Tree is full");
+        }
+        let end = start + leaves.len() as u32;
+
+        let levels = self.hash_levels(start, leaves)?;
+
+        // Every node left of a new leaf is final by the time it is appended, so
+        // each intermediate root only needs that leaf's left siblings.
+        let roots = (start..end)
+            .into_par_iter()
+            .with_min_len(MIN_PARALLEL_NODES)
+            .map(|index| Ok(self.batch_change_log(&levels, start, index)?.root))
+            .collect::<Result<Vec<_>, &'static str>>()?;
+        // History needs every changelog; otherwise only the ones that end up in the buffer.
+        let kept = if self.history_enabled() {
+            leaves.len() as u32
+        } else {
+            leaves.len().min(self.max_buffer_size as usize) as u32
+        };
+        let change_logs = (end - kept..end)
+            .map(|index| self.batch_change_log(&levels, start, index))
+            .collect::<Result<Vec<_>, &'static str>>()?;
+        let rightmost_proof = (0..self.max_depth)
+            .map(|level| {
+                let index = (end - 1) >> level;
+                if index & 1 == 1 {
+                    self.batch_node(&levels, start, level, index - 1)
+                } else {
+                    Ok(self.empty_nodes[level as usize])
+                }
+            })
+            .collect::<Result<Vec<_>, &'static str>>()?;
+
+        self.store_levels(start, &levels);
+
+        // Changelogs that would be overwritten within the batch are never written.
+        let skipped = (leaves.len() as u32 - kept) as u64;
+        let mask = self.max_buffer_size as u64 - 1;
+        self.active_index = (self.active_index + skipped) & mask;
+        self.buffer_size = (self.buffer_size + skipped).min(self.max_buffer_size as u64);
+        self.sequence_number = self.sequence_number.saturating_add(skipped);
+        for change_log in change_logs {
+            self.push_change_log(change_log);
+        }
+
+        if let Some(height) = self.subtree_height() {
+            for subtree in (start >> height)..=((end - 1) >> height) {
+                self.subtree_writes.insert(subtree, self.sequence_number);
+            }
         }
+        self.rightmost_proof = Path {
+            proof: rightmost_proof,
+            leaf: leaves[leaves.len() - 1],
+            index: end,
+        };
 
-        let mut proof = Vec::new();
-        let mut current_index = index;
-        let mut current_hash = self.leaves[index as usize].clone();
+        Ok(BatchAppend {
+            indices: start..end,
+            roots,
+        })
+    }
 
+    /// Hashes `leaves`, placed from index `start`, up to the root on top of the
+    /// existing nodes. `levels[l]` holds the nodes (l, start >> l ..= (end - 1) >> l).
+    fn hash_levels(&self, start: u32, leaves: &[Node]) -> Result<Vec<Vec<Node>>, &'static str> {
+        let end = start + leaves.len() as u32;
+        let mut levels: Vec<Vec<Node>> = Vec::with_capacity(self.max_depth as usize + 1);
+        levels.push(leaves.to_vec());
         for level in 0..self.max_depth {
-            let sibling_index = if current_index % 2 == 0 {
-                current_index + 1
-            } else {
-                current_index - 1
-            };
+            let parents = ((start >> (level + 1))..((end - 1) >> (level + 1)) + 1)
+                .into_par_iter()
+                .with_min_len(MIN_PARALLEL_NODES)
+                .map(|parent| {
+                    let left = self.batch_node(&levels, start, level, parent << 1)?;
+                    let right = self.batch_node(&levels, start, level, (parent << 1) | 1)?;
+                    Ok(hash_to_parent(&left, &right, true))
+                })
+                .collect::<Result<Vec<_>, &'static str>>()?;
+            levels.push(parents);
+        }
+        Ok(levels)
+    }
 
-            if sibling_index as usize < self.leaves.len() {
-                proof.push(self.leaves[sibling_index as usize].clone());
-            } else {
-                proof.push(vec![0; 32]); // Empty node
+    fn store_levels(&mut self, start: u32, levels: &[Vec<Node>]) {
+        for (level, nodes) in levels.iter().take(self.max_depth as usize).enumerate() {
+            let first = start >> level;
+            for (offset, node) in nodes.iter().enumerate() {
+                self.set_node(level as u32, first + offset as u32, *node);
             }
+        }
+    }
+
+    /// Looks up a node during a batch append, preferring the batch's new nodes.
+    fn batch_node(
+        &self,
+        levels: &[Vec<Node>],
+        start: u32,
+        level: u32,
+        index: u32,
+    ) -> Result<Node, &'static str> {
+        let offset = index.wrapping_sub(start >> level) as usize;
+        match levels.get(level as usize).and_then(|nodes| nodes.get(offset)) {
+            Some(node) => Ok(*node),
+            None => self.node_at(level, index),
+        }
+    }
 
-            current_index /= 2;
-            current_hash = if current_index % 2 == 0 {
-                keccak::hash(&[&current_hash[..], &proof[level as usize][..]].concat()).to_bytes().to_vec()
+    /// The changelog the append of leaf `index` produces within a batch.
+    fn batch_change_log(
+        &self,
+        levels: &[Vec<Node>],
+        start: u32,
+        index: u32,
+    ) -> Result<ChangeLog, &'static str> {
+        let mut path = Vec::with_capacity(self.max_depth as usize);
+        let mut node = levels[0][(index - start) as usize];
+        for level in 0..self.max_depth {
+            path.push(node);
+            let position = index >> level;
+            node = if position & 1 == 1 {
+                let sibling = self.batch_node(levels, start, level, position - 1)?;
+                hash_to_parent(&node, &sibling, false)
             } else {
-                keccak::hash(&[&proof[level as usize][..], &current_hash[..]].concat()).to_bytes().to_vec()
+                hash_to_parent(&node, &self.empty_nodes[level as usize], true)
             };
         }
+        Ok(ChangeLog { root: node, path, index })
+    }
+
+    /// Overwrites an existing leaf, rehashing only the nodes on its path to the root.
+    pub fn replace_leaf(&mut self, index: u32, new_leaf: Node) -> Result<Node, &'static str> {
+        if index >= self.num_leaves() {
+            return Err("Index out of bounds");
+        }
+
+        let proof = self.proof_nodes(index)?;
+        let mut path = Vec::with_capacity(proof.len());
+        let mut node = new_leaf;
+        for (level, sibling) in proof.iter().enumerate() {
+            path.push(node);
+            node = hash_to_parent(&node, sibling, (index >> level) & 1 == 0);
+        }
+
+        let change_log = ChangeLog { root: node, path, index };
+        self.cache_path(&change_log);
+        // The program stops maintaining the rightmost proof once the tree is full.
+        if (self.rightmost_proof.index as u64) < self.capacity() {
+            change_log.update_proof_or_leaf(
+                self.rightmost_proof.index - 1,
+                &mut self.rightmost_proof.proof,
+                &mut self.rightmost_proof.leaf,
+            );
+        }
+        self.push_change_log(change_log);
+
+        Ok(node)
+    }
+
+    /// Clears a leaf back to the empty node, as burn does on chain.
+    pub fn remove_leaf(&mut self, index: u32) -> Result<Node, &'static str> {
+        self.replace_leaf(index, EMPTY_NODE)
+    }
+
+    /// Brings a proof issued against an older root up to date by replaying
+    /// every changelog recorded since, like `fast_forward_proof` on chain.
+    /// Returns the updated proof and the current root.
+    pub fn fast_forward_proof(
+        &self,
+        root: &Node,
+        leaf: &Node,
+        proof: &[Node],
+        index: u32,
+    ) -> Result<(Vec<Node>, Node), FastForwardError> {
+        let mut nodes = proof.to_vec();
+        if nodes.len() != self.max_depth as usize {
+            return Err(FastForwardError::InvalidProofLength {
+                expected: self.max_depth as usize,
+                actual: nodes.len(),
+            });
+        }
+        if index >= self.num_leaves() {
+            return Err(FastForwardError::IndexOutOfBounds(index));
+        }
+
+        // Newest entries first, so a root that recurs resolves to its latest occurrence.
+        let mask = self.max_buffer_size as u64 - 1;
+        let age = (0..self.buffer_size)
+            .find(|age| {
+                self.change_logs[(self.active_index.wrapping_sub(*age) & mask) as usize].root == *root
+            })
+            .ok_or(FastForwardError::RootNotInBuffer(self.buffer_size))?;
+        if !Self::verify_proof(root, leaf, proof, index, self.max_depth) {
+            return Err(FastForwardError::InvalidProof);
+        }
+
+        let mut updated_leaf = *leaf;
+        let mut modified_at = None;
+        for age in (0..age).rev() {
+            let change_log = &self.change_logs[(self.active_index.wrapping_sub(age) & mask) as usize];
+            change_log.update_proof_or_leaf(index, &mut nodes, &mut updated_leaf);
+            if change_log.index == index {
+                modified_at = Some(self.sequence_number - age);
+            }
+        }
+        if updated_leaf != *leaf {
+            return Err(FastForwardError::LeafContentsModified {
+                index,
+                seq: modified_at.unwrap_or(self.sequence_number),
+            });
+        }
+
+        Ok((nodes, self.get_root()))
+    }
+
+    pub fn get_change_log(&self) -> &ChangeLog {
+        &self.change_logs[self.active_index as usize]
+    }
+
+    fn subtree_height(&self) -> Option<u32> {
+        (self.max_depth > SUBTREE_HEIGHT).then_some(SUBTREE_HEIGHT)
+    }
+
+    /// Returns the spilled subtree that holds leaf `index`, if any.
+    pub fn spilled_subtree_for(&self, index: u32) -> Option<u32> {
+        let height = self.subtree_height()?;
+        let subtree = index >> height;
+        self.spilled_subtrees.contains(&subtree).then_some(subtree)
+    }
+
+    /// Returns the node at (level, index), or the empty subtree hash if nothing
+    /// has been written there yet.
+    fn node_at(&self, level: u32, index: u32) -> Result<Node, &'static str> {
+        if let Some(height) = self.subtree_height() {
+            if level < height && self.spilled_subtrees.contains(&(index >> (height - level))) {
+                return Err("Node belongs to a spilled subtree");
+            }
+        }
+        Ok(self
+            .nodes
+            .get(&(level, index))
+            .copied()
+            .unwrap_or(self.empty_nodes[level as usize]))
+    }
+
+    fn set_node(&mut self, level: u32, index: u32, node: Node) {
+        self.dirty_nodes.insert((level, index));
+        if node == self.empty_nodes[level as usize] {
+            self.nodes.remove(&(level, index));
+        } else {
+            self.nodes.insert((level, index), node);
+        }
+    }
+
+    fn cache_path(&mut self, change_log: &ChangeLog) {
+        for (level, node) in change_log.path.iter().enumerate() {
+            self.set_node(level as u32, change_log.index >> level, *node);
+        }
+        if let Some(height) = self.subtree_height() {
+            self.subtree_writes
+                .insert(change_log.index >> height, self.sequence_number + 1);
+        }
+    }
+
+    pub fn get_leaf(&self, index: u32) -> Result<Node, &'static str> {
+        if index >= self.num_leaves() {
+            return Err("Index out of bounds");
+        }
+        self.node_at(0, index)
+    }
+
+    pub fn get_proof(&self, index: u32) -> Result<Vec<Node>, &'static str> {
+        if index >= self.num_leaves() {
+            return Err("Index out of bounds");
+        }
+
+        self.proof_nodes(index)
+    }
+
+    fn proof_nodes(&self, index: u32) -> Result<Vec<Node>, &'static str> {
+        (0..self.max_depth)
+            .map(|level| self.node_at(level, (index >> level) ^ 1))
+            .collect()
+    }
+
+    /// Complete subtrees that are resident and have not been written to in the
+    /// last `min_idle_ops` operations.
+    pub fn cold_subtrees(&self, min_idle_ops: u64) -> Vec<u32> {
+        let height = match self.subtree_height() {
+            Some(height) => height,
+            None => return Vec::new(),
+        };
+        let complete = self.num_leaves() >> height;
+
+        let mut cold: Vec<u32> = self
+            .subtree_writes
+            .iter()
+            .filter(|(subtree, last_write)| {
+                **subtree < complete && self.sequence_number - **last_write >= min_idle_ops
+            })
+            .map(|(subtree, _)| *subtree)
+            .collect();
+        cold.sort_unstable();
+        cold
+    }
+
+    /// Removes every node below the root of `subtree` from memory. Proofs that
+    /// touch it fail until it is handed back through `restore_subtree`. The
+    /// subtree must not hold unsaved nodes.
+    pub fn take_subtree(&mut self, subtree: u32) -> Result<Subtree, &'static str> {
+        let height = self.subtree_height().ok_or("Tree is too shallow to spill subtrees")?;
+        if self.spilled_subtrees.contains(&subtree) {
+            return Err("Subtree is already spilled");
+        }
+        if self
+            .dirty_nodes
+            .iter()
+            .any(|(level, index)| *level < height && index >> (height - level) == subtree)
+        {
+            return Err("Subtree has unsaved changes");
+        }
+
+        let mut nodes = Vec::new();
+        for level in 0..height {
+            let width = 1u32 << (height - level);
+            for index in subtree * width..(subtree + 1) * width {
+                if let Some(node) = self.nodes.remove(&(level, index)) {
+                    nodes.push((level, index, node));
+                }
+            }
+        }
+
+        self.spilled_subtrees.insert(subtree);
+        self.subtree_writes.remove(&subtree);
+
+        Ok(Subtree { index: subtree, nodes })
+    }
+
+    pub fn restore_subtree(&mut self, subtree: Subtree) -> Result<(), &'static str> {
+        if !self.spilled_subtrees.remove(&subtree.index) {
+            return Err("Subtree is not spilled");
+        }
+
+        for (level, index, node) in subtree.nodes {
+            self.nodes.insert((level, index), node);
+        }
+        self.subtree_writes.insert(subtree.index, self.sequence_number);
+
+        Ok(())
+    }
+
+    pub fn resident_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Rough heap footprint of the node store and changelog buffer.
+    pub fn approx_memory_bytes(&self) -> usize {
+        // Key, value and roughly one word of hash table overhead per node.
+        let node_bytes = self.nodes.len() * (std::mem::size_of::<(u32, u32)>() + 32 + 8);
+        let change_log_bytes =
+            self.change_logs.len() * (self.max_depth as usize * 32 + std::mem::size_of::<ChangeLog>());
+        node_bytes + change_log_bytes + self.subtree_writes.len() * 16
+    }
+
+    /// Builds a single proof covering every leaf in `indices` (in any order,
+    /// duplicates ignored).
+    pub fn get_multi_proof(&self, indices: &[u32]) -> Result<MultiProof, &'static str> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() {
+            return Err("No leaf indices given");
+        }
+        if indices[indices.len() - 1] >= self.num_leaves() {
+            return Err("Index out of bounds");
+        }
+
+        let nodes = multi_proof_siblings(self.max_depth, &indices)
+            .into_iter()
+            .map(|(level, index)| self.node_at(level, index))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(MultiProof {
+            max_depth: self.max_depth,
+            indices,
+            nodes,
+        })
+    }
+
+    /// Checks a multi-proof; `leaves` must follow the order of `proof.indices`.
+    pub fn verify_multi_proof(root: &Node, leaves: &[Node], proof: &MultiProof) -> bool {
+        if leaves.len() != proof.indices.len()
+            || !is_valid_multi_proof_shape(proof.max_depth, &proof.indices)
+        {
+            return false;
+        }
+
+        let mut known: Vec<(u32, Node)> = proof.indices.iter().copied().zip(leaves.iter().copied()).collect();
+
+        let mut nodes = proof.nodes.iter();
+        for _ in 0..proof.max_depth {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let (index, node) = known[i];
+                let parent = match known.get(i + 1) {
+                    Some((right, right_node)) if index & 1 == 0 && *right == index | 1 => {
+                        i += 2;
+                        hash_to_parent(&node, right_node, true)
+                    }
+                    _ => {
+                        let sibling = match nodes.next() {
+                            Some(sibling) => sibling,
+                            None => return false,
+                        };
+                        i += 1;
+                        hash_to_parent(&node, sibling, index & 1 == 0)
+                    }
+                };
+                parents.push((index >> 1, parent));
+            }
+            known = parents;
+        }
+
+        nodes.next().is_none() && known[0].1 == *root
+    }
+
+    /// Returns the proof for `index` together with the root it verifies against.
+    pub fn get_proof_with_root(&self, index: u32) -> Result<(Vec<Node>, Node), &'static str> {
+        Ok((self.get_proof(index)?, self.get_root()))
+    }
+
+    /// Returns the proof for `index` without the top `canopy_depth` levels, which
+    /// the on-chain program already holds in the tree account's canopy.
+    pub fn get_proof_for_canopy(
+        &self,
+        index: u32,
+        canopy_depth: u32,
+    ) -> Result<Vec<Node>, &'static str> {
+        if canopy_depth > self.max_depth {
+            return Err("Canopy depth exceeds tree depth");
+        }
+
+        let mut proof = self.get_proof(index)?;
+        proof.truncate((self.max_depth - canopy_depth) as usize);
 
         Ok(proof)
     }
 
+    /// Checks a full-length proof for a tree of depth `max_depth`; truncated
+    /// (canopy) proofs and out-of-range indices are rejected.
     pub fn verify_proof(
-        root: &[u8],
-        leaf_hash: &[u8],
-        proof: &[Vec<u8>],
+        root: &Node,
+        leaf_hash: &Node,
+        proof: &[Node],
         index: u32,
+        max_depth: u32,
     ) -> bool {
-        let mut current_hash = leaf_hash.to_vec();
-        let mut current_index = index;
+        if proof.len() != max_depth as usize || index as u64 >= 1u64 << max_depth {
+            return false;
+        }
 
-        for sibling in proof {
-            current_hash = if current_index % 2 == 0 {
-                keccak::hash(&[&current_hash[..], &sibling[..]].concat()).to_bytes().to_vec()
-            } else {
-                keccak::hash(&[&sibling[..], &current_hash[..]].concat()).to_bytes().to_vec()
-            };
-            current_index /= 2;
+        let mut current_hash = *leaf_hash;
+        for (level, sibling) in proof.iter().enumerate() {
+            current_hash = hash_to_parent(&current_hash, sibling, (index >> level) & 1 == 0);
         }
 
-        current_hash == root
+        current_hash == *root
     }
 
-    pub fn get_root(&self) -> Vec<u8> {
-        if self.leaves.is_empty() {
-            return vec![0; 32];
+    pub fn get_root(&self) -> Node {
+        self.get_change_log().root
+    }
+
+    /// Rebuilds the root from every stored leaf, padding with empty subtrees.
+    pub fn compute_root(&self) -> Result<Node, &'static str> {
+        if !self.spilled_subtrees.is_empty() {
+            return Err("Cannot recompute the root while subtrees are spilled");
         }
 
-        let mut current_level = self.leaves.clone();
-        while current_level.len() > 1 {
-            let mut next_level = Vec::new();
-            for chunk in current_level.chunks(2) {
-                if chunk.len() == 2 {
-                    let combined = keccak::hash(&[&chunk[0][..], &chunk[1][..]].concat()).to_bytes().to_vec();
-                    next_level.push(combined);
-                } else {
-                    next_level.push(chunk[0].clone());
+        let mut current_level: HashMap<u32, Node> = self
+            .nodes
+            .iter()
+            .filter(|((level, _), _)| *level == 0)
+            .map(|((_, index), node)| (*index, *node))
+            .collect();
+
+        for level in 0..self.max_depth as usize {
+            let empty = self.empty_nodes[level];
+            let mut next_level = HashMap::with_capacity(current_level.len() / 2 + 1);
+            for index in current_level.keys() {
+                let parent = index >> 1;
+                if next_level.contains_key(&parent) {
+                    continue;
                 }
+                let left = current_level.get(&(parent << 1)).unwrap_or(&empty);
+                let right = current_level.get(&((parent << 1) | 1)).unwrap_or(&empty);
+                next_level.insert(parent, hash_to_parent(left, right, true));
             }
             current_level = next_level;
         }
 
-        current_level[0].clone()
+        Ok(current_level
+            .get(&0)
+            .copied()
+            .unwrap_or(self.empty_nodes[self.max_depth as usize]))
+    }
+}
+
+/// Turns proof nodes into the read-only remaining accounts expected by
+/// Bubblegum leaf instructions (transfer, delegate, redeem, burn, ...).
+pub fn proof_to_remaining_accounts(proof: &[Node]) -> Vec<AccountMeta> {
+    proof
+        .iter()
+        .map(|node| AccountMeta::new_readonly(Pubkey::new_from_array(node.0), false))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(i: u32) -> Node {
+        Node(keccak::hash(&i.to_le_bytes()).to_bytes())
+    }
+
+    // Generated with spl-concurrent-merkle-tree 0.2.0: `initialize()` followed by
+    // `append(keccak(i.to_le_bytes()))` for i in 0..leaves.
+    // (max_depth, max_buffer_size, leaves, empty root, root after appends)
+    const GOLDEN_ROOTS: &[(u32, u32, u32, &str, &str)] = &[
+        (3, 8, 8, "3HCYqQRcQSChEuAw1ybNYHibrTNNjzbYzm56cmEmivB6", "7WvZChfVXt9Q6feEqDEvKAaecEwJpkWP1t1LAhJmdx5Z"),
+        (5, 8, 32, "zLUDhASAn7WA1Aqc724azRpZjKCjMQNATApe74JMg8C", "4uob26pFMcvKz8Y4CCFHWCaYZrMdK6jcYmEuDdnRXAjp"),
+        (6, 16, 37, "ABnEXHmveD6iuMwfw2po7t6TPjn5kYMVwYJMi3fa9K91", "gYMed8WJnLGVJxfggUgvQ347yukkEZHDUoNovP3ocPU"),
+        (10, 32, 37, "HpMJWAzQv9HFgHBqY1o8V1B27sCYPFHJdGivDA658jEL", "E8YhaYqJrxJWBK7rphXVhmJLKU9h3bkjZLx5X41bPaLy"),
+        (13, 32, 37, "E3oMtCuPEauftdZLX8EZ8YX7BbFzpBCVRYEiLxwPJLY2", "AsR1Jmn3J4MFXuHJRTXhaMrxcQMXixUqvjPGG18zPu1o"),
+        (14, 64, 37, "7DiCkBhs5HQLPEsKY6EjfNd3oBswnfRk9UAZcHqczL7m", "66scYfaymfw62JLuYe1YC4j1A9dVhLsu34b4SetZBZSA"),
+        (14, 256, 37, "7DiCkBhs5HQLPEsKY6EjfNd3oBswnfRk9UAZcHqczL7m", "66scYfaymfw62JLuYe1YC4j1A9dVhLsu34b4SetZBZSA"),
+        (14, 1024, 37, "7DiCkBhs5HQLPEsKY6EjfNd3oBswnfRk9UAZcHqczL7m", "66scYfaymfw62JLuYe1YC4j1A9dVhLsu34b4SetZBZSA"),
+        (14, 2048, 37, "7DiCkBhs5HQLPEsKY6EjfNd3oBswnfRk9UAZcHqczL7m", "66scYfaymfw62JLuYe1YC4j1A9dVhLsu34b4SetZBZSA"),
+        (15, 64, 37, "FhsNgK6GGU1cRPFbmPhrEZ95Zj8vorjK6GmhFuwmZsUm", "5VFUojt2qBZRk4GLvhmLxBuT7XKtZVEGax2ashGRygNo"),
+        (16, 64, 37, "3e2oBSLfSDVdUdS7jRGFKa8nreJUA9sFPEELrHaQyd4J", "8rup57bEccvCq21WUQJ97P459rL2pG5cAZqKDQB6ha9F"),
+        (17, 64, 37, "GCXyEHiFMtRFTNFT5LNHwxiXZfooBpUMGSkjyz7pfcS5", "tx4v2Hq7xTFRmQKQWtxfU1dMjEhQRYm2gM9mkgVmwPq"),
+        (18, 64, 37, "752CmMF5k7acEFEmJA7oE3aobbWj7CAZVm3KpDR6HiRV", "4LYCSn7ADQgsuC3nFN6wPMSk9muHjstBPTsvhausV8X3"),
+        (19, 64, 37, "D9GGr1ycBmgRbHJyJzmxMk5aoKZmjdezB4NpxopAcgpP", "jLft7cTYTSUoQuZbNiLVhnGrj8FyeT7YbKdM9mdPphE"),
+        (20, 64, 37, "EMMNtVYXaLtfbR67mR2H7E4Kqq4AaV2vv39KnHNWFX1B", "99RMDLsogvqFxAhs4bqhcFTMryXy6TARGQTqqU26NUjp"),
+        (20, 256, 37, "EMMNtVYXaLtfbR67mR2H7E4Kqq4AaV2vv39KnHNWFX1B", "99RMDLsogvqFxAhs4bqhcFTMryXy6TARGQTqqU26NUjp"),
+        (20, 1024, 37, "EMMNtVYXaLtfbR67mR2H7E4Kqq4AaV2vv39KnHNWFX1B", "99RMDLsogvqFxAhs4bqhcFTMryXy6TARGQTqqU26NUjp"),
+        (20, 2048, 37, "EMMNtVYXaLtfbR67mR2H7E4Kqq4AaV2vv39KnHNWFX1B", "99RMDLsogvqFxAhs4bqhcFTMryXy6TARGQTqqU26NUjp"),
+        (24, 64, 37, "ErGhrvyivvVQtKoBqNCjwvQKJvtB754MarqwKk89BrNS", "6vuCkNagDr9Ee3fh9NdXcghhpydGy3mb61jiwF1n6GkE"),
+        (24, 256, 37, "ErGhrvyivvVQtKoBqNCjwvQKJvtB754MarqwKk89BrNS", "6vuCkNagDr9Ee3fh9NdXcghhpydGy3mb61jiwF1n6GkE"),
+        (24, 512, 37, "ErGhrvyivvVQtKoBqNCjwvQKJvtB754MarqwKk89BrNS", "6vuCkNagDr9Ee3fh9NdXcghhpydGy3mb61jiwF1n6GkE"),
+        (24, 1024, 37, "ErGhrvyivvVQtKoBqNCjwvQKJvtB754MarqwKk89BrNS", "6vuCkNagDr9Ee3fh9NdXcghhpydGy3mb61jiwF1n6GkE"),
+        (24, 2048, 37, "ErGhrvyivvVQtKoBqNCjwvQKJvtB754MarqwKk89BrNS", "6vuCkNagDr9Ee3fh9NdXcghhpydGy3mb61jiwF1n6GkE"),
+        (26, 512, 37, "DSPims9N2UfuyDSGtfMUZ8xs1vQ9PykBb41y3LeW5PG3", "6CuQLdCYrnYkCgcjmEN4vBvkVZiPsfwjLWdMEdcfGV3g"),
+        (26, 1024, 37, "DSPims9N2UfuyDSGtfMUZ8xs1vQ9PykBb41y3LeW5PG3", "6CuQLdCYrnYkCgcjmEN4vBvkVZiPsfwjLWdMEdcfGV3g"),
+        (26, 2048, 37, "DSPims9N2UfuyDSGtfMUZ8xs1vQ9PykBb41y3LeW5PG3", "6CuQLdCYrnYkCgcjmEN4vBvkVZiPsfwjLWdMEdcfGV3g"),
+        (30, 512, 37, "AuNKHQVRLUSvCT7FPXENbAfN9ruCH5ZNLWoCAYi4E4pC", "4wUaMxAya5QnEUtWA4WvGzXUGM814WhvFB43meqsH8wx"),
+        (30, 1024, 37, "AuNKHQVRLUSvCT7FPXENbAfN9ruCH5ZNLWoCAYi4E4pC", "4wUaMxAya5QnEUtWA4WvGzXUGM814WhvFB43meqsH8wx"),
+        (30, 2048, 37, "AuNKHQVRLUSvCT7FPXENbAfN9ruCH5ZNLWoCAYi4E4pC", "4wUaMxAya5QnEUtWA4WvGzXUGM814WhvFB43meqsH8wx"),
+    ];
+
+    #[test]
+    fn test_golden_roots() {
+        for &(max_depth, max_buffer_size, leaves, empty_root, root) in GOLDEN_ROOTS {
+            let mut tree = MerkleTree::new(max_depth, max_buffer_size).unwrap();
+            assert_eq!(bs58::encode(tree.get_root()).into_string(), empty_root);
+
+            for i in 0..leaves {
+                assert_eq!(tree.append(leaf(i)).unwrap(), i);
+            }
+
+            assert_eq!(bs58::encode(tree.get_root()).into_string(), root);
+            assert_eq!(tree.compute_root().unwrap(), tree.get_root());
+            assert_eq!(tree.sequence_number, leaves as u64);
+            assert_eq!(tree.active_index, leaves as u64 % max_buffer_size as u64);
+            assert_eq!(tree.buffer_size, (leaves as u64 + 1).min(max_buffer_size as u64));
+        }
+    }
+
+    #[test]
+    fn test_proofs_verify_at_every_level() {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        for i in 0..37 {
+            tree.append(leaf(i)).unwrap();
+        }
+
+        for i in 0..37 {
+            let (proof, root) = tree.get_proof_with_root(i).unwrap();
+            assert_eq!(proof.len(), 14);
+            assert!(MerkleTree::verify_proof(&root, &leaf(i), &proof, i, 14));
+            assert!(!MerkleTree::verify_proof(&root, &leaf(i + 1), &proof, i, 14));
+        }
+
+        // Truncated, padded and out-of-range proofs are rejected outright.
+        let (proof, root) = tree.get_proof_with_root(0).unwrap();
+        assert!(!MerkleTree::verify_proof(&root, &leaf(0), &proof[..13], 0, 14));
+        assert!(!MerkleTree::verify_proof(&root, &leaf(0), &[proof.clone(), vec![EMPTY_NODE]].concat(), 0, 14));
+        assert!(!MerkleTree::verify_proof(&root, &leaf(0), &proof, 1 << 14, 14));
+
+        // Upper levels of the proof must be real interior nodes, not sibling leaves.
+        let proof = tree.get_proof(0).unwrap();
+        let left_pair = Node(keccak::hashv(&[&leaf(2).0, &leaf(3).0]).to_bytes());
+        assert_eq!(proof[1], left_pair);
+        assert_eq!(proof[13], empty_node(13));
+        assert!(tree.get_proof(37).is_err());
+    }
+
+    #[test]
+    fn test_canopy_proof_and_remaining_accounts() {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        for i in 0..5 {
+            tree.append(leaf(i)).unwrap();
+        }
+
+        let full = tree.get_proof(3).unwrap();
+        let truncated = tree.get_proof_for_canopy(3, 5).unwrap();
+        assert_eq!(truncated.len(), 9);
+        assert_eq!(truncated[..], full[..9]);
+        assert!(tree.get_proof_for_canopy(3, 14).unwrap().is_empty());
+        assert!(tree.get_proof_for_canopy(3, 15).is_err());
+
+        let accounts = proof_to_remaining_accounts(&truncated);
+        assert_eq!(accounts.len(), 9);
+        assert!(accounts.iter().all(|meta| !meta.is_signer && !meta.is_writable));
+        assert_eq!(Node(accounts[0].pubkey.to_bytes()), full[0]);
+    }
+
+    #[test]
+    fn test_replace_and_remove_leaf() {
+        let mut tree = MerkleTree::new(5, 8).unwrap();
+        for i in 0..11 {
+            tree.append(leaf(i)).unwrap();
+        }
+
+        let root = tree.replace_leaf(4, leaf(100)).unwrap();
+        assert_eq!(root, tree.compute_root().unwrap());
+        assert_eq!(tree.get_change_log().index, 4);
+        assert_eq!(tree.get_change_log().get_leaf(), leaf(100));
+
+        let root = tree.replace_leaf(10, leaf(101)).unwrap();
+        assert_eq!(root, tree.compute_root().unwrap());
+        assert_eq!(tree.rightmost_proof.leaf, leaf(101));
+
+        let root = tree.remove_leaf(7).unwrap();
+        assert_eq!(root, tree.compute_root().unwrap());
+        assert_eq!(tree.get_leaf(7).unwrap(), EMPTY_NODE);
+        assert_eq!(tree.sequence_number, 14);
+
+        // Appends after in-place updates must still extend the updated tree.
+        tree.append(leaf(11)).unwrap();
+        assert_eq!(tree.get_root(), tree.compute_root().unwrap());
+        for i in [0, 4, 10, 11] {
+            let (proof, root) = tree.get_proof_with_root(i).unwrap();
+            let leaf_hash = tree.get_leaf(i).unwrap();
+            assert!(MerkleTree::verify_proof(&root, &leaf_hash, &proof, i, 5));
+        }
+
+        assert!(tree.replace_leaf(12, leaf(0)).is_err());
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_append_batch_matches_sequential_appends() {
+        // (max_depth, max_buffer_size, leaves before the batch, batch size)
+        for &(max_depth, max_buffer_size, before, batch) in
+            &[(3, 8, 0, 8), (5, 8, 3, 20), (5, 8, 7, 2), (14, 64, 37, 1000), (14, 64, 1024, 1)]
+        {
+            let mut sequential = MerkleTree::new(max_depth, max_buffer_size).unwrap();
+            let mut batched = MerkleTree::new(max_depth, max_buffer_size).unwrap();
+            for i in 0..before {
+                sequential.append(leaf(i)).unwrap();
+                batched.append(leaf(i)).unwrap();
+            }
+
+            let leaves: Vec<Node> = (before..before + batch).map(leaf).collect();
+            let mut roots = Vec::new();
+            for l in &leaves {
+                sequential.append(*l).unwrap();
+                roots.push(sequential.get_change_log().root);
+            }
+            let result = batched.append_batch(&leaves).unwrap();
+
+            assert_eq!(result.indices, before..before + batch);
+            assert_eq!(result.roots, roots);
+            assert_eq!(batched.get_root(), sequential.get_root());
+            assert_eq!(batched.change_logs, sequential.change_logs);
+            assert_eq!(batched.rightmost_proof, sequential.rightmost_proof);
+            assert_eq!(batched.sequence_number, sequential.sequence_number);
+            assert_eq!(batched.active_index, sequential.active_index);
+            assert_eq!(batched.buffer_size, sequential.buffer_size);
+            assert_eq!(batched.compute_root().unwrap(), batched.get_root());
+            assert_eq!(batched.get_proof(before).unwrap(), sequential.get_proof(before).unwrap());
+
+            // Both trees must keep evolving identically.
+            assert_eq!(batched.append(leaf(5000)), sequential.append(leaf(5000)));
+            assert_eq!(batched.get_root(), sequential.get_root());
+        }
+
+        let mut tree = MerkleTree::new(3, 8).unwrap();
+        assert_eq!(tree.append_batch(&[]), Err("Batch is empty"));
+        assert_eq!(tree.append_batch(&[leaf(0), EMPTY_NODE]), Err("Cannot append an empty node"));
+        assert_eq!(tree.append_batch(&[leaf(0); 9]), Err("Tree is full"));
+        assert_eq!(tree.num_leaves(), 0);
+    }
+
+    #[test]
+    fn test_fast_forward_proof() {
+        let mut tree = MerkleTree::new(5, 8).unwrap();
+        for i in 0..6 {
+            tree.append(leaf(i)).unwrap();
+        }
+        let (stale, old_root) = tree.get_proof_with_root(2).unwrap();
+
+        // Sibling, cousin and far-away changes all touch the stale proof.
+        tree.replace_leaf(3, leaf(100)).unwrap();
+        tree.replace_leaf(0, leaf(101)).unwrap();
+        tree.append(leaf(6)).unwrap();
+        tree.append(leaf(7)).unwrap();
+        assert!(!MerkleTree::verify_proof(&tree.get_root(), &leaf(2), &stale, 2, 5));
+
+        let (proof, root) = tree.fast_forward_proof(&old_root, &leaf(2), &stale, 2).unwrap();
+        assert_eq!(root, tree.get_root());
+        assert_eq!(proof, tree.get_proof(2).unwrap());
+        assert!(MerkleTree::verify_proof(&root, &leaf(2), &proof, 2, 5));
+
+        // Already-current proofs pass through unchanged.
+        assert_eq!(tree.fast_forward_proof(&root, &leaf(2), &proof, 2).unwrap().0, proof);
+
+        assert_eq!(
+            tree.fast_forward_proof(&old_root, &leaf(3), &stale, 2),
+            Err(FastForwardError::InvalidProof)
+        );
+        assert_eq!(
+            tree.fast_forward_proof(&old_root, &leaf(2), &stale[..4], 2),
+            Err(FastForwardError::InvalidProofLength { expected: 5, actual: 4 })
+        );
+        assert_eq!(
+            tree.fast_forward_proof(&old_root, &leaf(2), &stale, 20),
+            Err(FastForwardError::IndexOutOfBounds(20))
+        );
+
+        tree.replace_leaf(2, leaf(102)).unwrap();
+        assert_eq!(
+            tree.fast_forward_proof(&old_root, &leaf(2), &stale, 2),
+            Err(FastForwardError::LeafContentsModified { index: 2, seq: 11 })
+        );
+
+        // Once the old root falls out of the buffer the proof is unrecoverable.
+        for i in 8..16 {
+            tree.append(leaf(i)).unwrap();
+        }
+        assert_eq!(
+            tree.fast_forward_proof(&old_root, &leaf(2), &stale, 2),
+            Err(FastForwardError::RootNotInBuffer(8))
+        );
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        for i in 0..37 {
+            tree.append(leaf(i)).unwrap();
+        }
+        let root = tree.get_root();
+
+        let indices = [36, 0, 3, 2, 17, 3];
+        let proof = tree.get_multi_proof(&indices).unwrap();
+        assert_eq!(proof.indices, vec![0, 2, 3, 17, 36]);
+        let leaves: Vec<Node> = proof.indices.iter().map(|i| leaf(*i)).collect();
+        assert!(MerkleTree::verify_multi_proof(&root, &leaves, &proof));
+
+        // Shared interior nodes are only sent once.
+        assert!(proof.nodes.len() < 5 * 14);
+        let bytes = proof.to_bytes();
+        assert_eq!(MultiProof::from_bytes(&bytes).unwrap(), proof);
+        // Empty subtrees above the 37 leaves are elided from the encoding.
+        assert!(bytes.len() < proof.nodes.len() * 32);
+
+        let mut wrong_leaves = leaves.clone();
+        wrong_leaves[1] = leaf(1);
+        assert!(!MerkleTree::verify_multi_proof(&root, &wrong_leaves, &proof));
+        assert!(!MerkleTree::verify_multi_proof(&root, &leaves[..4], &proof));
+        assert!(!MerkleTree::verify_multi_proof(&leaf(0), &leaves, &proof));
+        let mut short = proof.clone();
+        short.nodes.pop();
+        assert!(!MerkleTree::verify_multi_proof(&root, &leaves, &short));
+
+        // A single-leaf multi-proof is just the regular proof.
+        let single = tree.get_multi_proof(&[5]).unwrap();
+        assert_eq!(single.nodes, tree.get_proof(5).unwrap());
+
+        assert!(MultiProof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MultiProof::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(tree.get_multi_proof(&[]).is_err());
+        assert!(tree.get_multi_proof(&[1, 37]).is_err());
+    }
+
+    #[test]
+    fn test_spill_and_restore_subtrees() {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        let leaves = 3 * (1 << SUBTREE_HEIGHT) + 5;
+        for i in 0..leaves {
+            tree.append(leaf(i)).unwrap();
+        }
+        let root = tree.get_root();
+        let proof = tree.get_proof(17).unwrap();
+        let resident = tree.resident_nodes();
+
+        // Only complete subtrees are cold; the one being appended to is not.
+        assert_eq!(tree.cold_subtrees(0), vec![0, 1, 2]);
+        assert_eq!(tree.cold_subtrees(1 << SUBTREE_HEIGHT), vec![0, 1]);
+
+        assert_eq!(tree.take_subtree(0), Err("Subtree has unsaved changes"));
+        tree.mark_saved();
+        let subtree = tree.take_subtree(0).unwrap();
+        assert!(tree.resident_nodes() < resident);
+        assert_eq!(tree.spilled_subtree_for(17), Some(0));
+        assert!(tree.get_proof(17).is_err());
+        assert!(tree.replace_leaf(17, leaf(0)).is_err());
+        assert!(tree.compute_root().is_err());
+
+        // Leaves outside the spilled subtree stay provable and appendable.
+        let (other_proof, _) = tree.get_proof_with_root(2000).unwrap();
+        assert!(MerkleTree::verify_proof(&root, &leaf(2000), &other_proof, 2000, 14));
+        tree.append(leaf(leaves)).unwrap();
+
+        tree.restore_subtree(subtree).unwrap();
+        assert_eq!(tree.spilled_subtree_for(17), None);
+        assert!(MerkleTree::verify_proof(&tree.get_root(), &leaf(17), &tree.get_proof(17).unwrap(), 17, 14));
+        assert_eq!(proof[..SUBTREE_HEIGHT as usize], tree.get_proof(17).unwrap()[..SUBTREE_HEIGHT as usize]);
+        assert_eq!(tree.compute_root().unwrap(), tree.get_root());
+    }
+
+    // Memory benchmarks; run with `cargo test --release -- --ignored bench_`.
+    fn bench_memory_bounded(max_depth: u32, max_buffer_size: u32) {
+        let mut tree = MerkleTree::new(max_depth, max_buffer_size).unwrap();
+        let leaves = tree.capacity().min(1 << 18) as u32;
+        let mut peak_nodes = 0;
+
+        for i in 0..leaves {
+            tree.append(leaf(i)).unwrap();
+            if i % (1 << SUBTREE_HEIGHT) == 0 {
+                tree.mark_saved();
+                for subtree in tree.cold_subtrees(1 << SUBTREE_HEIGHT) {
+                    tree.take_subtree(subtree).unwrap();
+                }
+            }
+            peak_nodes = peak_nodes.max(tree.resident_nodes());
+        }
+
+        // At most a few hot subtrees plus the resident subtree roots and the path above them.
+        let subtree_nodes = (2usize << SUBTREE_HEIGHT) - 1;
+        let bound = 3 * subtree_nodes
+            + 2 * (leaves >> SUBTREE_HEIGHT) as usize
+            + max_depth as usize;
+        assert!(peak_nodes <= bound, "{} > {}", peak_nodes, bound);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_memory_depth_14() {
+        bench_memory_bounded(14, 64);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_memory_depth_20() {
+        bench_memory_bounded(20, 256);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_memory_depth_24() {
+        bench_memory_bounded(24, 1024);
+    }
+
+    #[test]
+    fn test_proof_replay_from_history() {
+        let mut tree = MerkleTree::new(5, 8).unwrap();
+        tree.enable_history(16);
+
+        // (seq, index, leaf, proof, root) as the tree stood after each step.
+        let mut snapshots = Vec::new();
+        let mut snapshot = |tree: &MerkleTree, index: u32| {
+            let (proof, root) = tree.get_proof_with_root(index).unwrap();
+            snapshots.push((tree.sequence_number, index, tree.get_leaf(index).unwrap(), proof, root));
+        };
+        for i in 0..3 {
+            tree.append(leaf(i)).unwrap();
+        }
+        snapshot(&tree, 1);
+        // Larger than the buffer, so most of these never reach the changelog ring.
+        let batch = tree.append_batch(&(3..15).map(leaf).collect::<Vec<_>>()).unwrap();
+        snapshot(&tree, 1);
+        snapshot(&tree, 9);
+        tree.replace_leaf(1, leaf(100)).unwrap();
+        tree.remove_leaf(9).unwrap();
+        snapshot(&tree, 1);
+        snapshot(&tree, 9);
+
+        let change_logs = tree.unsaved_change_logs().to_vec();
+        assert_eq!(change_logs.len() as u64, tree.sequence_number);
+        for (seq, index, leaf, proof, root) in snapshots {
+            let mut replay = ProofReplay::new(5, index, seq).unwrap();
+            // Feed the history in uneven chunks, as storage would hand it back.
+            for chunk in change_logs.chunks(5) {
+                replay.apply(chunk).unwrap();
+            }
+            let historical = replay.finish().unwrap();
+            assert_eq!(historical, HistoricalProof { index, seq, leaf, proof, root });
+            assert!(MerkleTree::verify_proof(&root, &leaf, &historical.proof, index, 5));
+        }
+
+        // Roots of old sequence numbers come from the history once the buffer has moved on.
+        tree.mark_saved();
+        for i in 15..25 {
+            tree.append(leaf(i)).unwrap();
+        }
+        assert_eq!(tree.root_at(tree.sequence_number), Some(tree.get_root()));
+        assert_eq!(tree.root_at(12), Some(batch.roots[8]));
+        assert_eq!(tree.root_at(11), None);
+        assert_eq!(tree.unsaved_change_logs().len(), 10);
+
+        let mut gap = ProofReplay::new(5, 1, 10).unwrap();
+        assert_eq!(gap.apply(&change_logs[1..]), Err("Changelog history is incomplete"));
+        let mut early = ProofReplay::new(5, 9, 3).unwrap();
+        early.apply(&change_logs).unwrap();
+        assert_eq!(early.finish(), Err("Leaf had not been appended at that sequence number"));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        tree.enable_history(32);
+        tree.append_batch(&(0..2100).map(leaf).collect::<Vec<_>>()).unwrap();
+        tree.replace_leaf(7, leaf(5000)).unwrap();
+        tree.mark_saved();
+        let spilled = tree.take_subtree(0).unwrap();
+
+        let mut restored = MerkleTree::from_snapshot(&tree.to_snapshot().unwrap()).unwrap();
+        assert_eq!(restored.get_root(), tree.get_root());
+        assert_eq!(restored.change_logs, tree.change_logs);
+        assert_eq!(restored.rightmost_proof, tree.rightmost_proof);
+        assert_eq!(restored.root_at(tree.sequence_number - 20), tree.root_at(tree.sequence_number - 20));
+        assert_eq!(restored.spilled_subtree_for(7), Some(0));
+        assert_eq!(restored.get_proof(2000), tree.get_proof(2000));
+
+        restored.restore_subtree(spilled.clone()).unwrap();
+        tree.restore_subtree(spilled).unwrap();
+        assert_eq!(restored.append(leaf(9000)), tree.append(leaf(9000)));
+        assert_eq!(restored.replace_leaf(7, leaf(9001)), tree.replace_leaf(7, leaf(9001)));
+        assert_eq!(restored.compute_root(), tree.compute_root());
+    }
+
+    #[test]
+    fn test_snapshot_rejects_corruption() {
+        let mut tree = MerkleTree::new(5, 8).unwrap();
+        for i in 0..10 {
+            tree.append(leaf(i)).unwrap();
+        }
+        let snapshot = tree.to_snapshot().unwrap();
+        assert!(MerkleTree::from_snapshot(&snapshot).is_ok());
+
+        for position in [SNAPSHOT_HEADER_LEN, snapshot.len() / 2, snapshot.len() - 1] {
+            let mut corrupted = snapshot.clone();
+            corrupted[position] ^= 1;
+            assert_eq!(MerkleTree::from_snapshot(&corrupted).err(), Some(SnapshotError::ChecksumMismatch));
+        }
+        assert_eq!(MerkleTree::from_snapshot(&snapshot[..snapshot.len() - 1]).err(), Some(SnapshotError::Truncated));
+        assert_eq!(MerkleTree::from_snapshot(&snapshot[..10]).err(), Some(SnapshotError::Truncated));
+
+        let mut future = snapshot.clone();
+        future[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            MerkleTree::from_snapshot(&future).err(),
+            Some(SnapshotError::UnsupportedVersion { version: SNAPSHOT_VERSION + 1, latest: SNAPSHOT_VERSION })
+        );
+
+        // Trees stored before snapshots were versioned are plain bincode.
+        let legacy = bincode::serialize(&tree).unwrap();
+        assert_eq!(MerkleTree::from_snapshot(&legacy).err(), Some(SnapshotError::InvalidMagic));
+
+        // A well-formed snapshot of an inconsistent tree is still refused.
+        tree.change_logs.pop();
+        assert!(matches!(
+            MerkleTree::from_snapshot(&tree.to_snapshot().unwrap()),
+            Err(SnapshotError::InvalidPayload(_))
+        ));
+    }
+
+    #[test]
+    fn test_incremental_parts_round_trip() {
+        // Stand-in for an incremental backend: one entry per node and buffer slot.
+        #[derive(Default)]
+        struct Store {
+            header: Vec<u8>,
+            change_logs: HashMap<u32, ChangeLog>,
+            nodes: HashMap<(u32, u32), Node>,
+        }
+        let save = |store: &mut Store, tree: &mut MerkleTree| -> usize {
+            let written = tree.dirty_nodes().count();
+            for (level, index, node) in tree.dirty_nodes() {
+                match node {
+                    Some(node) => store.nodes.insert((level, index), node),
+                    None => store.nodes.remove(&(level, index)),
+                };
+            }
+            for (slot, change_log) in tree.dirty_change_logs() {
+                store.change_logs.insert(slot, change_log.clone());
+            }
+            store.header = tree.header_snapshot().unwrap();
+            tree.mark_saved();
+            written
+        };
+        let load = |store: &Store| {
+            MerkleTree::from_parts(
+                &store.header,
+                store.change_logs.iter().map(|(slot, change_log)| (*slot, change_log.clone())),
+                store.nodes.iter().map(|((level, index), node)| (*level, *index, *node)),
+            )
+            .unwrap()
+        };
+
+        let mut store = Store::default();
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        tree.append_batch(&(0..1100).map(leaf).collect::<Vec<_>>()).unwrap();
+        save(&mut store, &mut tree);
+
+        // A single operation only writes its own path.
+        tree.append(leaf(1100)).unwrap();
+        assert_eq!(save(&mut store, &mut tree), 14);
+        tree.remove_leaf(3).unwrap();
+        assert_eq!(save(&mut store, &mut tree), 14);
+        assert!(!store.nodes.contains_key(&(0, 3)));
+        let spilled = tree.take_subtree(0).unwrap();
+        save(&mut store, &mut tree);
+
+        let mut loaded = load(&store);
+        assert_eq!(loaded.get_root(), tree.get_root());
+        assert_eq!(loaded.change_logs, tree.change_logs);
+        assert_eq!(loaded.rightmost_proof, tree.rightmost_proof);
+        assert_eq!(loaded.resident_nodes(), tree.resident_nodes());
+        assert_eq!(loaded.spilled_subtree_for(3), Some(0));
+        assert_eq!(loaded.dirty_nodes().count(), 0);
+
+        loaded.restore_subtree(spilled.clone()).unwrap();
+        tree.restore_subtree(spilled).unwrap();
+        assert_eq!(loaded.replace_leaf(3, leaf(7000)), tree.replace_leaf(3, leaf(7000)));
+        assert_eq!(loaded.append(leaf(7001)), tree.append(leaf(7001)));
+        assert_eq!(loaded.compute_root(), tree.compute_root());
+
+        // A fresh tree only has its first buffer slot to write.
+        let mut empty = MerkleTree::new(5, 8).unwrap();
+        let mut store = Store::default();
+        assert_eq!(save(&mut store, &mut empty), 0);
+        assert_eq!(store.change_logs.len(), 1);
+        assert_eq!(load(&store).change_logs, empty.change_logs);
+
+        assert_eq!(
+            MerkleTree::from_parts(&tree.to_snapshot().unwrap(), [], []).err(),
+            Some(SnapshotError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_migrations_run_in_order() {
+        fn append_one(mut payload: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+            payload.push(1);
+            Ok(payload)
+        }
+        fn append_two(mut payload: Vec<u8>) -> Result<Vec<u8>, SnapshotError> {
+            payload.push(2);
+            Ok(payload)
+        }
+        let migrations: &[SnapshotMigration] = &[append_one, append_two];
+
+        assert_eq!(migrate_snapshot(1, vec![0], migrations), Ok(vec![0, 1, 2]));
+        assert_eq!(migrate_snapshot(2, vec![0], migrations), Ok(vec![0, 2]));
+        assert_eq!(migrate_snapshot(3, vec![0], migrations), Ok(vec![0]));
+        assert_eq!(
+            migrate_snapshot(4, vec![0], migrations),
+            Err(SnapshotError::UnsupportedVersion { version: 4, latest: 3 })
+        );
+    }
+
+    #[test]
+    fn test_node_parsing() {
+        let node = leaf(1);
+        assert_eq!(node.to_string().parse::<Node>(), Ok(node));
+        assert_eq!(Node::try_from(&node.0[..]), Ok(node));
+
+        assert_eq!(Node::try_from(&node.0[..31]), Err(NodeError::InvalidLength(31)));
+        assert_eq!(bs58::encode(&node.0[..31]).into_string().parse::<Node>(), Err(NodeError::InvalidLength(31)));
+        assert_eq!(bs58::encode([1u8; 33]).into_string().parse::<Node>(), Err(NodeError::InvalidLength(33)));
+        assert!(matches!("0OIl".parse::<Node>(), Err(NodeError::InvalidEncoding(_))));
+    }
+
+    #[test]
+    fn test_rejects_unsupported_pairs() {
+        assert!(MerkleTree::new(14, 65).is_err());
+        assert!(MerkleTree::new(31, 2048).is_err());
+        assert!(MerkleTree::new(30, 64).is_err());
+        assert!(MerkleTree::new(6, 8).is_err());
+        assert!(MerkleTree::new(13, 64).is_err());
+        for depth in 6..=13 {
+            assert!(MerkleTree::new(depth, if depth < 10 { 16 } else { 32 }).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_full_tree() {
+        let mut tree = MerkleTree::new(3, 8).unwrap();
+        for i in 0..8 {
+            tree.append(leaf(i)).unwrap();
+        }
+        assert_eq!(tree.append(leaf(8)), Err("Tree is full"));
+        assert_eq!(tree.append(EMPTY_NODE), Err("Cannot append an empty node"));
+    }
+}
@@ -0,0 +1,460 @@
+use crate::merkle::{ChangeLog, MerkleTree, Node, Subtree, SUBTREE_HEIGHT};
+use crate::tree_manager::{LeafRecord, TreeDescriptor, TreeStorage};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use solana_sdk::pubkey::Pubkey;
+use std::{path::Path, str::FromStr, sync::Mutex};
+
+// Keys and leaf hashes are stored base58 encoded so the tables can be queried
+// by hand; nodes and changelogs, which nobody reads directly, as blobs.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS trees (
+        merkle_tree TEXT PRIMARY KEY,
+        max_depth INTEGER,
+        max_buffer_size INTEGER,
+        canopy_depth INTEGER,
+        authority TEXT,
+        creation_slot INTEGER,
+        header BLOB,
+        lookup_table TEXT
+    );
+    CREATE TABLE IF NOT EXISTS nodes (
+        merkle_tree TEXT NOT NULL,
+        level INTEGER NOT NULL,
+        node_index INTEGER NOT NULL,
+        node BLOB NOT NULL,
+        PRIMARY KEY (merkle_tree, level, node_index)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS buffer_slots (
+        merkle_tree TEXT NOT NULL,
+        slot INTEGER NOT NULL,
+        change_log BLOB NOT NULL,
+        PRIMARY KEY (merkle_tree, slot)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS change_logs (
+        merkle_tree TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        leaf_index INTEGER NOT NULL,
+        root TEXT NOT NULL,
+        change_log BLOB NOT NULL,
+        PRIMARY KEY (merkle_tree, seq)
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS leaves (
+        merkle_tree TEXT NOT NULL,
+        leaf_index INTEGER NOT NULL,
+        asset_id TEXT NOT NULL,
+        owner TEXT NOT NULL,
+        delegate TEXT NOT NULL,
+        nonce INTEGER NOT NULL,
+        data_hash TEXT NOT NULL,
+        creator_hash TEXT NOT NULL,
+        collection TEXT,
+        PRIMARY KEY (merkle_tree, leaf_index)
+    );
+    CREATE INDEX IF NOT EXISTS leaves_by_owner ON leaves (merkle_tree, owner);
+    CREATE INDEX IF NOT EXISTS leaves_by_collection ON leaves (merkle_tree, collection);
+    CREATE INDEX IF NOT EXISTS leaves_by_asset_id ON leaves (asset_id);
+";
+
+const LEAF_COLUMNS: &str =
+    "leaf_index, asset_id, owner, delegate, nonce, data_hash, creator_hash, collection";
+
+/// Stores trees, their changelog history and an index of Bubblegum leaves in
+/// SQLite tables (see `SCHEMA`). Every save is a single transaction.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn new(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> anyhow::Result<Self> {
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.execute_batch(SCHEMA)?;
+        // Databases created before trees had lookup tables lack the column.
+        let has_lookup_table: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('trees') WHERE name = 'lookup_table'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_lookup_table {
+            conn.execute("ALTER TABLE trees ADD COLUMN lookup_table TEXT", [])?;
+        }
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    fn conn(&self) -> anyhow::Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow::anyhow!("SQLite connection lock is poisoned"))
+    }
+
+    fn query_leaves(&self, filter: &str, merkle_tree: &Pubkey, key: &Pubkey) -> anyhow::Result<Vec<LeafRecord>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT {} FROM leaves WHERE merkle_tree = ?1 AND {} = ?2 ORDER BY leaf_index",
+            LEAF_COLUMNS, filter
+        ))?;
+        let rows = statement.query_map(params![merkle_tree.to_string(), key.to_string()], |row| Ok(read_leaf(row)))?;
+        rows.map(|row| row?).collect()
+    }
+}
+
+fn parse_pubkey(encoded: &str) -> anyhow::Result<Pubkey> {
+    Ok(Pubkey::from_str(encoded)?)
+}
+
+fn read_leaf(row: &Row) -> anyhow::Result<LeafRecord> {
+    let collection: Option<String> = row.get(7)?;
+    Ok(LeafRecord {
+        index: row.get(0)?,
+        asset_id: parse_pubkey(&row.get::<_, String>(1)?)?,
+        owner: parse_pubkey(&row.get::<_, String>(2)?)?,
+        delegate: parse_pubkey(&row.get::<_, String>(3)?)?,
+        nonce: row.get::<_, i64>(4)? as u64,
+        data_hash: row.get::<_, String>(5)?.parse()?,
+        creator_hash: row.get::<_, String>(6)?.parse()?,
+        collection: collection.as_deref().map(parse_pubkey).transpose()?,
+    })
+}
+
+#[async_trait]
+impl TreeStorage for SqliteStorage {
+    async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
+        let conn = self.conn()?;
+        let key = merkle_tree.to_string();
+        let header = conn
+            .query_row("SELECT header FROM trees WHERE merkle_tree = ?1", params![key], |row| {
+                row.get::<_, Option<Vec<u8>>>(0)
+            })
+            .optional()?
+            .flatten();
+        let header = match header {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let mut statement = conn.prepare_cached("SELECT slot, change_log FROM buffer_slots WHERE merkle_tree = ?1")?;
+        let change_logs = statement
+            .query_map(params![key], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+            .map(|row| {
+                let (slot, change_log) = row?;
+                Ok((slot, bincode::deserialize(&change_log)?))
+            })
+            .collect::<anyhow::Result<Vec<(u32, ChangeLog)>>>()?;
+
+        let mut statement = conn.prepare_cached("SELECT level, node_index, node FROM nodes WHERE merkle_tree = ?1")?;
+        let nodes = statement
+            .query_map(params![key], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?, row.get::<_, Vec<u8>>(2)?)))?
+            .map(|row| {
+                let (level, index, node) = row?;
+                Ok((level, index, Node::try_from(&node[..])?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        MerkleTree::from_parts(&header, change_logs, nodes)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Failed to load tree {}: {}", merkle_tree, e))
+    }
+
+    async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        let key = merkle_tree.to_string();
+        {
+            let mut put_node = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO nodes (merkle_tree, level, node_index, node) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            let mut delete_node = transaction.prepare_cached(
+                "DELETE FROM nodes WHERE merkle_tree = ?1 AND level = ?2 AND node_index = ?3",
+            )?;
+            for (level, index, node) in tree.dirty_nodes() {
+                match node {
+                    Some(node) => put_node.execute(params![key, level, index, node.as_ref()])?,
+                    None => delete_node.execute(params![key, level, index])?,
+                };
+            }
+
+            let mut put_slot = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO buffer_slots (merkle_tree, slot, change_log) VALUES (?1, ?2, ?3)",
+            )?;
+            for (slot, change_log) in tree.dirty_change_logs() {
+                put_slot.execute(params![key, slot, bincode::serialize(change_log)?])?;
+            }
+
+            let mut put_change_log = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO change_logs (merkle_tree, seq, leaf_index, root, change_log)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for (seq, change_log) in tree.unsaved_change_logs() {
+                put_change_log.execute(params![
+                    key,
+                    *seq as i64,
+                    change_log.index,
+                    change_log.root.to_string(),
+                    bincode::serialize(change_log)?
+                ])?;
+            }
+        }
+        transaction.execute(
+            "INSERT INTO trees (merkle_tree, header) VALUES (?1, ?2)
+             ON CONFLICT (merkle_tree) DO UPDATE SET header = excluded.header",
+            params![key, tree.header_snapshot()?],
+        )?;
+
+        Ok(transaction.commit()?)
+    }
+
+    async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()> {
+        let mut conn = self.conn()?;
+        let transaction = conn.transaction()?;
+        for table in ["trees", "nodes", "buffer_slots", "change_logs", "leaves"] {
+            transaction.execute(&format!("DELETE FROM {} WHERE merkle_tree = ?1", table), params![merkle_tree.to_string()])?;
+        }
+        Ok(transaction.commit()?)
+    }
+
+    async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()> {
+        self.conn()?.execute(
+            "INSERT INTO trees (merkle_tree, max_depth, max_buffer_size, canopy_depth, authority, creation_slot, lookup_table)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (merkle_tree) DO UPDATE SET
+                max_depth = excluded.max_depth,
+                max_buffer_size = excluded.max_buffer_size,
+                canopy_depth = excluded.canopy_depth,
+                authority = excluded.authority,
+                creation_slot = excluded.creation_slot,
+                lookup_table = excluded.lookup_table",
+            params![
+                merkle_tree.to_string(),
+                descriptor.max_depth,
+                descriptor.max_buffer_size,
+                descriptor.canopy_depth,
+                descriptor.authority.to_string(),
+                descriptor.creation_slot as i64,
+                descriptor.lookup_table.map(|table| table.to_string())
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+        let conn = self.conn()?;
+        let row = conn
+            .query_row(
+                "SELECT max_depth, max_buffer_size, canopy_depth, authority, creation_slot, lookup_table
+                 FROM trees WHERE merkle_tree = ?1 AND max_depth IS NOT NULL",
+                params![merkle_tree.to_string()],
+                |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, u32>(2)?,
+                        row.get::<_, String>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, Option<String>>(5)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        row.map(|(max_depth, max_buffer_size, canopy_depth, authority, creation_slot, lookup_table)| {
+            Ok(TreeDescriptor {
+                max_depth,
+                max_buffer_size,
+                canopy_depth,
+                authority: parse_pubkey(&authority)?,
+                creation_slot: creation_slot as u64,
+                lookup_table: lookup_table.as_deref().map(parse_pubkey).transpose()?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+        let merkle_trees = {
+            let conn = self.conn()?;
+            let mut statement = conn.prepare_cached("SELECT merkle_tree FROM trees WHERE max_depth IS NOT NULL")?;
+            let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut descriptors = Vec::new();
+        for merkle_tree in merkle_trees {
+            let merkle_tree = parse_pubkey(&merkle_tree)?;
+            if let Some(descriptor) = self.load_descriptor(&merkle_tree).await? {
+                descriptors.push((merkle_tree, descriptor));
+            }
+        }
+        Ok(descriptors)
+    }
+
+    /// Nodes are already stored one by one by `save_tree`.
+    async fn save_subtree(&self, _merkle_tree: &Pubkey, _subtree: &Subtree) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare_cached(
+            "SELECT node_index, node FROM nodes
+             WHERE merkle_tree = ?1 AND level = ?2 AND node_index BETWEEN ?3 AND ?4",
+        )?;
+
+        let mut nodes = Vec::new();
+        for level in 0..SUBTREE_HEIGHT {
+            let width = 1u32 << (SUBTREE_HEIGHT - level);
+            let (first, last) = (index * width, (index + 1) * width - 1);
+            let rows = statement.query_map(params![merkle_tree.to_string(), level, first, last], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?;
+            for row in rows {
+                let (index, node) = row?;
+                nodes.push((level, index, Node::try_from(&node[..])?));
+            }
+        }
+        Ok(Some(Subtree { index, nodes }))
+    }
+
+    async fn load_change_logs(
+        &self,
+        merkle_tree: &Pubkey,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(u64, ChangeLog)>> {
+        let conn = self.conn()?;
+        let mut statement = conn.prepare_cached(
+            "SELECT seq, change_log FROM change_logs WHERE merkle_tree = ?1 AND seq >= ?2 ORDER BY seq LIMIT ?3",
+        )?;
+        let rows = statement.query_map(params![merkle_tree.to_string(), from_seq as i64, limit as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?;
+        rows.map(|row| {
+            let (seq, change_log) = row?;
+            Ok((seq as u64, bincode::deserialize(&change_log)?))
+        })
+        .collect()
+    }
+
+    async fn save_leaf_record(&self, merkle_tree: &Pubkey, record: &LeafRecord) -> anyhow::Result<()> {
+        self.conn()?.execute(
+            &format!(
+                "INSERT OR REPLACE INTO leaves (merkle_tree, {}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                LEAF_COLUMNS
+            ),
+            params![
+                merkle_tree.to_string(),
+                record.index,
+                record.asset_id.to_string(),
+                record.owner.to_string(),
+                record.delegate.to_string(),
+                record.nonce as i64,
+                record.data_hash.to_string(),
+                record.creator_hash.to_string(),
+                record.collection.map(|collection| collection.to_string())
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn leaves_by_owner(&self, merkle_tree: &Pubkey, owner: &Pubkey) -> anyhow::Result<Vec<LeafRecord>> {
+        self.query_leaves("owner", merkle_tree, owner)
+    }
+
+    async fn leaves_by_collection(
+        &self,
+        merkle_tree: &Pubkey,
+        collection: &Pubkey,
+    ) -> anyhow::Result<Vec<LeafRecord>> {
+        self.query_leaves("collection", merkle_tree, collection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leaf::LeafSchemaV1;
+    use crate::tree_manager::TreeManager;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    fn descriptor() -> TreeDescriptor {
+        TreeDescriptor {
+            max_depth: 14,
+            max_buffer_size: 64,
+            canopy_depth: 0,
+            authority: Pubkey::new_from_array([7; 32]),
+            creation_slot: 1,
+            lookup_table: None,
+        }
+    }
+
+    fn record(merkle_tree: &Pubkey, index: u32, owner: &Pubkey, collection: Option<Pubkey>) -> LeafRecord {
+        let leaf = LeafSchemaV1 {
+            id: crate::leaf::get_asset_id(merkle_tree, index as u64),
+            owner: *owner,
+            delegate: *owner,
+            nonce: index as u64,
+            data_hash: [index as u8; 32],
+            creator_hash: [1; 32],
+        };
+        LeafRecord::new(index, &leaf, collection)
+    }
+
+    #[tokio::test]
+    async fn test_reopen_and_history() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("trees.sqlite");
+        let merkle_tree = Pubkey::new_unique();
+        let leaves: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 8]).collect();
+
+        let expected = {
+            let manager = TreeManager::new(Arc::new(SqliteStorage::new(&path)?)).with_root_history(8);
+            manager.open_tree(&merkle_tree, descriptor()).await?;
+            manager.insert_leaves(&merkle_tree, &leaves).await?;
+            manager.replace_leaf(&merkle_tree, 7, Node::new([9; 32])).await?;
+            manager.get_proof(&merkle_tree, 7).await?
+        };
+
+        let manager = TreeManager::new(Arc::new(SqliteStorage::new(&path)?));
+        assert_eq!(manager.list_trees().await?, vec![(merkle_tree, descriptor())]);
+        assert_eq!(manager.get_proof(&merkle_tree, 7).await?, expected);
+        let historical = manager.get_proof_at(&merkle_tree, 7, 40).await?;
+        assert_eq!(historical.leaf, Node::new(solana_program::keccak::hash(&leaves[7]).to_bytes()));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leaf_index() -> anyhow::Result<()> {
+        let manager = TreeManager::new(Arc::new(SqliteStorage::open_in_memory()?));
+        let merkle_tree = Pubkey::new_unique();
+        let (alice, bob, collection) = (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+
+        assert!(manager.record_leaf(&merkle_tree, &record(&merkle_tree, 0, &alice, None)).await.is_err());
+        manager.open_tree(&merkle_tree, descriptor()).await?;
+        manager.record_leaf(&merkle_tree, &record(&merkle_tree, 0, &alice, Some(collection))).await?;
+        manager.record_leaf(&merkle_tree, &record(&merkle_tree, 1, &bob, Some(collection))).await?;
+        manager.record_leaf(&merkle_tree, &record(&merkle_tree, 2, &alice, None)).await?;
+
+        assert_eq!(
+            manager.leaves_by_owner(&merkle_tree, &alice).await?,
+            vec![record(&merkle_tree, 0, &alice, Some(collection)), record(&merkle_tree, 2, &alice, None)]
+        );
+        assert_eq!(manager.leaves_by_collection(&merkle_tree, &collection).await?.len(), 2);
+
+        // Re-recording a leaf after a transfer moves it to the new owner.
+        manager.record_leaf(&merkle_tree, &record(&merkle_tree, 0, &bob, Some(collection))).await?;
+        let indices: Vec<u32> = manager.leaves_by_owner(&merkle_tree, &bob).await?.iter().map(|r| r.index).collect();
+        assert_eq!(indices, vec![0, 1]);
+        assert!(manager.leaves_by_owner(&Pubkey::new_unique(), &bob).await?.is_empty());
+
+        Ok(())
+    }
+}
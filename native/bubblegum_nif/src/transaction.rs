@@ -1,43 +1,286 @@
-use solana_client::rpc_client::RpcClient;
+use solana_client::{
+    nonce_utils,
+    rpc_client::RpcClient,
+    rpc_config::{RpcSendTransactionConfig, RpcSimulateTransactionConfig},
+};
 use solana_sdk::{
+    address_lookup_table::{instruction as lookup_table_ix, state::AddressLookupTable, AddressLookupTableAccount},
+    clock::MAX_PROCESSING_AGE,
     commitment_config::CommitmentConfig,
+    compute_budget::ComputeBudgetInstruction,
+    hash::Hash,
+    instruction::Instruction,
+    message::{v0, Message, VersionedMessage},
+    nonce::{self, state::Data as NonceData},
+    packet::PACKET_DATA_SIZE,
     signature::{Keypair, Signature},
     signer::Signer,
-    transaction::Transaction,
+    system_instruction::{self, SystemInstruction},
+    system_program,
+    transaction::VersionedTransaction,
     pubkey::Pubkey,
 };
-use crate::error::BubblegumError;
+use dashmap::DashMap;
+use mpl_bubblegum::types::MetadataArgs;
+use crate::{leaf::get_tree_authority, BubblegumError};
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 use tracing::{info, warn};
 
+// Most compute units a transaction may request.
+pub const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+// Addresses added to a lookup table per transaction, which keeps each
+// extension well inside the packet size.
+const LOOKUP_TABLE_EXTEND_CHUNK: usize = 20;
+
 pub struct TransactionManager {
     client: RpcClient,
     simulation_enabled: bool,
     retry_config: RetryConfig,
+    compute_budget: ComputeBudgetConfig,
+    // Lookup table registered for each merkle tree, as last read or written.
+    lookup_tables: DashMap<Pubkey, AddressLookupTableAccount>,
 }
 
 #[derive(Debug, Clone)]
 pub struct RetryConfig {
+    /// Blockhashes a transaction is signed with before it is reported expired.
     pub max_attempts: u32,
+    /// Delay between status polls, doubled after each RPC error up to `max_delay_ms`.
     pub base_delay_ms: u64,
     pub max_delay_ms: u64,
+    /// How often a transaction that has not landed is sent again.
+    pub rebroadcast_ms: u64,
+    /// Status polls in a row that may fail before a transaction is given up on.
+    pub max_poll_errors: u32,
+    /// How long a transaction is polled before it is given up on.
+    pub confirm_timeout_ms: u64,
 }
 
 impl Default for RetryConfig {
     fn default() -> Self {
         Self {
             max_attempts: 3,
-            base_delay_ms: 1000,
+            base_delay_ms: 500,
             max_delay_ms: 10000,
+            rebroadcast_ms: 2000,
+            max_poll_errors: 10,
+            confirm_timeout_ms: 120_000,
+        }
+    }
+}
+
+/// What keeps a signed transaction valid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionLifetime {
+    /// A recent blockhash, usable up to this block height.
+    BlockHeight(u64),
+    /// A durable nonce, usable until `account` no longer holds `nonce`.
+    Nonce { account: Pubkey, nonce: Hash },
+}
+
+/// How a transaction sent by `TransactionManager` ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendOutcome {
+    /// Confirmed at the client's commitment without error.
+    Landed { signature: Signature, slot: u64 },
+    /// Never landed, and `lifetime` has run out.
+    Expired { signature: Signature, lifetime: TransactionLifetime },
+    /// Landed with an error, which still costs its fee.
+    Failed { signature: Signature, error: String },
+}
+
+impl SendOutcome {
+    /// The signature of a landed transaction, or an error describing why it did not land.
+    pub fn into_result(self) -> Result<Signature, BubblegumError> {
+        match self {
+            SendOutcome::Landed { signature, .. } => Ok(signature),
+            SendOutcome::Expired { signature, lifetime: TransactionLifetime::BlockHeight(height) } => {
+                Err(BubblegumError::TransactionExpired {
+                    signature: signature.to_string(),
+                    reason: format!("block height {} passed", height),
+                })
+            }
+            SendOutcome::Expired { signature, lifetime: TransactionLifetime::Nonce { account, .. } } => {
+                Err(BubblegumError::TransactionExpired {
+                    signature: signature.to_string(),
+                    reason: format!("nonce account {} was advanced", account),
+                })
+            }
+            SendOutcome::Failed { signature, error } => Err(BubblegumError::TransactionFailed {
+                signature: signature.to_string(),
+                reason: error,
+            }),
         }
     }
 }
 
+/// How the compute-unit price of a transaction is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeePolicy {
+    /// No priority fee.
+    None,
+    /// A fixed price, in micro-lamports per compute unit.
+    Fixed(u64),
+    /// The given percentile (0-100) of the prioritization fees recently paid
+    /// to write the transaction's writable accounts.
+    Percentile(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ComputeBudgetConfig {
+    pub fee_policy: FeePolicy,
+    /// Upper bound on the price, whatever the policy picks.
+    pub max_micro_lamports: Option<u64>,
+    /// Compute units requested on top of those consumed in simulation, in percent.
+    pub unit_margin_percent: u32,
+    /// Compute-unit limit to request instead of simulating.
+    pub unit_limit: Option<u32>,
+}
+
+impl Default for ComputeBudgetConfig {
+    fn default() -> Self {
+        Self {
+            fee_policy: FeePolicy::None,
+            max_micro_lamports: None,
+            unit_margin_percent: 10,
+            unit_limit: None,
+        }
+    }
+}
+
+/// The `percentile` (0-100) of `fees` by nearest rank, or 0 without fees.
+pub fn fee_percentile(mut fees: Vec<u64>, percentile: u8) -> u64 {
+    if fees.is_empty() {
+        return 0;
+    }
+    fees.sort_unstable();
+    let rank = (fees.len() * percentile.min(100) as usize).div_ceil(100).max(1);
+    fees[rank - 1]
+}
+
+/// `units` plus `margin_percent`, rounded up and capped at what a transaction may request.
+pub fn unit_limit_with_margin(units: u64, margin_percent: u32) -> u32 {
+    let limit = (units * (100 + margin_percent as u64)).div_ceil(100);
+    limit.min(MAX_COMPUTE_UNIT_LIMIT as u64) as u32
+}
+
+/// The accounts every leaf instruction on `merkle_tree` uses besides its
+/// proof, which are what its lookup table holds.
+pub fn tree_lookup_addresses(merkle_tree: &Pubkey) -> Vec<Pubkey> {
+    vec![
+        *merkle_tree,
+        get_tree_authority(merkle_tree),
+        mpl_bubblegum::SPL_NOOP_ID,
+        mpl_bubblegum::SPL_ACCOUNT_COMPRESSION_ID,
+        mpl_bubblegum::ID,
+    ]
+}
+
+/// Serialized size of a transaction carrying `message` and its signatures.
+pub fn transaction_size(message: &VersionedMessage) -> usize {
+    let signatures = message.header().num_required_signatures as usize;
+    // The signature count is a compact-u16, one byte below 128.
+    1 + signatures * 64 + message.serialize().len()
+}
+
+/// Compiles a legacy message, or a v0 message resolving accounts through
+/// `lookup_tables` when the legacy one would not fit in a packet.
+pub fn compile_message(
+    instructions: &[Instruction],
+    payer: &Pubkey,
+    recent_blockhash: Hash,
+    lookup_tables: &[AddressLookupTableAccount],
+) -> Result<VersionedMessage, BubblegumError> {
+    let legacy = VersionedMessage::Legacy(Message::new_with_blockhash(instructions, Some(payer), &recent_blockhash));
+    let legacy_size = transaction_size(&legacy);
+    if legacy_size <= PACKET_DATA_SIZE || lookup_tables.is_empty() {
+        return Ok(legacy);
+    }
+
+    let message = VersionedMessage::V0(
+        v0::Message::try_compile(payer, instructions, lookup_tables, recent_blockhash)
+            .map_err(|e| BubblegumError::TransactionError(format!("Failed to compile v0 message: {}", e)))?,
+    );
+    info!("Transaction is {} bytes as legacy, {} bytes as v0", legacy_size, transaction_size(&message));
+    Ok(message)
+}
+
+fn check_transaction_size(message: &VersionedMessage, has_lookup_tables: bool) -> Result<(), BubblegumError> {
+    if transaction_size(message) > PACKET_DATA_SIZE {
+        return Err(BubblegumError::TransactionError(format!(
+            "Transaction is {} bytes, over the {}-byte limit{}",
+            transaction_size(message),
+            PACKET_DATA_SIZE,
+            if has_lookup_tables { " even with lookup tables" } else { "; create a lookup table for the tree" },
+        )));
+    }
+    Ok(())
+}
+
+/// A transaction for `message` with every signature still blank.
+pub fn unsigned_transaction(message: VersionedMessage) -> VersionedTransaction {
+    VersionedTransaction {
+        signatures: vec![Signature::default(); message.header().num_required_signatures as usize],
+        message,
+    }
+}
+
+/// Adds `keypair`'s signature to `tx`, leaving the others as they are.
+/// Fails if `keypair` is not one of its signers.
+pub fn partially_sign(tx: &mut VersionedTransaction, keypair: &Keypair) -> Result<(), BubblegumError> {
+    let signers = tx.message.header().num_required_signatures as usize;
+    let position = tx.message.static_account_keys()[..signers]
+        .iter()
+        .position(|key| *key == keypair.pubkey())
+        .ok_or_else(|| BubblegumError::KeypairError(
+            format!("{} is not a signer of the transaction", keypair.pubkey())
+        ))?;
+
+    tx.signatures.resize(signers, Signature::default());
+    tx.signatures[position] = keypair.sign_message(&tx.message.serialize());
+    Ok(())
+}
+
+/// The signers of `tx` whose signature is still blank.
+pub fn missing_signers(tx: &VersionedTransaction) -> Vec<Pubkey> {
+    let signers = tx.message.header().num_required_signatures as usize;
+    tx.message.static_account_keys()[..signers]
+        .iter()
+        .enumerate()
+        .filter(|(position, _)| tx.signatures.get(*position).is_none_or(|signature| *signature == Signature::default()))
+        .map(|(_, key)| *key)
+        .collect()
+}
+
+/// The nonce account of `tx` if it starts by advancing a durable nonce.
+pub fn durable_nonce_account(tx: &VersionedTransaction) -> Option<Pubkey> {
+    let keys = tx.message.static_account_keys();
+    let ix = tx.message.instructions().first()?;
+    if keys.get(ix.program_id_index as usize) != Some(&system_program::ID) {
+        return None;
+    }
+    match bincode::deserialize(&ix.data) {
+        Ok(SystemInstruction::AdvanceNonceAccount) => keys.get(*ix.accounts.first()? as usize).copied(),
+        _ => None,
+    }
+}
+
 impl TransactionManager {
     pub fn new(rpc_url: &str, commitment: CommitmentConfig) -> Self {
+        Self::with_client(RpcClient::new_with_commitment(rpc_url.to_string(), commitment))
+    }
+
+    pub fn with_client(client: RpcClient) -> Self {
         Self {
-            client: RpcClient::new_with_commitment(rpc_url.to_string(), commitment),
+            client,
             simulation_enabled: true,
             retry_config: RetryConfig::default(),
+            compute_budget: ComputeBudgetConfig::default(),
+            lookup_tables: DashMap::new(),
         }
     }
 
@@ -45,11 +288,499 @@ impl TransactionManager {
         self.simulation_enabled = false;
     }
 
-    pub async fn simulate_and_send(
+    /// Sets the compute budget used when a call does not pass its own.
+    pub fn set_compute_budget(&mut self, config: ComputeBudgetConfig) {
+        self.compute_budget = config;
+    }
+
+    pub fn compute_budget(&self) -> ComputeBudgetConfig {
+        self.compute_budget
+    }
+
+    /// The compute-unit price `config` asks for `instructions`, capped at
+    /// `max_micro_lamports`.
+    pub fn priority_fee(
+        &self,
+        instructions: &[Instruction],
+        config: &ComputeBudgetConfig,
+    ) -> Result<u64, BubblegumError> {
+        let price = match config.fee_policy {
+            FeePolicy::None => 0,
+            FeePolicy::Fixed(micro_lamports) => micro_lamports,
+            FeePolicy::Percentile(percentile) => {
+                let mut writable: Vec<Pubkey> = instructions
+                    .iter()
+                    .flat_map(|ix| ix.accounts.iter())
+                    .filter(|meta| meta.is_writable)
+                    .map(|meta| meta.pubkey)
+                    .collect();
+                writable.sort();
+                writable.dedup();
+
+                let fees = self.client
+                    .get_recent_prioritization_fees(&writable)
+                    .map_err(|e| BubblegumError::RpcError(format!("Failed to get prioritization fees: {}", e)))?;
+                fee_percentile(fees.iter().map(|fee| fee.prioritization_fee).collect(), percentile)
+            }
+        };
+        Ok(config.max_micro_lamports.map_or(price, |max| price.min(max)))
+    }
+
+    /// Simulates `instructions` unsigned, at the highest compute-unit limit,
+    /// and returns the units they consumed.
+    pub fn simulate_units(
+        &self,
+        instructions: &[Instruction],
+        payer: &Pubkey,
+        lookup_tables: &[AddressLookupTableAccount],
+    ) -> Result<u64, BubblegumError> {
+        let mut simulated = vec![ComputeBudgetInstruction::set_compute_unit_limit(MAX_COMPUTE_UNIT_LIMIT)];
+        simulated.extend_from_slice(instructions);
+        // The RPC node swaps in a recent blockhash and skips signature checks.
+        let tx = unsigned_transaction(compile_message(&simulated, payer, Hash::default(), lookup_tables)?);
+
+        let simulation = self.client
+            .simulate_transaction_with_config(&tx, RpcSimulateTransactionConfig {
+                sig_verify: false,
+                replace_recent_blockhash: true,
+                ..RpcSimulateTransactionConfig::default()
+            })
+            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+
+        if let Some(err) = simulation.value.err {
+            for log in simulation.value.logs.unwrap_or_default() {
+                warn!("Simulation log: {}", log);
+            }
+            return Err(BubblegumError::TransactionError(
+                format!("Simulation failed: {:?}", err)
+            ));
+        }
+        simulation.value.units_consumed.ok_or_else(|| {
+            BubblegumError::RpcError("Simulation did not report consumed compute units".to_string())
+        })
+    }
+
+    /// The ComputeBudget instructions `config` calls for, to go in front of
+    /// `instructions`. The limit is simulated unless `config` fixes it or
+    /// simulation is disabled, in which case the runtime default applies.
+    pub fn compute_budget_instructions(
+        &self,
+        instructions: &[Instruction],
+        payer: &Pubkey,
+        config: &ComputeBudgetConfig,
+        lookup_tables: &[AddressLookupTableAccount],
+    ) -> Result<Vec<Instruction>, BubblegumError> {
+        let mut budget = Vec::new();
+
+        let unit_limit = match config.unit_limit {
+            Some(limit) => Some(limit.min(MAX_COMPUTE_UNIT_LIMIT)),
+            None if self.simulation_enabled => {
+                let units = self.simulate_units(instructions, payer, lookup_tables)?;
+                Some(unit_limit_with_margin(units, config.unit_margin_percent))
+            }
+            None => None,
+        };
+        if let Some(limit) = unit_limit {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_limit(limit));
+        }
+
+        let price = self.priority_fee(instructions, config)?;
+        if price > 0 {
+            budget.push(ComputeBudgetInstruction::set_compute_unit_price(price));
+        }
+
+        info!("Compute budget: limit {:?}, price {} micro-lamports", unit_limit, price);
+        Ok(budget)
+    }
+
+    /// Sends `instructions` behind the ComputeBudget instructions `config`
+    /// calls for, as in `send_and_confirm`. Lookup tables registered for the
+    /// trees they touch are used if a legacy transaction would not fit.
+    pub async fn send_instructions(
         &self,
-        tx: &Transaction,
+        instructions: &[Instruction],
+        payer: &Pubkey,
         signers: &[&Keypair],
+        config: &ComputeBudgetConfig,
+    ) -> Result<SendOutcome, BubblegumError> {
+        let lookup_tables = self.lookup_tables_for(instructions);
+        let mut all = self.compute_budget_instructions(instructions, payer, config, &lookup_tables)?;
+        all.extend_from_slice(instructions);
+
+        self.send_and_confirm(|recent_blockhash| {
+            let message = compile_message(&all, payer, recent_blockhash, &lookup_tables)?;
+            check_transaction_size(&message, !lookup_tables.is_empty())?;
+            VersionedTransaction::try_new(message, signers).map_err(|e| BubblegumError::KeypairError(e.to_string()))
+        })
+        .await
+    }
+
+    /// Signs a transaction with `build` and sends it until it lands or its
+    /// blockhash expires, then signs it again with a fresh blockhash, up to
+    /// `max_attempts` times. A transaction is only re-signed once the old one
+    /// provably can no longer land, so it never lands twice.
+    pub async fn send_and_confirm<F>(&self, build: F) -> Result<SendOutcome, BubblegumError>
+    where
+        F: Fn(Hash) -> Result<VersionedTransaction, BubblegumError>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let (recent_blockhash, last_valid_block_height) = self.client
+                .get_latest_blockhash_with_commitment(self.client.commitment())
+                .map_err(|e| BubblegumError::RpcError(format!("Failed to get blockhash: {}", e)))?;
+            let tx = build(recent_blockhash)?;
+
+            let outcome = self.submit(&tx, TransactionLifetime::BlockHeight(last_valid_block_height))?;
+            match outcome {
+                SendOutcome::Expired { signature, .. } if attempt < self.retry_config.max_attempts => {
+                    warn!("Transaction {} expired on attempt {}; signing it again", signature, attempt);
+                }
+                outcome => return Ok(outcome),
+            }
+        }
+    }
+
+    /// Sends `tx` and polls its status until it lands or its `lifetime` has
+    /// run out at finalized commitment, rebroadcasting it meanwhile. Sending
+    /// the same signed transaction again cannot make it land twice. Gives up
+    /// with a `TransactionUnknown` error after `max_poll_errors` failed polls in a row or
+    /// `confirm_timeout_ms`, when the transaction may still land.
+    pub fn submit(
+        &self,
+        tx: &VersionedTransaction,
+        lifetime: TransactionLifetime,
+    ) -> Result<SendOutcome, BubblegumError> {
+        let signature = tx.signatures[0];
+        let send_config = RpcSendTransactionConfig {
+            skip_preflight: true,
+            max_retries: Some(0),
+            ..RpcSendTransactionConfig::default()
+        };
+        let deadline = Instant::now() + Duration::from_millis(self.retry_config.confirm_timeout_ms);
+        let mut last_sent: Option<Instant> = None;
+        let mut delay_ms = self.retry_config.base_delay_ms;
+        let mut poll_errors = 0;
+
+        loop {
+            if last_sent.is_none_or(|sent| sent.elapsed() >= Duration::from_millis(self.retry_config.rebroadcast_ms)) {
+                if let Err(e) = self.client.send_transaction_with_config(tx, send_config) {
+                    warn!("Failed to send transaction {}: {}", signature, e);
+                }
+                last_sent = Some(Instant::now());
+            }
+
+            let polled = self.signature_outcome(&signature, false)
+                .and_then(|outcome| Ok((outcome, self.is_expired(&lifetime)?)));
+            match polled {
+                Ok((Some(outcome), _)) => {
+                    info!("Transaction {} settled: {:?}", signature, outcome);
+                    return Ok(outcome);
+                }
+                Ok((None, true)) => {
+                    // Any block that could hold the transaction is finalized, so
+                    // one more look through history is conclusive.
+                    return Ok(self.signature_outcome(&signature, true)?.unwrap_or(SendOutcome::Expired {
+                        signature,
+                        lifetime,
+                    }));
+                }
+                Ok((None, false)) => {
+                    delay_ms = self.retry_config.base_delay_ms;
+                    poll_errors = 0;
+                }
+                Err(e) => {
+                    poll_errors += 1;
+                    if poll_errors >= self.retry_config.max_poll_errors {
+                        return Err(BubblegumError::TransactionUnknown {
+                            signature: signature.to_string(),
+                            reason: format!("gave up after {} failed status polls, it may still land: {}", poll_errors, e),
+                        });
+                    }
+                    warn!("Failed to poll transaction {}: {}. Retrying in {}ms...", signature, e, delay_ms);
+                    delay_ms = (delay_ms * 2).min(self.retry_config.max_delay_ms);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(BubblegumError::TransactionUnknown {
+                    signature: signature.to_string(),
+                    reason: format!("not confirmed within {}ms, it may still land", self.retry_config.confirm_timeout_ms),
+                });
+            }
+
+            // Blocking like the RPC client itself; callers run on a dirty IO
+            // scheduler outside of any tokio runtime.
+            std::thread::sleep(Duration::from_millis(delay_ms));
+        }
+    }
+
+    /// Whether a transaction with `lifetime` can no longer land. Judged at
+    /// finalized commitment, so the answer cannot be rolled back.
+    fn is_expired(&self, lifetime: &TransactionLifetime) -> Result<bool, BubblegumError> {
+        match lifetime {
+            TransactionLifetime::BlockHeight(last_valid_block_height) => {
+                let height = self.client
+                    .get_block_height_with_commitment(CommitmentConfig::finalized())
+                    .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+                Ok(height > *last_valid_block_height)
+            }
+            TransactionLifetime::Nonce { account, nonce } => {
+                Ok(self.nonce_data(account, CommitmentConfig::finalized())?.blockhash() != *nonce)
+            }
+        }
+    }
+
+    /// The outcome of `signature` if it has landed, confirmed at the client's
+    /// commitment or with an error.
+    fn signature_outcome(
+        &self,
+        signature: &Signature,
+        search_history: bool,
+    ) -> Result<Option<SendOutcome>, BubblegumError> {
+        let statuses = if search_history {
+            self.client.get_signature_statuses_with_history(&[*signature])
+        } else {
+            self.client.get_signature_statuses(&[*signature])
+        }
+        .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+
+        let status = match statuses.value.into_iter().next().flatten() {
+            Some(status) => status,
+            None => return Ok(None),
+        };
+        if let Some(err) = status.err {
+            return Ok(Some(SendOutcome::Failed { signature: *signature, error: err.to_string() }));
+        }
+        if status.satisfies_commitment(self.client.commitment()) {
+            return Ok(Some(SendOutcome::Landed { signature: *signature, slot: status.slot }));
+        }
+        Ok(None)
+    }
+
+    /// The registered lookup tables of the trees `instructions` touch.
+    fn lookup_tables_for(&self, instructions: &[Instruction]) -> Vec<AddressLookupTableAccount> {
+        let mut tables: Vec<AddressLookupTableAccount> = Vec::new();
+        for meta in instructions.iter().flat_map(|ix| ix.accounts.iter()) {
+            if let Some(table) = self.lookup_tables.get(&meta.pubkey) {
+                if !tables.iter().any(|known| known.key == table.key) {
+                    tables.push(table.clone());
+                }
+            }
+        }
+        tables
+    }
+
+    /// Reads the addresses a lookup table holds.
+    pub fn load_lookup_table(&self, table: &Pubkey) -> Result<AddressLookupTableAccount, BubblegumError> {
+        let data = self.client
+            .get_account_data(table)
+            .map_err(|e| BubblegumError::RpcError(format!("Failed to fetch lookup table {}: {}", table, e)))?;
+        let state = AddressLookupTable::deserialize(&data)
+            .map_err(|e| BubblegumError::DecodingError(format!("Invalid lookup table {}: {}", table, e)))?;
+
+        Ok(AddressLookupTableAccount {
+            key: *table,
+            addresses: state.addresses.to_vec(),
+        })
+    }
+
+    /// Uses `table` for the transactions of `merkle_tree` from now on.
+    pub fn register_lookup_table(&self, merkle_tree: &Pubkey, table: &Pubkey) -> Result<(), BubblegumError> {
+        let account = self.load_lookup_table(table)?;
+        self.lookup_tables.insert(*merkle_tree, account);
+        Ok(())
+    }
+
+    pub fn lookup_table(&self, merkle_tree: &Pubkey) -> Option<Pubkey> {
+        self.lookup_tables.get(merkle_tree).map(|table| table.key)
+    }
+
+    /// Creates a lookup table owned by `authority` holding `addresses`.
+    pub async fn create_lookup_table(
+        &self,
+        authority: &Keypair,
+        payer: &Keypair,
+        addresses: &[Pubkey],
+    ) -> Result<Pubkey, BubblegumError> {
+        let recent_slot = self.client
+            .get_slot()
+            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+        let (ix, table) = lookup_table_ix::create_lookup_table(authority.pubkey(), payer.pubkey(), recent_slot);
+
+        self.send_instructions(&[ix], &payer.pubkey(), &[payer], &self.compute_budget).await?.into_result()?;
+        info!("Created lookup table {}", table);
+        self.extend_lookup_table(&table, authority, payer, addresses).await?;
+        Ok(table)
+    }
+
+    /// Adds the `addresses` that `table` does not hold yet. Returns how many
+    /// were added.
+    pub async fn extend_lookup_table(
+        &self,
+        table: &Pubkey,
+        authority: &Keypair,
+        payer: &Keypair,
+        addresses: &[Pubkey],
+    ) -> Result<usize, BubblegumError> {
+        let existing = self.load_lookup_table(table)?.addresses;
+        let mut seen: HashSet<Pubkey> = existing.into_iter().collect();
+        let missing: Vec<Pubkey> = addresses.iter().filter(|address| seen.insert(**address)).copied().collect();
+
+        let mut signers = vec![payer];
+        if authority.pubkey() != payer.pubkey() {
+            signers.push(authority);
+        }
+        for chunk in missing.chunks(LOOKUP_TABLE_EXTEND_CHUNK) {
+            let ix = lookup_table_ix::extend_lookup_table(*table, authority.pubkey(), Some(payer.pubkey()), chunk.to_vec());
+            self.send_instructions(&[ix], &payer.pubkey(), &signers, &self.compute_budget).await?.into_result()?;
+        }
+        Ok(missing.len())
+    }
+
+    /// Makes sure `merkle_tree` has a registered lookup table holding its
+    /// static accounts, creating one or extending the registered one. New
+    /// addresses become usable a slot after they are added.
+    pub async fn ensure_tree_lookup_table(
+        &self,
+        merkle_tree: &Pubkey,
+        authority: &Keypair,
+        payer: &Keypair,
+    ) -> Result<Pubkey, BubblegumError> {
+        let addresses = tree_lookup_addresses(merkle_tree);
+        let table = match self.lookup_table(merkle_tree) {
+            Some(table) => {
+                self.extend_lookup_table(&table, authority, payer, &addresses).await?;
+                table
+            }
+            None => self.create_lookup_table(authority, payer, &addresses).await?,
+        };
+        self.register_lookup_table(merkle_tree, &table)?;
+        Ok(table)
+    }
+
+    fn nonce_data(&self, account: &Pubkey, commitment: CommitmentConfig) -> Result<NonceData, BubblegumError> {
+        let account_data = nonce_utils::get_account_with_commitment(&self.client, account, commitment)
+            .map_err(|e| BubblegumError::RpcError(format!("Failed to fetch nonce account {}: {}", account, e)))?;
+        nonce_utils::data_from_account(&account_data)
+            .map_err(|e| BubblegumError::DecodingError(format!("Invalid nonce account {}: {}", account, e)))
+    }
+
+    /// The authority and current durable nonce of `account`.
+    pub fn nonce_info(&self, account: &Pubkey) -> Result<NonceData, BubblegumError> {
+        self.nonce_data(account, self.client.commitment())
+    }
+
+    /// Creates `nonce_account` as a durable nonce account advanced by `authority`.
+    pub async fn create_nonce_account(
+        &self,
+        payer: &Keypair,
+        nonce_account: &Keypair,
+        authority: &Pubkey,
     ) -> Result<Signature, BubblegumError> {
+        let rent = self.client
+            .get_minimum_balance_for_rent_exemption(nonce::State::size())
+            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+        let ixs = system_instruction::create_nonce_account(&payer.pubkey(), &nonce_account.pubkey(), authority, rent);
+
+        let signature = self.send_instructions(&ixs, &payer.pubkey(), &[payer, nonce_account], &self.compute_budget)
+            .await?
+            .into_result()?;
+        info!("Created nonce account {}", nonce_account.pubkey());
+        Ok(signature)
+    }
+
+    /// An unsigned transaction running `instructions` on the durable nonce of
+    /// `nonce_account`, so it can be signed offline and sent any time until
+    /// the nonce is advanced. `advance_nonce_account` comes first, as the
+    /// runtime requires, then the ComputeBudget instructions `config` calls for.
+    pub fn build_nonce_transaction(
+        &self,
+        instructions: &[Instruction],
+        payer: &Pubkey,
+        nonce_account: &Pubkey,
+        config: &ComputeBudgetConfig,
+    ) -> Result<(VersionedTransaction, TransactionLifetime), BubblegumError> {
+        let nonce_data = self.nonce_info(nonce_account)?;
+        let lookup_tables = self.lookup_tables_for(instructions);
+
+        let mut all = vec![system_instruction::advance_nonce_account(nonce_account, &nonce_data.authority)];
+        all.extend(self.compute_budget_instructions(instructions, payer, config, &lookup_tables)?);
+        all.extend_from_slice(instructions);
+
+        let message = compile_message(&all, payer, nonce_data.blockhash(), &lookup_tables)?;
+        check_transaction_size(&message, !lookup_tables.is_empty())?;
+        let lifetime = TransactionLifetime::Nonce { account: *nonce_account, nonce: nonce_data.blockhash() };
+        Ok((unsigned_transaction(message), lifetime))
+    }
+
+    /// An unsigned transaction running `instructions` on a recent blockhash,
+    /// for signing elsewhere before its lifetime runs out.
+    pub fn build_transaction(
+        &self,
+        instructions: &[Instruction],
+        payer: &Pubkey,
+        config: &ComputeBudgetConfig,
+    ) -> Result<(VersionedTransaction, TransactionLifetime), BubblegumError> {
+        let lookup_tables = self.lookup_tables_for(instructions);
+        let mut all = self.compute_budget_instructions(instructions, payer, config, &lookup_tables)?;
+        all.extend_from_slice(instructions);
+
+        let (recent_blockhash, last_valid_block_height) = self.client
+            .get_latest_blockhash_with_commitment(self.client.commitment())
+            .map_err(|e| BubblegumError::RpcError(format!("Failed to get blockhash: {}", e)))?;
+        let message = compile_message(&all, payer, recent_blockhash, &lookup_tables)?;
+        check_transaction_size(&message, !lookup_tables.is_empty())?;
+        Ok((unsigned_transaction(message), TransactionLifetime::BlockHeight(last_valid_block_height)))
+    }
+
+    /// The lifetime of a transaction built elsewhere. A blockhash one is
+    /// bounded by the current block height plus the blockhash's maximum
+    /// age, which may run past its actual expiry but never ends before it.
+    pub fn transaction_lifetime(&self, tx: &VersionedTransaction) -> Result<TransactionLifetime, BubblegumError> {
+        let blockhash = *tx.message.recent_blockhash();
+        if let Some(account) = durable_nonce_account(tx) {
+            return Ok(TransactionLifetime::Nonce { account, nonce: blockhash });
+        }
+
+        let commitment = CommitmentConfig::processed();
+        let height = self.client
+            .get_block_height_with_commitment(commitment)
+            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+        let valid = self.client
+            .is_blockhash_valid(&blockhash, commitment)
+            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+        if !valid {
+            return Err(BubblegumError::TransactionError(format!("Blockhash {} has expired", blockhash)));
+        }
+        Ok(TransactionLifetime::BlockHeight(height + MAX_PROCESSING_AGE as u64))
+    }
+
+    /// Sends a transaction signed elsewhere once every signer has signed.
+    pub async fn submit_signed(&self, tx: &VersionedTransaction) -> Result<SendOutcome, BubblegumError> {
+        let missing = missing_signers(tx);
+        if !missing.is_empty() {
+            let missing: Vec<String> = missing.iter().map(Pubkey::to_string).collect();
+            return Err(BubblegumError::TransactionError(
+                format!("Transaction is missing signatures from {}", missing.join(", "))
+            ));
+        }
+        if tx.verify_with_results().iter().any(|valid| !valid) {
+            return Err(BubblegumError::TransactionError("Transaction has an invalid signature".to_string()));
+        }
+
+        let lifetime = self.transaction_lifetime(tx)?;
+        self.simulate_and_send(tx, lifetime).await
+    }
+
+    /// Simulates a transaction signed elsewhere, then sends it as `submit`
+    /// does. It cannot be re-signed, so it is reported expired once its
+    /// `lifetime` runs out.
+    pub async fn simulate_and_send(
+        &self,
+        tx: &VersionedTransaction,
+        lifetime: TransactionLifetime,
+    ) -> Result<SendOutcome, BubblegumError> {
         if self.simulation_enabled {
             info!("Simulating transaction...");
             let simulation = self.client
@@ -70,41 +801,7 @@ impl TransactionManager {
             }
         }
 
-        self.send_with_retry(tx, signers).await
-    }
-
-    async fn send_with_retry(
-        &self,
-        tx: &Transaction,
-        signers: &[&Keypair],
-    ) -> Result<Signature, BubblegumError> {
-        let mut attempt = 0;
-        let mut delay_ms = self.retry_config.base_delay_ms;
-
-        loop {
-            attempt += 1;
-            match self.client.send_and_confirm_transaction_with_spinner(tx) {
-                Ok(signature) => {
-                    info!("Transaction successful: {}", signature);
-                    return Ok(signature);
-                }
-                Err(err) => {
-                    if attempt >= self.retry_config.max_attempts {
-                        return Err(BubblegumError::TransactionError(
-                            format!("Transaction failed after {} attempts: {}", attempt, err)
-                        ));
-                    }
-
-                    warn!(
-                        "Transaction attempt {} failed: {}. Retrying in {}ms...",
-                        attempt, err, delay_ms
-                    );
-
-                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
-                    delay_ms = (delay_ms * 2).min(self.retry_config.max_delay_ms);
-                }
-            }
-        }
+        self.submit(tx, lifetime)
     }
 
     pub async fn mint_to_collection(
@@ -127,17 +824,127 @@ impl TransactionManager {
             metadata,
         ).map_err(|e| BubblegumError::TransactionError(e.to_string()))?;
 
-        let recent_blockhash = self.client
-            .get_latest_blockhash()
-            .map_err(|e| BubblegumError::RpcError(e.to_string()))?;
+        self.send_instructions(&[ix], &payer.pubkey(), &[payer], &self.compute_budget).await?.into_result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_percentile() {
+        let fees = vec![50, 10, 0, 40, 20, 30, 0, 0, 100, 60];
+        assert_eq!(fee_percentile(fees.clone(), 0), 0);
+        assert_eq!(fee_percentile(fees.clone(), 50), 20);
+        assert_eq!(fee_percentile(fees.clone(), 75), 50);
+        assert_eq!(fee_percentile(fees.clone(), 100), 100);
+        assert_eq!(fee_percentile(fees, 200), 100);
+        assert_eq!(fee_percentile(Vec::new(), 50), 0);
+    }
+
+    #[test]
+    fn test_v0_message_when_legacy_overflows() {
+        let payer = Pubkey::new_unique();
+        let table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: (0..40).map(|_| Pubkey::new_unique()).collect(),
+        };
+        let accounts = table.addresses.iter()
+            .map(|address| solana_sdk::instruction::AccountMeta::new_readonly(*address, false))
+            .collect();
+        let ix = Instruction::new_with_bytes(mpl_bubblegum::ID, &[0; 8], accounts);
+
+        // 40 looked up accounts take 40 bytes instead of 1280.
+        let message = compile_message(&[ix.clone()], &payer, Hash::default(), &[table.clone()]).unwrap();
+        assert!(matches!(message, VersionedMessage::V0(_)));
+        assert!(transaction_size(&message) <= PACKET_DATA_SIZE);
+        let legacy = compile_message(&[ix.clone()], &payer, Hash::default(), &[]).unwrap();
+        assert!(matches!(legacy, VersionedMessage::Legacy(_)));
+        assert!(transaction_size(&legacy) > PACKET_DATA_SIZE);
+
+        // Small transactions stay legacy.
+        let small = Instruction::new_with_bytes(mpl_bubblegum::ID, &[0; 8], Vec::new());
+        let message = compile_message(&[small], &payer, Hash::default(), &[table]).unwrap();
+        assert!(matches!(message, VersionedMessage::Legacy(_)));
+    }
+
+    #[test]
+    fn test_transfer_with_full_proof_fits() {
+        let mut tree = crate::merkle::MerkleTree::new(14, 64).unwrap();
+        let leaf = crate::merkle::Node::new([1; 32]);
+        let index = tree.append(leaf).unwrap();
+        let proof = tree.get_proof(index).unwrap();
+        assert_eq!(proof.len(), 14);
+
+        let merkle_tree = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut ix = mpl_bubblegum::instructions::transfer(
+            &get_tree_authority(&merkle_tree),
+            &owner,
+            &Pubkey::new_unique(),
+            &merkle_tree,
+            tree.get_proof_with_root(index).unwrap().1.as_ref(),
+            leaf.as_ref(),
+            leaf.as_ref(),
+            index as u64,
+            index,
+        ).unwrap();
+        let proof_accounts = crate::merkle::proof_to_remaining_accounts(&proof);
+        ix.accounts.extend(proof_accounts.clone());
+
+        let mut ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(MAX_COMPUTE_UNIT_LIMIT),
+            ComputeBudgetInstruction::set_compute_unit_price(1),
+        ];
+        ixs.push(ix);
+        let table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: tree_lookup_addresses(&merkle_tree),
+        };
+        let message = compile_message(&ixs, &owner, Hash::default(), &[table]).unwrap();
+        check_transaction_size(&message, true).unwrap();
+
+        // Every proof node reaches the transaction, in order, after the
+        // instruction's own accounts.
+        let keys = message.static_account_keys();
+        let compiled = message.instructions().last().unwrap();
+        let tail: Vec<Pubkey> = compiled.accounts[compiled.accounts.len() - 14..]
+            .iter()
+            .map(|&key| keys[key as usize])
+            .collect();
+        let expected: Vec<Pubkey> = proof_accounts.iter().map(|meta| meta.pubkey).collect();
+        assert_eq!(tail, expected);
+    }
+
+    #[test]
+    fn test_unit_limit_with_margin() {
+        assert_eq!(unit_limit_with_margin(100_000, 10), 110_000);
+        assert_eq!(unit_limit_with_margin(33_333, 10), 36_667);
+        assert_eq!(unit_limit_with_margin(5_000, 0), 5_000);
+        assert_eq!(unit_limit_with_margin(1_300_000, 20), MAX_COMPUTE_UNIT_LIMIT);
+    }
+
+    #[test]
+    fn test_partially_sign() {
+        let payer = Keypair::new();
+        let authority = Keypair::new();
+        let nonce_account = Pubkey::new_unique();
+        let ixs = [
+            system_instruction::advance_nonce_account(&nonce_account, &authority.pubkey()),
+            system_instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), 1),
+        ];
+        let message = compile_message(&ixs, &payer.pubkey(), Hash::new_unique(), &[]).unwrap();
+        let mut tx = unsigned_transaction(message);
+        assert_eq!(durable_nonce_account(&tx), Some(nonce_account));
+        assert_eq!(missing_signers(&tx), vec![payer.pubkey(), authority.pubkey()]);
 
-        let tx = Transaction::new_signed_with_payer(
-            &[ix],
-            Some(&payer.pubkey()),
-            &[payer],
-            recent_blockhash,
-        );
+        partially_sign(&mut tx, &authority).unwrap();
+        assert_eq!(missing_signers(&tx), vec![payer.pubkey()]);
+        assert!(partially_sign(&mut tx, &Keypair::new()).is_err());
 
-        self.simulate_and_send(&tx, &[payer]).await
+        partially_sign(&mut tx, &payer).unwrap();
+        assert!(missing_signers(&tx).is_empty());
+        assert!(tx.verify_with_results().iter().all(|valid| *valid));
     }
 } 
\ No newline at end of file
@@ -0,0 +1,294 @@
+use crate::merkle::{is_valid_depth_size_pair, ChangeLog, Node, Path, EMPTY_NODE};
+use solana_program::pubkey::Pubkey;
+
+/// Size of the account type byte, the header version byte and the V1 header.
+const CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1: usize = 2 + 54;
+
+const ACCOUNT_TYPE_CONCURRENT_MERKLE_TREE: u8 = 1;
+const HEADER_VERSION_V1: u8 = 0;
+
+/// A spl-account-compression merkle tree account, decoded from its raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeAccount {
+    pub max_buffer_size: u32,
+    pub max_depth: u32,
+    pub authority: Pubkey,
+    pub creation_slot: u64,
+    pub sequence_number: u64,
+    pub active_index: u64,
+    pub buffer_size: u64,
+    pub change_logs: Vec<ChangeLog>,
+    pub rightmost_proof: Path,
+    /// Cached upper levels in the program's order: level `max_depth - 1` first,
+    /// left to right. Nodes never written are all zeros.
+    pub canopy: Vec<Node>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or("Account data is too short")?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn node(&mut self) -> Result<Node, &'static str> {
+        Ok(Node::new(self.take(32)?.try_into().unwrap()))
+    }
+
+    fn nodes(&mut self, count: usize) -> Result<Vec<Node>, &'static str> {
+        (0..count).map(|_| self.node()).collect()
+    }
+}
+
+impl TreeAccount {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 {
+            return Err("Account data is too short");
+        }
+        let mut reader = Reader { data, offset: 0 };
+
+        if reader.u8()? != ACCOUNT_TYPE_CONCURRENT_MERKLE_TREE {
+            return Err("Account is not an initialized concurrent merkle tree");
+        }
+        if reader.u8()? != HEADER_VERSION_V1 {
+            return Err("Unsupported concurrent merkle tree header version");
+        }
+        let max_buffer_size = reader.u32()?;
+        let max_depth = reader.u32()?;
+        let authority = Pubkey::new_from_array(reader.node()?.to_bytes());
+        let creation_slot = reader.u64()?;
+        reader.take(6)?;
+
+        if !is_valid_depth_size_pair(max_depth, max_buffer_size) {
+            return Err("Unsupported max_depth/max_buffer_size pair");
+        }
+        let depth = max_depth as usize;
+
+        let sequence_number = reader.u64()?;
+        let active_index = reader.u64()?;
+        let buffer_size = reader.u64()?;
+        let change_logs = (0..max_buffer_size)
+            .map(|_| {
+                let root = reader.node()?;
+                let path = reader.nodes(depth)?;
+                let index = reader.u32()?;
+                reader.take(4)?;
+                Ok(ChangeLog { root, path, index })
+            })
+            .collect::<Result<Vec<_>, &'static str>>()?;
+        let proof = reader.nodes(depth)?;
+        let leaf = reader.node()?;
+        let index = reader.u32()?;
+        reader.take(4)?;
+
+        if active_index >= max_buffer_size as u64 || buffer_size > max_buffer_size as u64 {
+            return Err("Changelog counters exceed the buffer size");
+        }
+
+        // Whatever follows the tree is the canopy: a full binary tree without its root.
+        let canopy_bytes = &data[reader.offset..];
+        if canopy_bytes.len() % 32 != 0 {
+            return Err("Canopy length is not a multiple of 32");
+        }
+        let canopy_len = canopy_bytes.len() / 32;
+        if !(canopy_len + 2).is_power_of_two() || canopy_len + 2 > 2usize << max_depth {
+            return Err("Canopy length does not describe whole tree levels");
+        }
+        let canopy = reader.nodes(canopy_len)?;
+
+        Ok(Self {
+            max_buffer_size,
+            max_depth,
+            authority,
+            creation_slot,
+            sequence_number,
+            active_index,
+            buffer_size,
+            change_logs,
+            rightmost_proof: Path { proof, leaf, index },
+            canopy,
+        })
+    }
+
+    pub fn root(&self) -> Node {
+        self.change_logs[self.active_index as usize].root
+    }
+
+    pub fn num_leaves(&self) -> u32 {
+        self.rightmost_proof.index
+    }
+
+    pub fn canopy_depth(&self) -> u32 {
+        (self.canopy.len() + 2).trailing_zeros() - 1
+    }
+
+    /// Canopy node at (level, index) as stored on chain, or `None` if that
+    /// level is not cached or the node was never written.
+    pub fn canopy_node(&self, level: u32, index: u32) -> Option<Node> {
+        if level >= self.max_depth || level < self.max_depth - self.canopy_depth() {
+            return None;
+        }
+        let position = (1usize << (self.max_depth - level)) + index as usize - 2;
+        self.canopy
+            .get(position)
+            .copied()
+            .filter(|node| *node != EMPTY_NODE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::MerkleTree;
+    use solana_program::keccak;
+
+    fn leaf(i: u32) -> Node {
+        Node::new(keccak::hash(&i.to_le_bytes()).to_bytes())
+    }
+
+    /// Replays the operations the 14/64 fixture was generated from.
+    fn local_tree_14_64() -> MerkleTree {
+        let mut tree = MerkleTree::new(14, 64).unwrap();
+        for i in 0..37 {
+            tree.append(leaf(i)).unwrap();
+        }
+        tree.replace_leaf(5, leaf(100)).unwrap();
+        tree.remove_leaf(36).unwrap();
+        for i in 37..40 {
+            tree.append(leaf(i)).unwrap();
+        }
+        tree
+    }
+
+    // Account bytes generated with spl-concurrent-merkle-tree 0.2.0: the Pod tree
+    // struct behind a V1 header (authority [7; 32], creation slot 123456), with the
+    // canopy maintained from each changelog as the program's update_canopy does.
+    //   tree_14_64_canopy_5: appends of keccak(i.to_le_bytes()) for i in 0..37,
+    //     leaf 5 set to keccak(100), leaf 36 cleared, then appends for 37..40.
+    //   tree_3_8: appends for 0..8, then leaf 2 set to keccak(100).
+    //   tree_5_8_empty: freshly initialized, canopy depth 2.
+    const TREE_14_64_CANOPY_5: &[u8] = include_bytes!("../tests/fixtures/tree_14_64_canopy_5.bin");
+    const TREE_3_8: &[u8] = include_bytes!("../tests/fixtures/tree_3_8.bin");
+    const TREE_5_8_EMPTY: &[u8] = include_bytes!("../tests/fixtures/tree_5_8_empty.bin");
+
+    #[test]
+    fn test_parse_header_and_counters() {
+        let account = TreeAccount::parse(TREE_14_64_CANOPY_5).unwrap();
+        assert_eq!(account.max_depth, 14);
+        assert_eq!(account.max_buffer_size, 64);
+        assert_eq!(account.authority, Pubkey::new_from_array([7; 32]));
+        assert_eq!(account.creation_slot, 123_456);
+        assert_eq!(account.sequence_number, 42);
+        assert_eq!(account.active_index, 42);
+        assert_eq!(account.buffer_size, 43);
+        assert_eq!(account.num_leaves(), 40);
+        assert_eq!(account.canopy_depth(), 5);
+        assert_eq!(account.canopy.len(), 62);
+        assert_eq!(
+            account.root().to_string(),
+            "69LvCtuUApw1CjGRJhRrwYjELeQULhgJczdfD7ZDHxF8"
+        );
+
+        let account = TreeAccount::parse(TREE_3_8).unwrap();
+        assert_eq!((account.sequence_number, account.active_index, account.buffer_size), (9, 1, 8));
+        assert_eq!(account.canopy_depth(), 0);
+        assert_eq!(account.change_logs[1].index, 2);
+
+        let account = TreeAccount::parse(TREE_5_8_EMPTY).unwrap();
+        assert_eq!(account.sequence_number, 0);
+        assert_eq!(account.canopy_depth(), 2);
+        assert_eq!(account.canopy_node(4, 0), None);
+        assert_eq!(
+            account.root().to_string(),
+            "zLUDhASAn7WA1Aqc724azRpZjKCjMQNATApe74JMg8C"
+        );
+    }
+
+    #[test]
+    fn test_check_local_tree_against_account() {
+        let account = TreeAccount::parse(TREE_14_64_CANOPY_5).unwrap();
+        let mut tree = local_tree_14_64();
+        assert_eq!(tree.check_against_account(&account), Ok(()));
+        assert_eq!(tree.change_logs, account.change_logs);
+
+        tree.append(leaf(40)).unwrap();
+        assert!(tree.check_against_account(&account).is_err());
+
+        let account = TreeAccount::parse(TREE_3_8).unwrap();
+        let mut tree = MerkleTree::new(3, 8).unwrap();
+        for i in 0..8 {
+            tree.append(leaf(i)).unwrap();
+        }
+        tree.replace_leaf(2, leaf(100)).unwrap();
+        assert_eq!(tree.check_against_account(&account), Ok(()));
+
+        let account = TreeAccount::parse(TREE_5_8_EMPTY).unwrap();
+        assert_eq!(MerkleTree::new(5, 8).unwrap().check_against_account(&account), Ok(()));
+    }
+
+    #[test]
+    fn test_seed_tree_from_account() {
+        let account = TreeAccount::parse(TREE_14_64_CANOPY_5).unwrap();
+        let local = local_tree_14_64();
+        let leaves: Vec<Node> = (0..40).map(|i| local.get_leaf(i).unwrap()).collect();
+
+        let mut seeded = MerkleTree::from_tree_account(&account, &leaves).unwrap();
+        assert_eq!(seeded.check_against_account(&account), Ok(()));
+        assert_eq!(seeded.get_proof(5).unwrap(), local.get_proof(5).unwrap());
+
+        // The seeded tree carries on exactly like the one that produced the account.
+        let mut local = local;
+        assert_eq!(seeded.append(leaf(40)), local.append(leaf(40)));
+        assert_eq!(seeded.replace_leaf(3, leaf(101)), local.replace_leaf(3, leaf(101)));
+        assert_eq!(seeded.change_logs, local.change_logs);
+
+        let mut wrong = leaves.clone();
+        wrong[5] = leaf(5);
+        assert!(MerkleTree::from_tree_account(&account, &wrong).is_err());
+        assert!(MerkleTree::from_tree_account(&account, &leaves[..39]).is_err());
+
+        let account = TreeAccount::parse(TREE_5_8_EMPTY).unwrap();
+        let seeded = MerkleTree::from_tree_account(&account, &[]).unwrap();
+        assert_eq!(seeded.get_root(), account.root());
+    }
+
+    #[test]
+    fn test_rejects_malformed_accounts() {
+        let truncated = &TREE_3_8[..TREE_3_8.len() - 1];
+        assert!(TreeAccount::parse(truncated).is_err());
+        assert!(TreeAccount::parse(&TREE_3_8[..40]).is_err());
+
+        let mut uninitialized = TREE_3_8.to_vec();
+        uninitialized[0] = 0;
+        assert!(TreeAccount::parse(&uninitialized).is_err());
+
+        let mut bad_pair = TREE_3_8.to_vec();
+        bad_pair[2] = 9;
+        assert!(TreeAccount::parse(&bad_pair).is_err());
+
+        // A single extra node is not a whole canopy level.
+        let mut bad_canopy = TREE_3_8.to_vec();
+        bad_canopy.extend_from_slice(&[1; 32]);
+        assert!(TreeAccount::parse(&bad_canopy).is_err());
+    }
+}
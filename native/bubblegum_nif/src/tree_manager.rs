@@ -1,18 +1,179 @@
-use crate::merkle::MerkleTree;
+use crate::merkle::{
+    ChangeLog, HistoricalProof, MerkleTree, MultiProof, Node, ProofReplay, Subtree,
+    SUBTREE_HEIGHT,
+};
+use crate::leaf::LeafSchemaV1;
+use crate::tree_account::TreeAccount;
+use crate::wal::{LeafOp, WalEntry, WalStatus, WriteAheadLog};
 use async_trait::async_trait;
-use dashmap::DashMap;
-use solana_sdk::pubkey::Pubkey;
-use std::{sync::Arc, path::PathBuf};
+use dashmap::{mapref::one::Ref, DashMap};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+#[cfg(feature = "persistent-storage")]
+use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::sync::Mutex;
 use tracing::{info, error};
 
+// Complete subtrees untouched for this many operations are spilled to storage.
+const SUBTREE_IDLE_OPS: u64 = 1 << SUBTREE_HEIGHT;
+// Changelogs read from storage at a time while replaying history.
+const CHANGE_LOG_PAGE_SIZE: usize = 1024;
+// Changelogs `get_proof_at` replays at most unless configured otherwise.
+const DEFAULT_MAX_PROOF_REPLAY: u64 = 1 << 20;
+
+/// Parameters a managed tree was created with, stored next to its nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeDescriptor {
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+    pub canopy_depth: u32,
+    pub authority: Pubkey,
+    pub creation_slot: u64,
+    /// Address lookup table holding the tree's static accounts, if one was
+    /// created or registered for it. Not part of the tree's parameters.
+    pub lookup_table: Option<Pubkey>,
+}
+
+/// A `TreeDescriptor` as stored before it held a lookup table.
+#[cfg(any(feature = "persistent-storage", feature = "file-storage", test))]
+#[derive(Deserialize)]
+struct TreeDescriptorV1 {
+    max_depth: u32,
+    max_buffer_size: u32,
+    canopy_depth: u32,
+    authority: Pubkey,
+    creation_slot: u64,
+}
+
+/// Decodes a bincode `TreeDescriptor`, including ones stored without a
+/// lookup table.
+#[cfg(any(feature = "persistent-storage", feature = "file-storage", test))]
+pub(crate) fn decode_descriptor(bytes: &[u8]) -> anyhow::Result<TreeDescriptor> {
+    if let Ok(descriptor) = bincode::deserialize(bytes) {
+        return Ok(descriptor);
+    }
+    let v1: TreeDescriptorV1 = bincode::deserialize(bytes)?;
+    Ok(TreeDescriptor {
+        max_depth: v1.max_depth,
+        max_buffer_size: v1.max_buffer_size,
+        canopy_depth: v1.canopy_depth,
+        authority: v1.authority,
+        creation_slot: v1.creation_slot,
+        lookup_table: None,
+    })
+}
+
+impl From<&TreeAccount> for TreeDescriptor {
+    fn from(account: &TreeAccount) -> Self {
+        Self {
+            max_depth: account.max_depth,
+            max_buffer_size: account.max_buffer_size,
+            canopy_depth: account.canopy_depth(),
+            authority: account.authority,
+            creation_slot: account.creation_slot,
+            lookup_table: None,
+        }
+    }
+}
+
+/// A Bubblegum leaf as indexed by storage backends that support asset queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeafRecord {
+    pub index: u32,
+    pub asset_id: Pubkey,
+    pub owner: Pubkey,
+    pub delegate: Pubkey,
+    pub nonce: u64,
+    pub data_hash: Node,
+    pub creator_hash: Node,
+    pub collection: Option<Pubkey>,
+}
+
+impl LeafRecord {
+    pub fn new(index: u32, leaf: &LeafSchemaV1, collection: Option<Pubkey>) -> Self {
+        Self {
+            index,
+            asset_id: leaf.id,
+            owner: leaf.owner,
+            delegate: leaf.delegate,
+            nonce: leaf.nonce,
+            data_hash: Node::new(leaf.data_hash),
+            creator_hash: Node::new(leaf.creator_hash),
+            collection,
+        }
+    }
+}
+
 #[async_trait]
 pub trait TreeStorage: Send + Sync {
-    async fn load_tree(&self, authority: &Pubkey) -> anyhow::Result<Option<MerkleTree>>;
-    async fn save_tree(&self, authority: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()>;
-    async fn delete_tree(&self, authority: &Pubkey) -> anyhow::Result<()>;
+    async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>>;
+    /// Persists everything changed since the tree was last marked saved, including
+    /// the changelogs it queued for history, as one atomic write.
+    async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()>;
+    async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()>;
+    async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()>;
+    async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>>;
+    /// Every stored tree with its descriptor.
+    async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>>;
+    async fn save_subtree(&self, merkle_tree: &Pubkey, subtree: &Subtree) -> anyhow::Result<()>;
+    async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>>;
+    /// Returns up to `limit` stored changelogs from sequence number `from_seq` onwards, in order.
+    async fn load_change_logs(
+        &self,
+        merkle_tree: &Pubkey,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(u64, ChangeLog)>>;
+
+    /// Stores the leaf at `record.index`, replacing any earlier record for it.
+    /// Backends without an asset index ignore it.
+    async fn save_leaf_record(&self, _merkle_tree: &Pubkey, _record: &LeafRecord) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Recorded leaves of `merkle_tree` owned by `owner`, by index.
+    async fn leaves_by_owner(&self, _merkle_tree: &Pubkey, _owner: &Pubkey) -> anyhow::Result<Vec<LeafRecord>> {
+        anyhow::bail!("This storage backend does not index leaves")
+    }
+
+    /// Recorded leaves of `merkle_tree` in `collection`, by index.
+    async fn leaves_by_collection(
+        &self,
+        _merkle_tree: &Pubkey,
+        _collection: &Pubkey,
+    ) -> anyhow::Result<Vec<LeafRecord>> {
+        anyhow::bail!("This storage backend does not index leaves")
+    }
 }
 
+#[cfg(feature = "persistent-storage")]
+const LEAVES_CF: &str = "leaves";
+#[cfg(feature = "persistent-storage")]
+const NODES_CF: &str = "nodes";
+#[cfg(feature = "persistent-storage")]
+const METADATA_CF: &str = "metadata";
+
+// Metadata keys are a tag, the tree address, then a big-endian suffix.
+#[cfg(feature = "persistent-storage")]
+const DESCRIPTOR_TAG: u8 = 0;
+#[cfg(feature = "persistent-storage")]
+const HEADER_TAG: u8 = 1;
+#[cfg(feature = "persistent-storage")]
+const BUFFER_SLOT_TAG: u8 = 2;
+#[cfg(feature = "persistent-storage")]
+const CHANGE_LOG_TAG: u8 = 3;
+
+/// Stores each tree incrementally: leaves and interior nodes under one key per
+/// node, and the header, changelog buffer slots, history and descriptor as
+/// metadata. Every save is a single write batch.
 #[cfg(feature = "persistent-storage")]
 pub struct RocksDBStorage {
     db: Arc<rocksdb::DB>,
@@ -21,36 +182,282 @@ pub struct RocksDBStorage {
 #[cfg(feature = "persistent-storage")]
 impl RocksDBStorage {
     pub fn new(path: PathBuf) -> anyhow::Result<Self> {
-        let db = rocksdb::DB::open_default(path)?;
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let db = rocksdb::DB::open_cf(&options, path, [LEAVES_CF, NODES_CF, METADATA_CF])?;
         Ok(Self { db: Arc::new(db) })
     }
+
+    fn cf(&self, name: &str) -> anyhow::Result<&rocksdb::ColumnFamily> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow::anyhow!("Missing column family {}", name))
+    }
+
+    /// Values of every key starting with `prefix`, with the rest of the key.
+    fn scan(&self, cf: &rocksdb::ColumnFamily, prefix: &[u8]) -> anyhow::Result<Vec<(Vec<u8>, Box<[u8]>)>> {
+        self.scan_from(cf, prefix, prefix)
+    }
+
+    /// Like `scan`, but starting at key `from`.
+    fn scan_from(
+        &self,
+        cf: &rocksdb::ColumnFamily,
+        prefix: &[u8],
+        from: &[u8],
+    ) -> anyhow::Result<Vec<(Vec<u8>, Box<[u8]>)>> {
+        let mode = rocksdb::IteratorMode::From(from, rocksdb::Direction::Forward);
+        let mut entries = Vec::new();
+        for entry in self.db.iterator_cf(cf, mode) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key[prefix.len()..].to_vec(), value));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(feature = "persistent-storage")]
+fn leaf_key(merkle_tree: &Pubkey, index: u32) -> Vec<u8> {
+    [merkle_tree.as_ref(), &index.to_be_bytes()].concat()
+}
+
+#[cfg(feature = "persistent-storage")]
+fn node_key(merkle_tree: &Pubkey, level: u32, index: u32) -> Vec<u8> {
+    [merkle_tree.as_ref(), &level.to_be_bytes(), &index.to_be_bytes()].concat()
+}
+
+#[cfg(feature = "persistent-storage")]
+fn metadata_key(tag: u8, merkle_tree: &Pubkey, suffix: &[u8]) -> Vec<u8> {
+    [&[tag][..], merkle_tree.as_ref(), suffix].concat()
+}
+
+/// First key past every key that starts with `prefix` and has at most 8 more bytes.
+#[cfg(feature = "persistent-storage")]
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    [prefix, &[0xff; 9]].concat()
 }
 
 #[cfg(feature = "persistent-storage")]
 #[async_trait]
 impl TreeStorage for RocksDBStorage {
-    async fn load_tree(&self, authority: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
-        let key = authority.to_bytes();
-        Ok(self.db.get(&key)?
-            .map(|bytes| bincode::deserialize(&bytes))
+    async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
+        let metadata = self.cf(METADATA_CF)?;
+        let header = match self.db.get_cf(metadata, metadata_key(HEADER_TAG, merkle_tree, &[]))? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let change_logs = self
+            .scan(metadata, &metadata_key(BUFFER_SLOT_TAG, merkle_tree, &[]))?
+            .into_iter()
+            .map(|(slot, value)| Ok((u32::from_be_bytes(slot[..].try_into()?), bincode::deserialize(&value)?)))
+            .collect::<anyhow::Result<Vec<(u32, ChangeLog)>>>()?;
+
+        // Deep trees only load the levels above their subtrees and the subtree
+        // being appended to; complete subtrees are paged in by `load_subtree`.
+        let append_subtree = MerkleTree::append_subtree(&header)
+            .map_err(|e| anyhow::anyhow!("Failed to load tree {}: {}", merkle_tree, e))?;
+        let mut nodes = Vec::new();
+        let upper_nodes = match append_subtree {
+            Some(subtree) => {
+                let start = node_key(merkle_tree, SUBTREE_HEIGHT, 0);
+                if let Some(stored) = self.load_subtree(merkle_tree, subtree).await? {
+                    nodes.extend(stored.nodes);
+                }
+                self.scan_from(self.cf(NODES_CF)?, merkle_tree.as_ref(), &start)?
+            }
+            None => {
+                for (key, value) in self.scan(self.cf(LEAVES_CF)?, merkle_tree.as_ref())? {
+                    let index = u32::from_be_bytes(key[..].try_into()?);
+                    nodes.push((0, index, Node::try_from(&value[..])?));
+                }
+                self.scan(self.cf(NODES_CF)?, merkle_tree.as_ref())?
+            }
+        };
+        for (key, value) in upper_nodes {
+            let level = u32::from_be_bytes(key[..4].try_into()?);
+            let index = u32::from_be_bytes(key[4..].try_into()?);
+            nodes.push((level, index, Node::try_from(&value[..])?));
+        }
+
+        let mut tree = MerkleTree::from_parts(&header, change_logs, nodes)
+            .map_err(|e| anyhow::anyhow!("Failed to load tree {}: {}", merkle_tree, e))?;
+        if append_subtree.is_some() {
+            tree.spill_complete_subtrees();
+        }
+        Ok(Some(tree))
+    }
+
+    async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
+        let leaves = self.cf(LEAVES_CF)?;
+        let nodes = self.cf(NODES_CF)?;
+        let metadata = self.cf(METADATA_CF)?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        for (level, index, node) in tree.dirty_nodes() {
+            let (cf, key) = if level == 0 {
+                (leaves, leaf_key(merkle_tree, index))
+            } else {
+                (nodes, node_key(merkle_tree, level, index))
+            };
+            match node {
+                Some(node) => batch.put_cf(cf, key, node),
+                None => batch.delete_cf(cf, key),
+            }
+        }
+        for (slot, change_log) in tree.dirty_change_logs() {
+            let key = metadata_key(BUFFER_SLOT_TAG, merkle_tree, &slot.to_be_bytes());
+            batch.put_cf(metadata, key, bincode::serialize(change_log)?);
+        }
+        for (seq, change_log) in tree.unsaved_change_logs() {
+            let key = metadata_key(CHANGE_LOG_TAG, merkle_tree, &seq.to_be_bytes());
+            batch.put_cf(metadata, key, bincode::serialize(change_log)?);
+        }
+        batch.put_cf(metadata, metadata_key(HEADER_TAG, merkle_tree, &[]), tree.header_snapshot()?);
+
+        Ok(self.db.write(batch)?)
+    }
+
+    async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()> {
+        let metadata = self.cf(METADATA_CF)?;
+        let mut batch = rocksdb::WriteBatch::default();
+        for cf in [self.cf(LEAVES_CF)?, self.cf(NODES_CF)?] {
+            batch.delete_range_cf(cf, merkle_tree.to_bytes().to_vec(), prefix_end(merkle_tree.as_ref()));
+        }
+        for tag in [DESCRIPTOR_TAG, HEADER_TAG, BUFFER_SLOT_TAG, CHANGE_LOG_TAG] {
+            let prefix = metadata_key(tag, merkle_tree, &[]);
+            let end = prefix_end(&prefix);
+            batch.delete_range_cf(metadata, prefix, end);
+        }
+        Ok(self.db.write(batch)?)
+    }
+
+    async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()> {
+        let key = metadata_key(DESCRIPTOR_TAG, merkle_tree, &[]);
+        Ok(self.db.put_cf(self.cf(METADATA_CF)?, key, bincode::serialize(descriptor)?)?)
+    }
+
+    async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+        let key = metadata_key(DESCRIPTOR_TAG, merkle_tree, &[]);
+        Ok(self.db.get_cf(self.cf(METADATA_CF)?, key)?
+            .map(|bytes| decode_descriptor(&bytes))
             .transpose()?)
     }
 
-    async fn save_tree(&self, authority: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
-        let key = authority.to_bytes();
-        let value = bincode::serialize(tree)?;
-        Ok(self.db.put(&key, value)?)
+    async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+        self.scan(self.cf(METADATA_CF)?, &[DESCRIPTOR_TAG])?
+            .into_iter()
+            .map(|(merkle_tree, value)| Ok((Pubkey::try_from(&merkle_tree[..])?, decode_descriptor(&value)?)))
+            .collect()
+    }
+
+    /// Nodes are already stored one by one by `save_tree`, and `take_subtree`
+    /// only hands out saved subtrees, so there is nothing left to write.
+    async fn save_subtree(&self, _merkle_tree: &Pubkey, _subtree: &Subtree) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>> {
+        let mut nodes = Vec::new();
+        for level in 0..SUBTREE_HEIGHT {
+            let width = 1u32 << (SUBTREE_HEIGHT - level);
+            let (first, last) = (index * width, (index + 1) * width - 1);
+            let (cf, from, to) = if level == 0 {
+                (self.cf(LEAVES_CF)?, leaf_key(merkle_tree, first), leaf_key(merkle_tree, last))
+            } else {
+                (self.cf(NODES_CF)?, node_key(merkle_tree, level, first), node_key(merkle_tree, level, last))
+            };
+
+            let mode = rocksdb::IteratorMode::From(&from, rocksdb::Direction::Forward);
+            for entry in self.db.iterator_cf(cf, mode) {
+                let (key, value) = entry?;
+                if key[..] > to[..] {
+                    break;
+                }
+                let index = u32::from_be_bytes(key[key.len() - 4..].try_into()?);
+                nodes.push((level, index, Node::try_from(&value[..])?));
+            }
+        }
+        Ok(Some(Subtree { index, nodes }))
+    }
+
+    async fn load_change_logs(
+        &self,
+        merkle_tree: &Pubkey,
+        from_seq: u64,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(u64, ChangeLog)>> {
+        let metadata = self.cf(METADATA_CF)?;
+        let prefix = metadata_key(CHANGE_LOG_TAG, merkle_tree, &[]);
+        let start = metadata_key(CHANGE_LOG_TAG, merkle_tree, &from_seq.to_be_bytes());
+        let mode = rocksdb::IteratorMode::From(&start, rocksdb::Direction::Forward);
+
+        let mut change_logs = Vec::new();
+        for entry in self.db.iterator_cf(metadata, mode).take(limit) {
+            let (key, value) = entry?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let seq = u64::from_be_bytes(key[prefix.len()..].try_into()?);
+            change_logs.push((seq, bincode::deserialize(&value)?));
+        }
+        Ok(change_logs)
     }
+}
 
-    async fn delete_tree(&self, authority: &Pubkey) -> anyhow::Result<()> {
-        let key = authority.to_bytes();
-        Ok(self.db.delete(&key)?)
+fn check_descriptor(
+    merkle_tree: &Pubkey,
+    stored: &TreeDescriptor,
+    requested: &TreeDescriptor,
+) -> anyhow::Result<()> {
+    if stored != &TreeDescriptor { lookup_table: stored.lookup_table, ..*requested } {
+        anyhow::bail!(
+            "Tree {} is managed with different parameters: {:?}, requested {:?}",
+            merkle_tree,
+            stored,
+            requested
+        );
     }
+    Ok(())
+}
+
+struct ManagedTree {
+    descriptor: TreeDescriptor,
+    tree: Arc<Mutex<MerkleTree>>,
+    // Value of `TreeManager::clock` when the tree was last used.
+    last_used: AtomicU64,
+    // `approx_memory_bytes` as of the last change made through the manager.
+    bytes: AtomicUsize,
+}
+
+/// Counters for the in-memory tree cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub resident_trees: usize,
+    pub resident_bytes: usize,
 }
 
 pub struct TreeManager {
-    trees: DashMap<Pubkey, Arc<Mutex<MerkleTree>>>,
+    trees: DashMap<Pubkey, ManagedTree>,
     storage: Arc<dyn TreeStorage>,
+    // Roots kept in memory per tree when history is enabled for new trees.
+    root_history: Option<usize>,
+    wal: Option<Arc<WriteAheadLog>>,
+    // Resident trees are evicted, least recently used first, past this many bytes.
+    memory_budget: Option<usize>,
+    // Changelogs `get_proof_at` may replay for a single proof.
+    max_proof_replay: u64,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl TreeManager {
@@ -58,99 +465,1101 @@ impl TreeManager {
         Self {
             trees: DashMap::new(),
             storage,
+            root_history: None,
+            wal: None,
+            memory_budget: None,
+            max_proof_replay: DEFAULT_MAX_PROOF_REPLAY,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps the trees held in memory under roughly `bytes`, saving and
+    /// dropping the least recently used ones. Trees in use are never evicted.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            resident_trees: self.trees.len(),
+            resident_bytes: self.trees.iter().map(|managed| managed.bytes.load(Ordering::Relaxed)).sum(),
+        }
+    }
+
+    /// The resident copy of `merkle_tree`, counted as a hit and marked as just used.
+    fn cached(&self, merkle_tree: &Pubkey) -> Option<Ref<'_, Pubkey, ManagedTree>> {
+        let managed = self.trees.get(merkle_tree)?;
+        managed.last_used.store(self.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(managed)
+    }
+
+    fn record_size(&self, merkle_tree: &Pubkey, tree: &MerkleTree) {
+        if let Some(managed) = self.trees.get(merkle_tree) {
+            managed.bytes.store(tree.approx_memory_bytes(), Ordering::Relaxed);
         }
     }
 
-    pub async fn get_or_create_tree(
+    /// Evicts least recently used trees until the cache fits its budget. A tree
+    /// is saved before it goes and stays if it is in use or cannot be saved.
+    async fn enforce_budget(&self) -> anyhow::Result<()> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        let mut resident_bytes = self.cache_stats().resident_bytes;
+        if resident_bytes <= budget {
+            return Ok(());
+        }
+
+        let mut candidates: Vec<(u64, Pubkey, Arc<Mutex<MerkleTree>>)> = self
+            .trees
+            .iter()
+            .map(|managed| (managed.last_used.load(Ordering::Relaxed), *managed.key(), managed.tree.clone()))
+            .collect();
+        candidates.sort_by_key(|(last_used, _, _)| *last_used);
+
+        for (_, merkle_tree, tree) in candidates {
+            if resident_bytes <= budget {
+                break;
+            }
+            {
+                let mut tree = match tree.try_lock() {
+                    Ok(tree) => tree,
+                    Err(_) => continue,
+                };
+                if tree.has_unsaved_changes() {
+                    if let Err(e) = self.persist(&merkle_tree, &mut tree).await {
+                        error!("Failed to flush tree {} before eviction: {}", merkle_tree, e);
+                        continue;
+                    }
+                }
+            }
+
+            // Only the map and `tree` may still hold the tree, and it must not
+            // have changed since it was flushed.
+            let evicted = self.trees.remove_if(&merkle_tree, |_, managed| {
+                Arc::strong_count(&managed.tree) == 2
+                    && matches!(managed.tree.try_lock(), Ok(tree) if !tree.has_unsaved_changes())
+            });
+            if let Some((_, managed)) = evicted {
+                resident_bytes = resident_bytes.saturating_sub(managed.bytes.load(Ordering::Relaxed));
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                info!("Evicted tree {} from memory", merkle_tree);
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs every leaf change to `wal` before applying it, and enables
+    /// `begin_leaf`/`commit_leaf`. Call `recover` before anything else.
+    pub fn with_wal(mut self, wal: Arc<WriteAheadLog>) -> Self {
+        self.wal = Some(wal);
+        self
+    }
+
+    /// Records history for every tree this manager creates: the last `max_roots`
+    /// roots in memory and every changelog in storage, for `get_proof_at`.
+    pub fn with_root_history(mut self, max_roots: usize) -> Self {
+        self.root_history = Some(max_roots);
+        self
+    }
+
+    /// Caps the changelogs `get_proof_at` replays for one proof, which is
+    /// the sequence number it is asked for.
+    pub fn with_max_proof_replay(mut self, change_logs: u64) -> Self {
+        self.max_proof_replay = change_logs;
+        self
+    }
+
+    /// Starts managing `merkle_tree`, creating an empty local copy the first time.
+    /// Fails if the tree is already managed with different parameters.
+    pub async fn open_tree(
         &self,
-        authority: &Pubkey,
-        max_depth: u32,
+        merkle_tree: &Pubkey,
+        descriptor: TreeDescriptor,
     ) -> anyhow::Result<Arc<Mutex<MerkleTree>>> {
-        if let Some(tree) = self.trees.get(authority) {
-            return Ok(tree.value().clone());
+        if descriptor.canopy_depth > descriptor.max_depth {
+            anyhow::bail!("Canopy depth {} exceeds max depth {}", descriptor.canopy_depth, descriptor.max_depth);
+        }
+        if let Some(managed) = self.cached(merkle_tree) {
+            check_descriptor(merkle_tree, &managed.descriptor, &descriptor)?;
+            return Ok(managed.tree.clone());
         }
 
-        let tree = if let Some(stored_tree) = self.storage.load_tree(authority).await? {
-            info!("Loaded existing tree for authority: {}", authority);
-            stored_tree
-        } else {
-            info!("Creating new tree for authority: {}", authority);
-            MerkleTree::new(max_depth)
+        let descriptor = match self.storage.load_descriptor(merkle_tree).await? {
+            Some(stored) => {
+                check_descriptor(merkle_tree, &stored, &descriptor)?;
+                stored
+            }
+            None => {
+                self.storage.save_descriptor(merkle_tree, &descriptor).await?;
+                descriptor
+            }
         };
+        self.load_tree(merkle_tree, descriptor).await
+    }
+
+    /// The descriptor `merkle_tree` is managed with, if it is.
+    pub async fn descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+        if let Some(managed) = self.trees.get(merkle_tree) {
+            return Ok(Some(managed.descriptor));
+        }
+        self.storage.load_descriptor(merkle_tree).await
+    }
+
+    /// Records `table` as the lookup table of managed tree `merkle_tree`, so
+    /// it can be used again after a restart.
+    pub async fn set_lookup_table(&self, merkle_tree: &Pubkey, table: &Pubkey) -> anyhow::Result<()> {
+        let mut descriptor = self.storage.load_descriptor(merkle_tree).await?
+            .ok_or_else(|| anyhow::anyhow!("Tree {} is not managed", merkle_tree))?;
+        descriptor.lookup_table = Some(*table);
+        self.storage.save_descriptor(merkle_tree, &descriptor).await?;
+        if let Some(mut managed) = self.trees.get_mut(merkle_tree) {
+            managed.descriptor = descriptor;
+        }
+        Ok(())
+    }
+
+    /// Whether `merkle_tree` has been opened, now or in an earlier session.
+    pub async fn is_managed(&self, merkle_tree: &Pubkey) -> anyhow::Result<bool> {
+        Ok(self.trees.contains_key(merkle_tree)
+            || self.storage.load_descriptor(merkle_tree).await?.is_some())
+    }
+
+    /// Indexes the Bubblegum leaf at `record.index` of a managed tree, for
+    /// `leaves_by_owner` and `leaves_by_collection`.
+    pub async fn record_leaf(&self, merkle_tree: &Pubkey, record: &LeafRecord) -> anyhow::Result<()> {
+        if !self.is_managed(merkle_tree).await? {
+            anyhow::bail!("Tree {} is not managed", merkle_tree);
+        }
+        self.storage.save_leaf_record(merkle_tree, record).await
+    }
+
+    pub async fn leaves_by_owner(&self, merkle_tree: &Pubkey, owner: &Pubkey) -> anyhow::Result<Vec<LeafRecord>> {
+        self.storage.leaves_by_owner(merkle_tree, owner).await
+    }
+
+    pub async fn leaves_by_collection(
+        &self,
+        merkle_tree: &Pubkey,
+        collection: &Pubkey,
+    ) -> anyhow::Result<Vec<LeafRecord>> {
+        self.storage.leaves_by_collection(merkle_tree, collection).await
+    }
+
+    /// Every managed tree with its descriptor, ordered by address.
+    pub async fn list_trees(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+        let mut trees = self.storage.list_descriptors().await?;
+        trees.sort_by_key(|(merkle_tree, _)| *merkle_tree);
+        Ok(trees)
+    }
+
+    async fn get_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Arc<Mutex<MerkleTree>>> {
+        if let Some(managed) = self.cached(merkle_tree) {
+            return Ok(managed.tree.clone());
+        }
+
+        let descriptor = self.storage.load_descriptor(merkle_tree).await?
+            .ok_or_else(|| anyhow::anyhow!("Tree {} is not managed", merkle_tree))?;
+        self.load_tree(merkle_tree, descriptor).await
+    }
+
+    /// The tree as storage holds it, or an empty one if nothing is stored yet.
+    async fn stored_tree(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<MerkleTree> {
+        if let Some(stored_tree) = self.storage.load_tree(merkle_tree).await? {
+            if (stored_tree.max_depth, stored_tree.max_buffer_size) != (descriptor.max_depth, descriptor.max_buffer_size) {
+                anyhow::bail!("Stored nodes for tree {} do not match its descriptor", merkle_tree);
+            }
+            info!("Loaded existing tree: {}", merkle_tree);
+            Ok(stored_tree)
+        } else {
+            info!("Creating new tree: {}", merkle_tree);
+            let mut tree = MerkleTree::new(descriptor.max_depth, descriptor.max_buffer_size)
+                .map_err(anyhow::Error::msg)?;
+            if let Some(max_roots) = self.root_history {
+                tree.enable_history(max_roots);
+            }
+            Ok(tree)
+        }
+    }
+
+    async fn load_tree(
+        &self,
+        merkle_tree: &Pubkey,
+        descriptor: TreeDescriptor,
+    ) -> anyhow::Result<Arc<Mutex<MerkleTree>>> {
+        let tree = self.stored_tree(merkle_tree, &descriptor).await?;
 
-        let tree = Arc::new(Mutex::new(tree));
-        self.trees.insert(*authority, tree.clone());
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let managed = ManagedTree {
+            descriptor,
+            bytes: AtomicUsize::new(tree.approx_memory_bytes()),
+            tree: Arc::new(Mutex::new(tree)),
+            last_used: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+        };
+        // Another caller may have loaded the tree meanwhile; keep whichever got in first.
+        let tree = self.trees.entry(*merkle_tree).or_insert(managed).tree.clone();
+        self.enforce_budget().await?;
         Ok(tree)
     }
 
+    /// Moves cold, complete subtrees out of memory and into storage. The tree
+    /// must have been persisted first.
+    async fn spill_cold_subtrees(
+        &self,
+        merkle_tree: &Pubkey,
+        tree: &mut MerkleTree,
+    ) -> anyhow::Result<()> {
+        for index in tree.cold_subtrees(SUBTREE_IDLE_OPS) {
+            let subtree = tree.take_subtree(index).map_err(anyhow::Error::msg)?;
+            if let Err(e) = self.storage.save_subtree(merkle_tree, &subtree).await {
+                error!("Failed to spill subtree {} for tree {}: {}", index, merkle_tree, e);
+                tree.restore_subtree(subtree).map_err(anyhow::Error::msg)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the subtree holding leaf `index` back into memory if it was spilled.
+    async fn ensure_resident(
+        &self,
+        merkle_tree: &Pubkey,
+        tree: &mut MerkleTree,
+        index: u32,
+    ) -> anyhow::Result<()> {
+        if let Some(subtree) = tree.spilled_subtree_for(index) {
+            let stored = self.storage.load_subtree(merkle_tree, subtree).await?
+                .ok_or_else(|| anyhow::anyhow!("Spilled subtree {} missing from storage", subtree))?;
+            tree.restore_subtree(stored).map_err(anyhow::Error::msg)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the tree's unsaved changes to storage in one commit.
+    async fn persist(
+        &self,
+        merkle_tree: &Pubkey,
+        tree: &mut MerkleTree,
+    ) -> anyhow::Result<()> {
+        self.storage.save_tree(merkle_tree, tree).await?;
+        tree.mark_saved();
+        Ok(())
+    }
+
     pub async fn save_tree_state(
         &self,
-        authority: &Pubkey,
+        merkle_tree: &Pubkey,
     ) -> anyhow::Result<()> {
-        if let Some(tree) = self.trees.get(authority) {
-            let tree = tree.value().lock().await;
-            self.storage.save_tree(authority, &tree).await?;
-            info!("Saved tree state for authority: {}", authority);
+        let tree = self.trees.get(merkle_tree).map(|managed| managed.tree.clone());
+        if let Some(tree) = tree {
+            let mut tree = tree.lock().await;
+            self.persist(merkle_tree, &mut tree).await?;
+            info!("Saved tree state for tree: {}", merkle_tree);
         }
         Ok(())
     }
 
-    pub async fn insert_leaf(
+    /// Applies `op` under the tree lock. With a write-ahead log, the change is
+    /// logged first and only marked applied once storage holds it, so a crash
+    /// or failed save in between is finished by `recover`.
+    async fn apply_op(
         &self,
-        authority: &Pubkey,
-        leaf_data: &[u8],
-    ) -> anyhow::Result<(u32, Vec<u8>)> {
-        let tree = self.get_or_create_tree(authority, 14).await?;
+        merkle_tree: &Pubkey,
+        op: &LeafOp,
+        id: Option<u64>,
+        signature: Option<Signature>,
+    ) -> anyhow::Result<(Range<u32>, Vec<Node>)> {
+        let tree = self.get_tree(merkle_tree).await?;
         let mut tree = tree.lock().await;
-        
-        let index = tree.insert(leaf_data)?;
-        let root = tree.get_root();
-        
-        // Save state after modification
-        drop(tree); // Release lock before saving
-        self.save_tree_state(authority).await?;
-        
-        Ok((index, root))
+
+        if let Some(index) = op.existing_index() {
+            self.ensure_resident(merkle_tree, &mut tree, index).await?;
+        }
+        let committing = id.is_some();
+        let id = match &self.wal {
+            Some(wal) => Some(wal.mark_applying(id, merkle_tree, op, tree.sequence_number, signature)?),
+            None => None,
+        };
+        let applied = match op.apply(&mut tree) {
+            Ok(applied) => applied,
+            Err(e) => {
+                // Nothing changed, so there is nothing for recovery to finish.
+                if let (Some(wal), Some(id)) = (&self.wal, id) {
+                    wal.mark_aborted(id)?;
+                }
+                anyhow::bail!(e);
+            }
+        };
+
+        if let Err(e) = self.persist(merkle_tree, &mut tree).await {
+            // The change must not outlive the failed save, or a retry would
+            // apply it twice. A change from `begin_leaf` is pending again, for
+            // `commit_leaf` to retry; `recover` only finishes interrupted saves.
+            self.revert_to_stored(merkle_tree, &mut tree).await;
+            if let (Some(wal), Some(id)) = (&self.wal, id) {
+                if committing {
+                    wal.mark_pending(id)?;
+                } else {
+                    wal.mark_aborted(id)?;
+                }
+            }
+            return Err(e);
+        }
+        if let (Some(wal), Some(id)) = (&self.wal, id) {
+            wal.mark_applied(id)?;
+        }
+        // Only the new path is written; subtrees can be spilled once it is saved.
+        if let LeafOp::Append(_) = op {
+            self.spill_cold_subtrees(merkle_tree, &mut tree).await?;
+        }
+        self.record_size(merkle_tree, &tree);
+        drop(tree);
+        self.enforce_budget().await?;
+
+        Ok(applied)
+    }
+
+    /// Drops the unsaved changes of `tree` by reloading it from storage. If
+    /// that fails too, the tree is evicted so its next use reloads it.
+    async fn revert_to_stored(&self, merkle_tree: &Pubkey, tree: &mut MerkleTree) {
+        let descriptor = match self.trees.get(merkle_tree) {
+            Some(managed) => managed.descriptor,
+            None => return,
+        };
+        match self.stored_tree(merkle_tree, &descriptor).await {
+            Ok(stored) => {
+                *tree = stored;
+                self.record_size(merkle_tree, tree);
+            }
+            Err(e) => {
+                error!("Failed to reload tree {} after a failed save: {}", merkle_tree, e);
+                self.trees.remove(merkle_tree);
+            }
+        }
+    }
+
+    // Changes that skip `begin_leaf`/`commit_leaf`, and so bypass on-chain
+    // confirmation, are only for tests.
+    #[cfg(test)]
+    pub(crate) async fn insert_leaf(
+        &self,
+        merkle_tree: &Pubkey,
+        leaf_data: &[u8],
+    ) -> anyhow::Result<(u32, Node)> {
+        let leaf = Node::new(solana_program::keccak::hash(leaf_data).to_bytes());
+        let (indices, roots) = self.apply_op(merkle_tree, &LeafOp::Append(vec![leaf]), None, None).await?;
+
+        Ok((indices.start, roots[0]))
+    }
+
+    /// Appends several leaves under a single lock and a single save. The returned
+    /// roots are the ones each corresponding mint will see on chain.
+    #[cfg(test)]
+    pub(crate) async fn insert_leaves(
+        &self,
+        merkle_tree: &Pubkey,
+        leaves_data: &[Vec<u8>],
+    ) -> anyhow::Result<crate::merkle::BatchAppend> {
+        let leaves: Vec<Node> = leaves_data
+            .iter()
+            .map(|data| Node::new(solana_program::keccak::hash(data).to_bytes()))
+            .collect();
+
+        let (indices, roots) = self.apply_op(merkle_tree, &LeafOp::Append(leaves), None, None).await?;
+        Ok(crate::merkle::BatchAppend { indices, roots })
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn replace_leaf(
+        &self,
+        merkle_tree: &Pubkey,
+        index: u32,
+        new_leaf_hash: Node,
+    ) -> anyhow::Result<Node> {
+        let op = LeafOp::Replace { index, leaf: new_leaf_hash };
+        Ok(self.apply_op(merkle_tree, &op, None, None).await?.1[0])
+    }
+
+    #[cfg(test)]
+    pub(crate) async fn remove_leaf(
+        &self,
+        merkle_tree: &Pubkey,
+        index: u32,
+    ) -> anyhow::Result<Node> {
+        Ok(self.apply_op(merkle_tree, &LeafOp::Remove { index }, None, None).await?.1[0])
+    }
+
+    fn wal(&self) -> anyhow::Result<&WriteAheadLog> {
+        self.wal
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No write-ahead log is configured"))
+    }
+
+    /// Logs a leaf change whose transaction is about to be sent, without
+    /// touching the tree. Returns the id to pass to `commit_leaf` once the
+    /// transaction is confirmed, or to `abort_leaf` if it never lands.
+    pub async fn begin_leaf(&self, merkle_tree: &Pubkey, op: LeafOp) -> anyhow::Result<u64> {
+        if !self.is_managed(merkle_tree).await? {
+            anyhow::bail!("Tree {} is not managed", merkle_tree);
+        }
+        self.wal()?.begin(merkle_tree, op)
+    }
+
+    /// Applies a change logged by `begin_leaf` now that `signature` is confirmed
+    /// on chain. Returns the leaf indices it touched and the root after each.
+    pub async fn commit_leaf(&self, id: u64, signature: Signature) -> anyhow::Result<(Range<u32>, Vec<Node>)> {
+        let entry = self.wal()?.entry(id)?
+            .filter(|entry| entry.status == WalStatus::Pending)
+            .ok_or_else(|| anyhow::anyhow!("No pending leaf change {}", id))?;
+        self.apply_op(&entry.merkle_tree, &entry.op, Some(id), Some(signature)).await
+    }
+
+    pub fn abort_leaf(&self, id: u64) -> anyhow::Result<()> {
+        self.wal()?.mark_aborted(id)
+    }
+
+    /// Changes still waiting for `commit_leaf` or `abort_leaf`, e.g. after a restart.
+    pub fn pending_leaves(&self) -> anyhow::Result<Vec<WalEntry>> {
+        Ok(self
+            .wal()?
+            .open_entries()?
+            .into_iter()
+            .filter(|entry| entry.status == WalStatus::Pending)
+            .collect())
+    }
+
+    /// Finishes every change the write-ahead log shows as confirmed but not yet
+    /// saved, tree by tree in the order they were applied. A change counts as
+    /// saved once the stored tree is past the sequence number it was applied
+    /// on. Returns how many changes had to be applied again.
+    pub async fn recover(&self) -> anyhow::Result<usize> {
+        let wal = match &self.wal {
+            Some(wal) => wal,
+            None => return Ok(0),
+        };
+
+        let mut applying: BTreeMap<Pubkey, Vec<(u64, WalEntry)>> = BTreeMap::new();
+        for entry in wal.open_entries()? {
+            if let WalStatus::Applying { seq, .. } = entry.status {
+                applying.entry(entry.merkle_tree).or_default().push((seq, entry));
+            }
+        }
+
+        let mut replayed = 0;
+        for (merkle_tree, mut entries) in applying {
+            entries.sort_by_key(|(seq, _)| *seq);
+            let tree = self.get_tree(&merkle_tree).await?;
+            let mut tree = tree.lock().await;
+
+            for (seq, entry) in entries {
+                if tree.sequence_number < seq {
+                    anyhow::bail!(
+                        "Tree {} is at sequence number {}, behind logged change {} at {}",
+                        merkle_tree, tree.sequence_number, entry.id, seq
+                    );
+                }
+                if tree.sequence_number == seq {
+                    if let Some(index) = entry.op.existing_index() {
+                        self.ensure_resident(&merkle_tree, &mut tree, index).await?;
+                    }
+                    entry.op.apply(&mut tree).map_err(anyhow::Error::msg)?;
+                    self.persist(&merkle_tree, &mut tree).await?;
+                    replayed += 1;
+                }
+                wal.mark_applied(entry.id)?;
+            }
+            info!("Recovered tree {} at sequence number {}", merkle_tree, tree.sequence_number);
+        }
+        Ok(replayed)
     }
 
     pub async fn verify_leaf(
         &self,
-        authority: &Pubkey,
-        leaf_hash: &[u8],
+        merkle_tree: &Pubkey,
+        leaf_hash: &Node,
         index: u32,
     ) -> anyhow::Result<bool> {
-        let tree = self.get_or_create_tree(authority, 14).await?;
-        let tree = tree.lock().await;
-        
-        let proof = tree.get_proof(index)?;
+        let tree = self.get_tree(merkle_tree).await?;
+        let mut tree = tree.lock().await;
+
+        self.ensure_resident(merkle_tree, &mut tree, index).await?;
+        let proof = tree.get_proof(index).map_err(anyhow::Error::msg)?;
         let root = tree.get_root();
         
-        Ok(MerkleTree::verify_proof(&root, leaf_hash, &proof, index))
+        Ok(MerkleTree::verify_proof(&root, leaf_hash, &proof, index, tree.max_depth))
+    }
+
+    /// Builds one proof for several leaves, loading any spilled subtrees they live in.
+    pub async fn get_multi_proof(
+        &self,
+        merkle_tree: &Pubkey,
+        indices: &[u32],
+    ) -> anyhow::Result<(MultiProof, Node)> {
+        let tree = self.get_tree(merkle_tree).await?;
+        let mut tree = tree.lock().await;
+
+        for index in indices {
+            self.ensure_resident(merkle_tree, &mut tree, *index).await?;
+        }
+        let proof = tree.get_multi_proof(indices).map_err(anyhow::Error::msg)?;
+
+        Ok((proof, tree.get_root()))
+    }
+
+    /// Updates a proof fetched against an older root so it verifies against the
+    /// current one. Errors downcast to `FastForwardError`.
+    pub async fn fast_forward_proof(
+        &self,
+        merkle_tree: &Pubkey,
+        root: &Node,
+        leaf_hash: &Node,
+        proof: &[Node],
+        index: u32,
+    ) -> anyhow::Result<(Vec<Node>, Node)> {
+        let tree = self.get_tree(merkle_tree).await?;
+        let tree = tree.lock().await;
+
+        Ok(tree.fast_forward_proof(root, leaf_hash, proof, index)?)
+    }
+
+    /// Rebuilds the proof for leaf `index` as it stood right after sequence number
+    /// `seq`, from the changelogs persisted since the tree was created. Only
+    /// roots are kept from earlier states, so this replays `seq` changelogs
+    /// and fails past `with_max_proof_replay`; the current state needs none.
+    pub async fn get_proof_at(
+        &self,
+        merkle_tree: &Pubkey,
+        index: u32,
+        seq: u64,
+    ) -> anyhow::Result<HistoricalProof> {
+        let tree = self.get_tree(merkle_tree).await?;
+        let mut tree = tree.lock().await;
+
+        if !tree.history_enabled() {
+            anyhow::bail!("History is not enabled for this tree");
+        }
+        if seq > tree.sequence_number {
+            anyhow::bail!("Sequence number {} is ahead of the tree ({})", seq, tree.sequence_number);
+        }
+        if seq == tree.sequence_number {
+            self.ensure_resident(merkle_tree, &mut tree, index).await?;
+            let leaf = tree.get_leaf(index).map_err(anyhow::Error::msg)?;
+            let (proof, root) = tree.get_proof_with_root(index).map_err(anyhow::Error::msg)?;
+            return Ok(HistoricalProof { index, seq, leaf, proof, root });
+        }
+        if seq > self.max_proof_replay {
+            anyhow::bail!(
+                "Rebuilding a proof at sequence number {} would replay more than {} changelogs",
+                seq, self.max_proof_replay
+            );
+        }
+        self.persist(merkle_tree, &mut tree).await?;
+        let expected_root = tree.root_at(seq);
+        let mut replay = ProofReplay::new(tree.max_depth, index, seq).map_err(anyhow::Error::msg)?;
+        drop(tree);
+
+        while replay.next_seq() <= seq {
+            let change_logs = self.storage
+                .load_change_logs(merkle_tree, replay.next_seq(), CHANGE_LOG_PAGE_SIZE)
+                .await?;
+            if change_logs.is_empty() {
+                break;
+            }
+            replay.apply(&change_logs).map_err(anyhow::Error::msg)?;
+        }
+        let proof = replay.finish().map_err(anyhow::Error::msg)?;
+
+        if expected_root.is_some_and(|root| root != proof.root) {
+            anyhow::bail!("Replayed root does not match the recorded root at sequence {}", seq);
+        }
+        Ok(proof)
+    }
+
+    pub async fn get_proof(
+        &self,
+        merkle_tree: &Pubkey,
+        index: u32,
+    ) -> anyhow::Result<(Vec<Node>, Node)> {
+        let tree = self.get_tree(merkle_tree).await?;
+        let mut tree = tree.lock().await;
+
+        self.ensure_resident(merkle_tree, &mut tree, index).await?;
+        tree.get_proof_with_root(index).map_err(anyhow::Error::msg)
+    }
+
+    /// The proof for `index` without the levels the tree's canopy holds on
+    /// chain, as Bubblegum leaf instructions take it, and the root it is for.
+    pub async fn get_canopy_proof(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<(Vec<Node>, Node)> {
+        let descriptor = self.storage.load_descriptor(merkle_tree).await?
+            .ok_or_else(|| anyhow::anyhow!("Tree {} is not managed", merkle_tree))?;
+        let tree = self.get_tree(merkle_tree).await?;
+        let mut tree = tree.lock().await;
+
+        self.ensure_resident(merkle_tree, &mut tree, index).await?;
+        let proof = tree.get_proof_for_canopy(index, descriptor.canopy_depth).map_err(anyhow::Error::msg)?;
+        Ok((proof, tree.get_root()))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory_storage::InMemoryStorage;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use tempfile::tempdir;
 
+    /// Fails every `save_tree` while `fail_saves` is set, like a process killed
+    /// before its save reached disk.
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: InMemoryStorage,
+        fail_saves: AtomicBool,
+    }
+
+    #[async_trait]
+    impl TreeStorage for FlakyStorage {
+        async fn load_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<MerkleTree>> {
+            self.inner.load_tree(merkle_tree).await
+        }
+
+        async fn save_tree(&self, merkle_tree: &Pubkey, tree: &MerkleTree) -> anyhow::Result<()> {
+            if self.fail_saves.load(Ordering::SeqCst) {
+                anyhow::bail!("Killed during save");
+            }
+            self.inner.save_tree(merkle_tree, tree).await
+        }
+
+        async fn delete_tree(&self, merkle_tree: &Pubkey) -> anyhow::Result<()> {
+            self.inner.delete_tree(merkle_tree).await
+        }
+
+        async fn save_descriptor(&self, merkle_tree: &Pubkey, descriptor: &TreeDescriptor) -> anyhow::Result<()> {
+            self.inner.save_descriptor(merkle_tree, descriptor).await
+        }
+
+        async fn load_descriptor(&self, merkle_tree: &Pubkey) -> anyhow::Result<Option<TreeDescriptor>> {
+            self.inner.load_descriptor(merkle_tree).await
+        }
+
+        async fn list_descriptors(&self) -> anyhow::Result<Vec<(Pubkey, TreeDescriptor)>> {
+            self.inner.list_descriptors().await
+        }
+
+        async fn save_subtree(&self, merkle_tree: &Pubkey, subtree: &Subtree) -> anyhow::Result<()> {
+            self.inner.save_subtree(merkle_tree, subtree).await
+        }
+
+        async fn load_subtree(&self, merkle_tree: &Pubkey, index: u32) -> anyhow::Result<Option<Subtree>> {
+            self.inner.load_subtree(merkle_tree, index).await
+        }
+
+        async fn load_change_logs(
+            &self,
+            merkle_tree: &Pubkey,
+            from_seq: u64,
+            limit: usize,
+        ) -> anyhow::Result<Vec<(u64, ChangeLog)>> {
+            self.inner.load_change_logs(merkle_tree, from_seq, limit).await
+        }
+    }
+
+    fn descriptor(max_depth: u32, max_buffer_size: u32) -> TreeDescriptor {
+        TreeDescriptor {
+            max_depth,
+            max_buffer_size,
+            canopy_depth: 0,
+            authority: Pubkey::new_from_array([7; 32]),
+            creation_slot: 1,
+            lookup_table: None,
+        }
+    }
+
     #[tokio::test]
     async fn test_tree_manager() -> anyhow::Result<()> {
-        let temp_dir = tempdir()?;
-        let storage = Arc::new(RocksDBStorage::new(temp_dir.path().to_path_buf())?);
+        let storage = Arc::new(InMemoryStorage::new());
         let manager = TreeManager::new(storage);
 
-        let authority = Pubkey::new_unique();
+        let merkle_tree = Pubkey::new_unique();
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
         let leaf_data = b"test leaf";
 
         // Insert leaf and verify
-        let (index, root) = manager.insert_leaf(&authority, leaf_data).await?;
-        assert!(manager.verify_leaf(&authority, &root, index).await?);
+        let (index, root) = manager.insert_leaf(&merkle_tree, leaf_data).await?;
+        let leaf_hash = Node::new(solana_program::keccak::hash(leaf_data).to_bytes());
+        assert!(manager.verify_leaf(&merkle_tree, &leaf_hash, index).await?);
+        assert!(!manager.verify_leaf(&merkle_tree, &root, index).await?);
+
+        let (proof, proof_root) = manager.get_proof(&merkle_tree, index).await?;
+        assert_eq!(proof_root, root);
+        assert!(MerkleTree::verify_proof(&root, &leaf_hash, &proof, index, 14));
+
+        let leaves: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 8]).collect();
+        let batch = manager.insert_leaves(&merkle_tree, &leaves).await?;
+        assert_eq!(batch.indices, 1..5);
+        let (_, root) = manager.get_proof(&merkle_tree, 4).await?;
+        assert_eq!(*batch.roots.last().unwrap(), root);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_tree_checks_descriptor() -> anyhow::Result<()> {
+        let storage: Arc<dyn TreeStorage> = Arc::new(InMemoryStorage::new());
+        let manager = TreeManager::new(storage.clone());
+
+        let small = Pubkey::new_unique();
+        let large = Pubkey::new_unique();
+        assert!(manager.insert_leaf(&small, b"leaf").await.is_err());
+
+        manager.open_tree(&small, descriptor(5, 8)).await?;
+        manager.open_tree(&large, descriptor(20, 256)).await?;
+        manager.insert_leaf(&small, b"leaf").await?;
+        assert!(manager.get_proof(&large, 0).await.is_err());
+        assert_eq!(manager.get_proof(&small, 0).await?.0.len(), 5);
+
+        assert!(manager.open_tree(&small, descriptor(14, 64)).await.is_err());
+        assert!(manager.open_tree(&large, TreeDescriptor { creation_slot: 2, ..descriptor(20, 256) }).await.is_err());
+        assert!(manager.open_tree(&Pubkey::new_unique(), TreeDescriptor { canopy_depth: 6, ..descriptor(5, 8) }).await.is_err());
+
+        // A fresh manager over the same storage still knows both trees and their parameters.
+        let manager = TreeManager::new(storage);
+        let mut expected = vec![(small, descriptor(5, 8)), (large, descriptor(20, 256))];
+        expected.sort_by_key(|(merkle_tree, _)| *merkle_tree);
+        assert_eq!(manager.list_trees().await?, expected);
+        assert!(manager.is_managed(&small).await?);
+        assert_eq!(manager.get_proof(&small, 0).await?.0.len(), 5);
+        assert!(manager.open_tree(&small, descriptor(14, 64)).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_table_kept_with_descriptor() -> anyhow::Result<()> {
+        let storage: Arc<dyn TreeStorage> = Arc::new(InMemoryStorage::new());
+        let merkle_tree = Pubkey::new_unique();
+        let table = Pubkey::new_unique();
+
+        let manager = TreeManager::new(storage.clone());
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        manager.set_lookup_table(&merkle_tree, &table).await?;
+        assert_eq!(manager.descriptor(&merkle_tree).await?.unwrap().lookup_table, Some(table));
+        assert!(manager.set_lookup_table(&Pubkey::new_unique(), &table).await.is_err());
+
+        // Reopening with the on-chain parameters keeps the stored table.
+        let manager = TreeManager::new(storage);
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        assert_eq!(manager.descriptor(&merkle_tree).await?.unwrap().lookup_table, Some(table));
+
+        // Descriptors stored before they had a lookup table still decode.
+        let legacy = &bincode::serialize(&descriptor(14, 64))?[..52];
+        assert_eq!(decode_descriptor(legacy)?, descriptor(14, 64));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_canopy_proof() -> anyhow::Result<()> {
+        let manager = TreeManager::new(Arc::new(InMemoryStorage::new()));
+        let merkle_tree = Pubkey::new_unique();
+        manager.open_tree(&merkle_tree, TreeDescriptor { canopy_depth: 4, ..descriptor(14, 64) }).await?;
+        manager.insert_leaf(&merkle_tree, b"leaf").await?;
+
+        let (proof, root) = manager.get_proof(&merkle_tree, 0).await?;
+        assert_eq!(manager.get_canopy_proof(&merkle_tree, 0).await?, (proof[..10].to_vec(), root));
+        assert!(manager.get_canopy_proof(&Pubkey::new_unique(), 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_proof_at() -> anyhow::Result<()> {
+        let storage = Arc::new(InMemoryStorage::new());
+        let manager = TreeManager::new(storage).with_root_history(8);
+
+        let merkle_tree = Pubkey::new_unique();
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        let (index, _) = manager.insert_leaf(&merkle_tree, b"sold").await?;
+        let (proof, root) = manager.get_proof(&merkle_tree, index).await?;
+        let leaves: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 8]).collect();
+        manager.insert_leaves(&merkle_tree, &leaves).await?;
+        manager.remove_leaf(&merkle_tree, index).await?;
+
+        let historical = manager.get_proof_at(&merkle_tree, index, 1).await?;
+        assert_eq!((historical.proof, historical.root), (proof, root));
+        assert_eq!(historical.leaf, Node::new(solana_program::keccak::hash(b"sold").to_bytes()));
+
+        assert!(manager.get_proof_at(&merkle_tree, 50, 1).await.is_err());
+        assert!(manager.get_proof_at(&merkle_tree, index, 103).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_proof_at_replay_limit() -> anyhow::Result<()> {
+        let storage = Arc::new(InMemoryStorage::new());
+        let manager = TreeManager::new(storage).with_root_history(8).with_max_proof_replay(50);
+
+        let merkle_tree = Pubkey::new_unique();
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        let leaves: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 8]).collect();
+        manager.insert_leaves(&merkle_tree, &leaves).await?;
+        manager.remove_leaf(&merkle_tree, 3).await?;
+
+        let sold = Node::new(solana_program::keccak::hash(&leaves[3]).to_bytes());
+        assert_eq!(manager.get_proof_at(&merkle_tree, 3, 50).await?.leaf, sold);
+        assert!(manager.get_proof_at(&merkle_tree, 3, 51).await.is_err());
+
+        // The current state is read from the tree, whatever the limit.
+        let current = manager.get_proof_at(&merkle_tree, 3, 101).await?;
+        assert_eq!((current.proof, current.root), manager.get_proof(&merkle_tree, 3).await?);
+        assert_eq!(current.leaf, crate::merkle::EMPTY_NODE);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "persistent-storage")]
+    #[tokio::test]
+    async fn test_reopen_after_incremental_saves() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let merkle_tree = Pubkey::new_unique();
+        let leaves: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i; 8]).collect();
+
+        let expected = {
+            let storage = Arc::new(RocksDBStorage::new(temp_dir.path().to_path_buf())?);
+            let manager = TreeManager::new(storage);
+            manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+            manager.insert_leaves(&merkle_tree, &leaves).await?;
+            manager.replace_leaf(&merkle_tree, 7, Node::new([9; 32])).await?;
+            manager.remove_leaf(&merkle_tree, 39).await?;
+            manager.get_proof(&merkle_tree, 7).await?
+        };
+
+        let storage = Arc::new(RocksDBStorage::new(temp_dir.path().to_path_buf())?);
+        let manager = TreeManager::new(storage);
+        assert_eq!(manager.get_proof(&merkle_tree, 7).await?, expected);
+        assert!(manager.verify_leaf(&merkle_tree, &Node::new([9; 32]), 7).await?);
+        let (index, _) = manager.insert_leaf(&merkle_tree, b"next").await?;
+        assert_eq!(index, 40);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "persistent-storage")]
+    #[tokio::test]
+    async fn test_reopen_pages_in_subtrees() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let merkle_tree = Pubkey::new_unique();
+        let leaves: Vec<Vec<u8>> = (0..3 * (1u32 << SUBTREE_HEIGHT) + 5).map(|i| i.to_le_bytes().to_vec()).collect();
+
+        let expected = {
+            let storage = Arc::new(RocksDBStorage::new(temp_dir.path().to_path_buf())?);
+            let manager = TreeManager::new(storage);
+            manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+            manager.insert_leaves(&merkle_tree, &leaves).await?;
+            manager.get_proof(&merkle_tree, 17).await?
+        };
+
+        let storage = Arc::new(RocksDBStorage::new(temp_dir.path().to_path_buf())?);
+        let manager = TreeManager::new(storage);
+        {
+            // Only the upper levels and the subtree being appended to are read.
+            let tree = manager.get_tree(&merkle_tree).await?;
+            let tree = tree.lock().await;
+            assert!(tree.resident_nodes() < 1 << SUBTREE_HEIGHT);
+            assert_eq!(tree.spilled_subtree_for(17), Some(0));
+            assert_eq!(tree.spilled_subtree_for(leaves.len() as u32 - 1), None);
+        }
+        assert_eq!(manager.get_proof(&merkle_tree, 17).await?, expected);
+        assert_eq!(manager.insert_leaf(&merkle_tree, b"next").await?.0, leaves.len() as u32);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recover_after_killed_save() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let wal_path = temp_dir.path().join("wal");
+        let storage = Arc::new(InMemoryStorage::new());
+        let merkle_tree = Pubkey::new_unique();
+        let lost = Node::new(solana_program::keccak::hash(b"lost").to_bytes());
+
+        {
+            let wal = Arc::new(WriteAheadLog::open(wal_path.clone())?);
+            let manager = TreeManager::new(storage.clone()).with_wal(wal.clone());
+            manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+            manager.insert_leaf(&merkle_tree, b"saved").await?;
+            // Killed after logging the next two changes, before saving either.
+            wal.mark_applying(None, &merkle_tree, &LeafOp::Append(vec![lost]), 1, None)?;
+            wal.mark_applying(None, &merkle_tree, &LeafOp::Replace { index: 0, leaf: Node::new([9; 32]) }, 2, None)?;
+        }
+
+        // Storage never saw the last two changes; the log still has them.
+        let manager = TreeManager::new(storage.clone()).with_wal(Arc::new(WriteAheadLog::open(wal_path.clone())?));
+        assert_eq!(manager.recover().await?, 2);
+        assert!(manager.verify_leaf(&merkle_tree, &Node::new([9; 32]), 0).await?);
+        assert!(manager.verify_leaf(&merkle_tree, &lost, 1).await?);
+        drop(manager);
+
+        let manager = TreeManager::new(storage).with_wal(Arc::new(WriteAheadLog::open(wal_path.clone())?));
+        assert_eq!(manager.recover().await?, 0);
+        assert_eq!(manager.insert_leaf(&merkle_tree, b"next").await?.0, 2);
+        assert_eq!(std::fs::metadata(&wal_path)?.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_with_wal_then_retry() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let storage = Arc::new(FlakyStorage::default());
+        let manager = TreeManager::new(storage.clone())
+            .with_wal(Arc::new(WriteAheadLog::open(temp_dir.path().join("wal"))?));
+        let merkle_tree = Pubkey::new_unique();
+        let leaf = Node::new([3; 32]);
+
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        manager.insert_leaf(&merkle_tree, b"saved").await?;
+        let id = manager.begin_leaf(&merkle_tree, LeafOp::Append(vec![leaf])).await?;
+        storage.fail_saves.store(true, Ordering::SeqCst);
+        assert!(manager.commit_leaf(id, Signature::default()).await.is_err());
+        assert!(manager.insert_leaf(&merkle_tree, b"local").await.is_err());
+
+        // Neither change outlives its failed save; the confirmed one can be committed again.
+        assert!(manager.get_proof(&merkle_tree, 1).await.is_err());
+        let pending: Vec<u64> = manager.pending_leaves()?.iter().map(|entry| entry.id).collect();
+        assert_eq!(pending, vec![id]);
+
+        storage.fail_saves.store(false, Ordering::SeqCst);
+        let (indices, roots) = manager.commit_leaf(id, Signature::default()).await?;
+        assert_eq!(indices, 1..2);
+        assert!(manager.verify_leaf(&merkle_tree, &leaf, 1).await?);
+        assert_eq!(manager.recover().await?, 0);
+        assert_eq!(manager.get_proof(&merkle_tree, 1).await?.1, roots[0]);
+        assert_eq!(manager.insert_leaf(&merkle_tree, b"next").await?.0, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leaf_committed_on_confirmation() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let wal_path = temp_dir.path().join("wal");
+        let storage = Arc::new(InMemoryStorage::new());
+        let merkle_tree = Pubkey::new_unique();
+        let leaf = Node::new([3; 32]);
+
+        let id = {
+            let manager = TreeManager::new(storage.clone()).with_wal(Arc::new(WriteAheadLog::open(wal_path.clone())?));
+            manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+            let id = manager.begin_leaf(&merkle_tree, LeafOp::Append(vec![leaf])).await?;
+            assert!(manager.get_proof(&merkle_tree, 0).await.is_err());
+            id
+        };
+
+        // A torn record from a crash mid-append does not hide the pending change.
+        std::fs::OpenOptions::new().append(true).open(&wal_path)?.write_all(&[0xff; 12])?;
+        let manager = TreeManager::new(storage).with_wal(Arc::new(WriteAheadLog::open(wal_path)?));
+        assert_eq!(manager.recover().await?, 0);
+        assert_eq!(manager.pending_leaves()?.len(), 1);
 
-        // Test persistence
-        let leaf_hash = solana_program::keccak::hash(leaf_data).to_bytes();
-        assert!(manager.verify_leaf(&authority, &leaf_hash, index).await?);
+        let (indices, roots) = manager.commit_leaf(id, Signature::default()).await?;
+        assert_eq!(indices, 0..1);
+        assert_eq!(manager.get_proof(&merkle_tree, 0).await?.1, roots[0]);
+        assert!(manager.commit_leaf(id, Signature::default()).await.is_err());
+
+        let aborted = manager.begin_leaf(&merkle_tree, LeafOp::Remove { index: 0 }).await?;
+        manager.abort_leaf(aborted)?;
+        assert!(manager.pending_leaves()?.is_empty());
+        assert!(manager.verify_leaf(&merkle_tree, &leaf, 0).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_evicts_least_recently_used() -> anyhow::Result<()> {
+        // A one-byte budget keeps only the tree in use resident.
+        let manager = TreeManager::new(Arc::new(InMemoryStorage::new())).with_memory_budget(1);
+        let first = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+
+        manager.open_tree(&first, descriptor(14, 64)).await?;
+        let (_, first_root) = manager.insert_leaf(&first, b"first").await?;
+        manager.open_tree(&second, descriptor(14, 64)).await?;
+        manager.insert_leaf(&second, b"second").await?;
+        let stats = manager.cache_stats();
+        assert_eq!(stats.resident_trees, 1);
+        assert!(stats.evictions >= 1);
+
+        // The evicted tree comes back from storage intact.
+        let misses = stats.misses;
+        assert_eq!(manager.get_proof(&first, 0).await?.1, first_root);
+        let stats = manager.cache_stats();
+        assert_eq!(stats.misses, misses + 1);
+        assert_eq!(stats.resident_trees, 1);
+        assert!(stats.hits > 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_without_wal_reverts_change() -> anyhow::Result<()> {
+        let storage = Arc::new(FlakyStorage::default());
+        let manager = TreeManager::new(storage.clone());
+        let merkle_tree = Pubkey::new_unique();
+
+        manager.open_tree(&merkle_tree, descriptor(14, 64)).await?;
+        manager.insert_leaf(&merkle_tree, b"saved").await?;
+        storage.fail_saves.store(true, Ordering::SeqCst);
+        assert!(manager.insert_leaf(&merkle_tree, b"retried").await.is_err());
+        assert!(manager.replace_leaf(&merkle_tree, 0, Node::new([9; 32])).await.is_err());
+
+        // Retrying appends the leaf once, where the failed attempt would have.
+        storage.fail_saves.store(false, Ordering::SeqCst);
+        assert_eq!(manager.insert_leaf(&merkle_tree, b"retried").await?.0, 1);
+        let saved = Node::new(solana_program::keccak::hash(b"saved").to_bytes());
+        assert!(manager.verify_leaf(&merkle_tree, &saved, 0).await?);
 
         Ok(())
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_unsaved_tree_flushed_before_eviction() -> anyhow::Result<()> {
+        let temp_dir = tempdir()?;
+        let storage = Arc::new(FlakyStorage::default());
+        let wal = Arc::new(WriteAheadLog::open(temp_dir.path().join("wal"))?);
+        let manager = TreeManager::new(storage.clone())
+            .with_wal(wal.clone())
+            .with_memory_budget(1);
+        let first = Pubkey::new_unique();
+        let second = Pubkey::new_unique();
+        let unsaved = Node::new(solana_program::keccak::hash(b"unsaved").to_bytes());
+
+        manager.open_tree(&first, descriptor(14, 64)).await?;
+        wal.mark_applying(None, &first, &LeafOp::Append(vec![unsaved]), 0, None)?;
+        storage.fail_saves.store(true, Ordering::SeqCst);
+        // Replayed in memory, but not saved.
+        assert!(manager.recover().await.is_err());
+
+        // The first tree cannot be flushed, so it stays over budget.
+        manager.open_tree(&second, descriptor(14, 64)).await?;
+        assert_eq!(manager.cache_stats().resident_trees, 2);
+
+        storage.fail_saves.store(false, Ordering::SeqCst);
+        manager.insert_leaf(&second, b"second").await?;
+        assert_eq!(manager.cache_stats().resident_trees, 1);
+
+        assert!(manager.verify_leaf(&first, &unsaved, 0).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::merkle::{MerkleTree, Node};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use solana_program::keccak;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Each log record is its payload length (u32 LE), the keccak hash of the
+// payload, then the payload.
+const RECORD_HEADER_LEN: usize = 4 + 32;
+
+// A log with open entries is rewritten without the closed ones past this size.
+const WAL_COMPACTION_BYTES: u64 = 1 << 20;
+
+/// Appends one record per value to the log at `path`, after cutting it back to
+/// `valid_len` in case it ends with a torn record. Returns the new length.
+pub(crate) fn append_records<T: Serialize>(path: &Path, valid_len: u64, values: &[T]) -> anyhow::Result<u64> {
+    let mut bytes = Vec::new();
+    for value in values {
+        let payload = bincode::serialize(value)?;
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&keccak::hash(&payload).to_bytes());
+        bytes.extend_from_slice(&payload);
+    }
+
+    OpenOptions::new().create(true).write(true).open(path)?.set_len(valid_len)?;
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_data()?;
+    Ok(valid_len + bytes.len() as u64)
+}
+
+/// Reads every complete record of the log at `path`, stopping at the first
+/// truncated or corrupted one. Also returns the length up to that point.
+pub(crate) fn read_records<T: DeserializeOwned>(path: &Path) -> anyhow::Result<(Vec<T>, u64)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while bytes.len() - offset >= RECORD_HEADER_LEN {
+        let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into()?) as usize;
+        let start = offset + RECORD_HEADER_LEN;
+        let payload = match bytes.get(start..start + len) {
+            Some(payload) if keccak::hash(payload).to_bytes()[..] == bytes[offset + 4..start] => payload,
+            _ => break,
+        };
+        records.push(bincode::deserialize(payload)?);
+        offset = start + len;
+    }
+    Ok((records, offset as u64))
+}
+
+/// A leaf change that goes through the write-ahead log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LeafOp {
+    Append(Vec<Node>),
+    Replace { index: u32, leaf: Node },
+    Remove { index: u32 },
+}
+
+impl LeafOp {
+    /// The leaf an existing-leaf change touches, which must be resident.
+    pub fn existing_index(&self) -> Option<u32> {
+        match self {
+            LeafOp::Append(_) => None,
+            LeafOp::Replace { index, .. } | LeafOp::Remove { index } => Some(*index),
+        }
+    }
+
+    /// Applies the change, returning the leaf indices it touched and the root
+    /// after each of them. Leaves the tree untouched on error.
+    pub fn apply(&self, tree: &mut MerkleTree) -> Result<(Range<u32>, Vec<Node>), &'static str> {
+        match self {
+            LeafOp::Append(leaves) => {
+                let batch = tree.append_batch(leaves)?;
+                Ok((batch.indices, batch.roots))
+            }
+            LeafOp::Replace { index, leaf } => Ok((*index..*index + 1, vec![tree.replace_leaf(*index, *leaf)?])),
+            LeafOp::Remove { index } => Ok((*index..*index + 1, vec![tree.remove_leaf(*index)?])),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WalStatus {
+    /// Waiting for its transaction to be confirmed; not in the tree yet.
+    Pending,
+    /// Being applied on top of sequence number `seq`, after `signature` (if
+    /// any; local changes have none) was confirmed on chain.
+    Applying { seq: u64, signature: Option<Signature> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalEntry {
+    pub id: u64,
+    pub merkle_tree: Pubkey,
+    pub op: LeafOp,
+    pub status: WalStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum WalRecord {
+    Open(WalEntry),
+    Applied(u64),
+    Aborted(u64),
+}
+
+struct WalState {
+    len: u64,
+    next_id: u64,
+    open: BTreeMap<u64, WalEntry>,
+}
+
+/// Durable record of leaf changes between the moment they are requested and
+/// the moment storage holds them, so `TreeManager::recover` can finish them
+/// after a crash. An entry is open from `begin` or `mark_applying` until
+/// `mark_applied` or `mark_aborted`; the file is emptied whenever no entry is open.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    state: Mutex<WalState>,
+}
+
+impl WriteAheadLog {
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let (records, len) = read_records::<WalRecord>(&path)?;
+
+        let mut open = BTreeMap::new();
+        let mut next_id = 0;
+        for record in records {
+            match record {
+                WalRecord::Open(entry) => {
+                    next_id = next_id.max(entry.id + 1);
+                    open.insert(entry.id, entry);
+                }
+                WalRecord::Applied(id) | WalRecord::Aborted(id) => {
+                    open.remove(&id);
+                }
+            }
+        }
+
+        Ok(Self {
+            path,
+            state: Mutex::new(WalState { len, next_id, open }),
+        })
+    }
+
+    fn lock(&self) -> anyhow::Result<std::sync::MutexGuard<'_, WalState>> {
+        self.state.lock().map_err(|_| anyhow::anyhow!("Write-ahead log lock is poisoned"))
+    }
+
+    fn append(&self, state: &mut WalState, record: WalRecord) -> anyhow::Result<()> {
+        state.len = append_records(&self.path, state.len, &[record])?;
+
+        if state.open.is_empty() {
+            let file = OpenOptions::new().write(true).open(&self.path)?;
+            file.set_len(0)?;
+            file.sync_data()?;
+            state.len = 0;
+        } else if state.len > WAL_COMPACTION_BYTES {
+            let temp = self.path.with_extension("tmp");
+            let records: Vec<WalRecord> = state.open.values().cloned().map(WalRecord::Open).collect();
+            let len = append_records(&temp, 0, &records)?;
+            fs::rename(&temp, &self.path)?;
+            if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                File::open(dir)?.sync_all()?;
+            }
+            state.len = len;
+        }
+        Ok(())
+    }
+
+    /// Logs a change waiting for on-chain confirmation and returns its id.
+    pub fn begin(&self, merkle_tree: &Pubkey, op: LeafOp) -> anyhow::Result<u64> {
+        let mut state = self.lock()?;
+        let entry = WalEntry {
+            id: state.next_id,
+            merkle_tree: *merkle_tree,
+            op,
+            status: WalStatus::Pending,
+        };
+        state.next_id += 1;
+        state.open.insert(entry.id, entry.clone());
+        self.append(&mut state, WalRecord::Open(entry.clone()))?;
+        Ok(entry.id)
+    }
+
+    /// Logs that a change is about to be applied on top of sequence number
+    /// `seq`. `id` is the pending entry being committed, or `None` for a change
+    /// that skipped `begin`. Returns the entry's id.
+    pub fn mark_applying(
+        &self,
+        id: Option<u64>,
+        merkle_tree: &Pubkey,
+        op: &LeafOp,
+        seq: u64,
+        signature: Option<Signature>,
+    ) -> anyhow::Result<u64> {
+        let mut state = self.lock()?;
+        let id = match id {
+            Some(id) => id,
+            None => {
+                state.next_id += 1;
+                state.next_id - 1
+            }
+        };
+        let entry = WalEntry {
+            id,
+            merkle_tree: *merkle_tree,
+            op: op.clone(),
+            status: WalStatus::Applying { seq, signature },
+        };
+        state.open.insert(id, entry.clone());
+        self.append(&mut state, WalRecord::Open(entry))?;
+        Ok(id)
+    }
+
+    pub fn mark_applied(&self, id: u64) -> anyhow::Result<()> {
+        let mut state = self.lock()?;
+        if state.open.remove(&id).is_some() {
+            self.append(&mut state, WalRecord::Applied(id))?;
+        }
+        Ok(())
+    }
+
+    /// Puts an entry whose change could not be saved back to waiting for
+    /// `commit_leaf`.
+    pub fn mark_pending(&self, id: u64) -> anyhow::Result<()> {
+        let mut state = self.lock()?;
+        let entry = match state.open.get_mut(&id) {
+            Some(entry) => {
+                entry.status = WalStatus::Pending;
+                entry.clone()
+            }
+            None => return Ok(()),
+        };
+        self.append(&mut state, WalRecord::Open(entry))
+    }
+
+    pub fn mark_aborted(&self, id: u64) -> anyhow::Result<()> {
+        let mut state = self.lock()?;
+        if state.open.remove(&id).is_some() {
+            self.append(&mut state, WalRecord::Aborted(id))?;
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self, id: u64) -> anyhow::Result<Option<WalEntry>> {
+        Ok(self.lock()?.open.get(&id).cloned())
+    }
+
+    /// Every open entry, by id.
+    pub fn open_entries(&self) -> anyhow::Result<Vec<WalEntry>> {
+        Ok(self.lock()?.open.values().cloned().collect())
+    }
+}
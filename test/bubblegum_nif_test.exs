@@ -87,9 +87,9 @@ defmodule BubblegumNifTest do
         "YOUR_TEST_PUBKEY",
         "RECIPIENT_TEST_PUBKEY",
         "MERKLE_TREE_PUBKEY",
-        <<0::256>>,
-        <<0::256>>,
-        <<0::256>>,
+        Base58.encode(<<0::256>>),
+        Base58.encode(<<0::256>>),
+        Base58.encode(<<0::256>>),
         0,
         0
       )